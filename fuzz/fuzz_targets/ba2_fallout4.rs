@@ -0,0 +1,15 @@
+#![no_main]
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use testract::ba2;
+
+fuzz_target!(|data: &[u8]| {
+    // the input is parsed straight from memory, behind the format's magic
+    let mut contents = b"BTDX".to_vec();
+    contents.extend_from_slice(data);
+
+    if let Ok(mut archive) = ba2::from_reader(Cursor::new(contents)) {
+        let _ = archive.verify_contents();
+    }
+});
@@ -0,0 +1,15 @@
+#![no_main]
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use testract::bsa;
+
+fuzz_target!(|data: &[u8]| {
+    // the input is parsed straight from memory, behind the format's magic
+    let mut contents = b"\x00\x01\x00\x00".to_vec();
+    contents.extend_from_slice(data);
+
+    if let Ok(mut archive) = bsa::from_reader(Cursor::new(contents)) {
+        let _ = archive.verify_contents();
+    }
+});
@@ -0,0 +1,19 @@
+#![no_main]
+use std::io::Cursor;
+use std::path::Path;
+
+use libfuzzer_sys::fuzz_target;
+use testract::bsa;
+
+fuzz_target!(|data: &[u8]| {
+    // the input is parsed straight from memory, behind the format's magic
+    let mut contents = b"BSA\0".to_vec();
+    contents.extend_from_slice(data);
+
+    if let Ok(mut archive) = bsa::lazy_from_reader(Cursor::new(&contents)) {
+        let _ = archive.extract_by_name(Path::new("meshes/clutter/bucket.nif"));
+    }
+    if let Ok(mut archive) = bsa::from_reader(Cursor::new(contents)) {
+        let _ = archive.verify_contents();
+    }
+});
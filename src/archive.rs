@@ -1,13 +1,23 @@
 use std::collections::HashMap;
 use std::ffi::OsStr;
+use std::fs::File;
 use std::hash::BuildHasherDefault;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 
 use twox_hash::XxHash;
 
-use crate::reader::{TESFile, TESReader};
+use crate::entry::Entry;
+use crate::layout::{LayoutReport, Structure};
+use crate::reader::TESReader;
+use crate::verify::{Problem, VerifyReport};
 use crate::{dump_to_file, Result};
 
+#[cfg(feature = "async")]
+use crate::prefetch::parse_async;
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncSeek};
+
 pub type FileMap<F> = HashMap<PathBuf, F, BuildHasherDefault<XxHash>>;
 
 /// List of file extensions
@@ -33,16 +43,23 @@ impl<'a> ExtensionSet<'a> {
     }
 }
 
-pub struct Archive<H, F> {
-    /// Path on disk to this file
-    pub path: PathBuf,
+pub struct Archive<H, F, R = BufReader<File>> {
     /// Header containing metadata for the entire archive
     pub header: H,
     /// HashMap mapping file paths to files
     pub file_hashmap: FileMap<F>,
+    /// Reader over the whole archive that file data is read from
+    pub(crate) reader: R,
+}
+
+impl<H, F, R> Archive<H, F, R> {
+    /// Gives back the reader the archive was parsed from
+    pub fn into_reader(self) -> R {
+        self.reader
+    }
 }
 
-impl<H, F: Extract> Archive<H, F> {
+impl<H, F: Extract, R: BufRead + Seek> Archive<H, F, R> {
     /// Given a set of extensions, find all of the files that match it
     fn get_by_extension(&self, extension_set: &ExtensionSet) -> Vec<&Path> {
         let mut file_names = Vec::new();
@@ -60,35 +77,179 @@ impl<H, F: Extract> Archive<H, F> {
                 }
             }
 
-            println!("{:#?}", file_name);
             file_names.push(file_name);
         }
         file_names
     }
 
     /// Given a set of extensions
-    pub fn extract_by_extension(&self, extension_set: &ExtensionSet, output_dir: &Path) -> Result<()> {
-        let file_names = self.get_by_extension(&extension_set);
-        if output_dir != Path::new("") && !file_names.is_empty() {
-            let mut reader = TESReader::from_file(&self.path)?;
-            for file_name in file_names {
-                let file_data = self.extract_by_name(&mut reader, file_name)?;
-                dump_to_file(&output_dir, &file_name, &file_data)?
+    pub fn extract_by_extension(&mut self, extension_set: &ExtensionSet, output_dir: &Path) -> Result<()> {
+        if output_dir == Path::new("") {
+            for file_name in self.get_by_extension(extension_set) {
+                println!("{:#?}", file_name);
             }
+            return Ok(());
+        }
+
+        self.for_each_matching(extension_set, |file_name, file_data| {
+            println!("{:#?}", file_name);
+            dump_to_file(output_dir, file_name, &file_data)
+        })
+    }
+
+    /// Given a set of extensions, extracts every file that matches it and passes its path and content to `visitor`.
+    ///
+    /// Extraction stops at the first error returned by either the archive or the visitor.
+    pub fn for_each_matching<V>(&mut self, extension_set: &ExtensionSet, mut visitor: V) -> Result<()>
+    where
+        V: FnMut(&Path, Vec<u8>) -> Result<()>,
+    {
+        let file_names: Vec<PathBuf> = self
+            .get_by_extension(extension_set)
+            .into_iter()
+            .map(Path::to_path_buf)
+            .collect();
+        for file_name in file_names {
+            let file_data = self.extract_by_name(&file_name)?;
+            visitor(&file_name, file_data)?;
         }
         Ok(())
     }
 
+    /// Given a set of extensions, extracts every file that matches it into a map of file paths to file content
+    pub fn extract_to_memory(&mut self, extension_set: &ExtensionSet) -> Result<FileMap<Vec<u8>>> {
+        let mut file_contents: FileMap<Vec<u8>> = Default::default();
+        self.for_each_matching(extension_set, |file_name, file_data| {
+            file_contents.insert(file_name.to_path_buf(), file_data);
+            Ok(())
+        })?;
+        Ok(file_contents)
+    }
+
     /// Given a file path, extracts the file content from the BSA
-    pub fn extract_by_name(&self, reader: &mut TESFile, file_path: &Path) -> Result<Vec<u8>> {
+    pub fn extract_by_name(&mut self, file_path: &Path) -> Result<Vec<u8>> {
         let file_record = self
             .file_hashmap
             .get(file_path)
             .ok_or_else(|| format_err!("File {:#?} not found", file_path))?;
-        file_record.extract(reader)
+        file_record.extract(&mut TESReader::from_reader(&mut self.reader)?)
+    }
+
+    /// Given a file path, collects all of the metadata the archive has about that file
+    pub fn entry(&mut self, file_path: &Path) -> Result<Entry> {
+        let file_record = self
+            .file_hashmap
+            .get(file_path)
+            .ok_or_else(|| format_err!("File {:#?} not found", file_path))?;
+        file_record.entry(&mut TESReader::from_reader(&mut self.reader)?, file_path)
+    }
+
+    /// Collects the metadata of every file in the archive, in the same order as the file records in the archive
+    pub fn entries(&mut self) -> Result<Vec<Entry>> {
+        let mut reader = TESReader::from_reader(&mut self.reader)?;
+        let mut entries = self
+            .file_hashmap
+            .iter()
+            .map(|(file_name, file_record)| file_record.entry(&mut reader, file_name))
+            .collect::<Result<Vec<Entry>>>()?;
+        entries.sort_by_key(|entry| entry.index);
+        Ok(entries)
+    }
+
+    /// Checks every file in the archive: its data must lie within the archive, must not overlap with the data of any
+    /// other file, and must extract to exactly what the file records declare
+    pub fn verify_contents(&mut self) -> Result<VerifyReport> {
+        let mut reader = TESReader::from_reader(&mut self.reader)?;
+        let archive_len = reader.seek(SeekFrom::End(0))?;
+
+        // sort the file names so that the report comes out in the same order every time
+        let mut file_names: Vec<&PathBuf> = self.file_hashmap.keys().collect();
+        file_names.sort();
+
+        let mut report = VerifyReport::default();
+        let mut blocks = Vec::new();
+        for file_name in file_names {
+            let file_record = &self.file_hashmap[file_name];
+            let mut in_bounds = true;
+            for (offset, size) in file_record.data_blocks() {
+                match offset.checked_add(size) {
+                    Some(end) if end <= archive_len => blocks.push((offset, size, file_name.as_path())),
+                    _ => {
+                        report.add(
+                            file_name,
+                            Problem::OutOfBounds {
+                                offset,
+                                size,
+                                archive_len,
+                            },
+                        );
+                        in_bounds = false;
+                    }
+                }
+            }
+
+            // only try to extract files whose data can actually be read
+            if in_bounds {
+                for problem in file_record.verify(&mut reader, file_name) {
+                    report.add(file_name, problem);
+                }
+            }
+            report.files_checked += 1;
+        }
+        report.find_overlaps(blocks);
+
+        Ok(report)
+    }
+}
+
+impl<H, F: Extract, R: BufRead + Seek> Archive<H, F, R>
+where
+    Self: Structure,
+{
+    /// Maps every byte of the archive to the header, records, names or file data it belongs to. Reports slack space
+    /// that nothing refers to, regions that overlap and payloads that are stored more than once.
+    pub fn layout(&mut self) -> Result<LayoutReport> {
+        let structure = self.structure()?;
+        let mut reader = TESReader::from_reader(&mut self.reader)?;
+        let blocks = self
+            .file_hashmap
+            .iter()
+            .flat_map(|(file_name, file_record)| {
+                file_record
+                    .data_blocks()
+                    .into_iter()
+                    .map(move |(offset, size)| (offset, size, file_name.as_path()))
+            })
+            .collect();
+        LayoutReport::build(&mut reader, structure, blocks)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<H, F: Extract, R: AsyncRead + AsyncSeek + Unpin> Archive<H, F, R> {
+    /// Given a file path, extracts the file content from an archive that was opened asynchronously. Only the file's own
+    /// data is read, decompression happens on the calling task.
+    pub async fn extract_async(&mut self, file_path: &Path) -> Result<Vec<u8>> {
+        let file_record = self
+            .file_hashmap
+            .get(file_path)
+            .ok_or_else(|| format_err!("File {:#?} not found", file_path))?;
+        parse_async(&mut self.reader, &file_record.data_blocks(), |prefetched| {
+            file_record.extract(prefetched)
+        })
+        .await
     }
 }
 
 pub trait Extract {
-    fn extract(&self, reader: &mut TESFile) -> Result<Vec<u8>>;
+    fn extract<B: BufRead + Seek>(&self, reader: &mut TESReader<B>) -> Result<Vec<u8>>;
+
+    /// Collects all of the metadata about this file, reading it from the archive if it isn't in the file record
+    fn entry<B: BufRead + Seek>(&self, reader: &mut TESReader<B>, file_path: &Path) -> Result<Entry>;
+
+    /// Lists the (offset, size) of every block of raw data belonging to this file
+    fn data_blocks(&self) -> Vec<(u64, u64)>;
+
+    /// Extracts this file and reports every way the result differs from what the archive declares
+    fn verify<B: BufRead + Seek>(&self, reader: &mut TESReader<B>, file_path: &Path) -> Vec<Problem>;
 }
@@ -0,0 +1,133 @@
+//! Repacking of BSA archives into the BA2 archives used by Fallout 4
+//!
+//! Fallout 4 keeps textures apart from every other kind of file, so a single BSA archive turns into a general archive
+//! named "<Mod> - Main.ba2" and a texture archive named "<Mod> - Textures.ba2".
+use std::io::{BufRead, Seek};
+use std::path::{Path, PathBuf};
+
+use failure::ResultExt;
+
+// top-level imports
+use crate::Result;
+
+// BA2 imports
+use crate::ba2::editor::BA2Editor;
+use crate::ba2::types::{BA2Type, BA2Version};
+
+// bsa imports
+use crate::bsa::BSAArchive;
+
+/// Repacks every file of `archive` into Fallout 4 archives for the mod `mod_name` inside `output_dir`. DDS files go
+/// into the texture archive and everything else into the general one. Archives that would be empty aren't written,
+/// the paths of the ones that were are returned.
+///
+/// Files are extracted one at a time while the archives are written, so the content of the whole archive is never held
+/// in memory. Textures are extracted once more up front to read their DDS headers.
+pub fn from_bsa<R>(archive: &mut BSAArchive<R>, output_dir: &Path, mod_name: &str) -> Result<Vec<PathBuf>>
+where
+    R: BufRead + Seek,
+{
+    let mut general = BA2Editor::new(BA2Version::Fallout4, BA2Type::General);
+    let mut textures = BA2Editor::new(BA2Version::Fallout4, BA2Type::Textures);
+    let (mut num_general, mut num_textures) = (0, 0);
+    // files are added by their index in here, which is how they're found again while saving
+    let mut file_paths: Vec<PathBuf> = archive.file_hashmap.keys().cloned().collect();
+    file_paths.sort();
+    for (key, file_path) in file_paths.iter().enumerate() {
+        let is_texture = file_path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("dds"));
+        if is_texture {
+            textures
+                .add_external(file_path, key, || archive.extract_by_name(file_path))
+                .with_context(|_| format!("Unable to add texture {:#?}", file_path))?;
+            num_textures += 1;
+        } else {
+            general.add_external(file_path, key, || archive.extract_by_name(file_path))?;
+            num_general += 1;
+        }
+    }
+
+    let mut written = Vec::new();
+    for (editor, num_files, suffix) in &[(general, num_general, "Main"), (textures, num_textures, "Textures")] {
+        if *num_files > 0 {
+            let path = output_dir.join(format!("{} - {}.ba2", mod_name, suffix));
+            editor.save_with(&path, |key| archive.extract_by_name(&file_paths[key]))?;
+            written.push(path);
+        }
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ba2::types::BA2TextureHeader;
+    use crate::bsa::{BSAEditor, Version};
+
+    /// Builds a 64x64 BC1 DDS file with a single mipmap
+    fn dds_file() -> Vec<u8> {
+        let header = BA2TextureHeader {
+            num_chunks:        0,
+            chunk_header_size: 0x18,
+            height:            64,
+            width:             64,
+            num_mipmaps:       1,
+            dxgi_format:       71,
+            unknown:           0,
+            unknown_2:         0x0800,
+        };
+        let mut file = crate::ba2::dds_header(&header).unwrap();
+        file.extend((0..16 * 16 * 8).map(|i| (i % 13) as u8));
+        file
+    }
+
+    #[test]
+    fn textures_and_other_files_go_into_separate_archives() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = vec![
+            ("meshes\\armor\\helmet.nif", b"helmet mesh".to_vec()),
+            ("scripts\\quest.pex", b"script".to_vec()),
+            ("textures\\armor\\helmet.dds", dds_file()),
+            ("textures\\armor\\boots.dds", dds_file()),
+        ];
+        let mut editor = BSAEditor::new(Version::SKYRIMSE).unwrap();
+        for (file_path, contents) in &files {
+            editor.add(Path::new(file_path), contents.clone()).unwrap();
+        }
+        let source = dir.path().join("source.bsa");
+        editor.save(&source).unwrap();
+
+        let mut archive = crate::bsa::from_file(source).unwrap();
+        let written = from_bsa(&mut archive, dir.path(), "Mod").unwrap();
+        let (main, textures) = (dir.path().join("Mod - Main.ba2"), dir.path().join("Mod - Textures.ba2"));
+        assert_eq!(written, vec![main.clone(), textures.clone()]);
+
+        let mut main = crate::ba2::from_file(main).unwrap();
+        let mut textures = crate::ba2::from_file(textures).unwrap();
+        assert_eq!((main.header.file_type, main.header.file_count), (BA2Type::General, 2));
+        assert_eq!((textures.header.file_type, textures.header.file_count), (BA2Type::Textures, 2));
+        for (file_path, contents) in &files {
+            let converted = match file_path.ends_with(".dds") {
+                true => &mut textures,
+                false => &mut main,
+            };
+            assert_eq!(&converted.extract_by_name(Path::new(file_path)).unwrap(), contents, "{}", file_path);
+        }
+    }
+
+    #[test]
+    fn empty_archives_are_not_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut editor = BSAEditor::new(Version::SKYRIMSE).unwrap();
+        editor.add(Path::new("scripts/quest.pex"), b"script".to_vec()).unwrap();
+        let source = dir.path().join("source.bsa");
+        editor.save(&source).unwrap();
+
+        let mut archive = crate::bsa::from_file(source).unwrap();
+        let written = from_bsa(&mut archive, dir.path(), "Mod").unwrap();
+        assert_eq!(written, vec![dir.path().join("Mod - Main.ba2")]);
+        assert!(!dir.path().join("Mod - Textures.ba2").exists());
+    }
+}
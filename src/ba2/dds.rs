@@ -0,0 +1,247 @@
+//! DirectDraw Surface headers for textures stored in DX10 BA2 archives
+//!
+//! Texture archives only store the pixel data of each texture, so a DDS header has to be rebuilt from the texture
+//! record to turn the data back into a file. Formats that older tools know by a FourCC code are written with that code,
+//! every other format gets the extended DX10 header. Going the other way, a DDS file is split into its header and
+//! chunks of mipmaps.
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+// top-level imports
+use crate::Result;
+
+// BA2 imports
+use crate::ba2::types::BA2TextureHeader;
+
+/// Size of the DDS header, not counting the magic
+const HEADER_SIZE: u32 = 124;
+/// Size of the pixel format structure inside the header
+const PIXEL_FORMAT_SIZE: u32 = 32;
+/// Size of a single texture chunk record
+const TEXTURE_CHUNK_LEN: u16 = 0x18;
+
+/// DDSD_CAPS | DDSD_HEIGHT | DDSD_WIDTH | DDSD_PIXELFORMAT | DDSD_MIPMAPCOUNT
+const DDSD_DEFAULT: u32 = 0x1 | 0x2 | 0x4 | 0x1000 | 0x2_0000;
+const DDSD_PITCH: u32 = 0x8;
+const DDSD_LINEARSIZE: u32 = 0x8_0000;
+const DDPF_FOURCC: u32 = 0x4;
+/// DDSCAPS_COMPLEX | DDSCAPS_TEXTURE | DDSCAPS_MIPMAP
+const DDSCAPS_DEFAULT: u32 = 0x8 | 0x1000 | 0x40_0000;
+/// DDSCAPS2_CUBEMAP and all six of its faces
+const DDSCAPS2_CUBEMAP_ALL_FACES: u32 = 0xFE00;
+/// D3D10_RESOURCE_DIMENSION_TEXTURE2D
+const DIMENSION_TEXTURE2D: u32 = 3;
+/// D3D10_RESOURCE_MISC_TEXTURECUBE
+const MISC_TEXTURECUBE: u32 = 0x4;
+/// Bit of the texture record's flags that marks a cubemap
+const CUBEMAP_FLAG: u16 = 0x1;
+/// Tile mode that Bethesda's archive tool writes into the upper byte of the texture record's flags
+const DEFAULT_TILE_MODE: u16 = 0x0800;
+/// DDPF_RGB
+const DDPF_RGB: u32 = 0x40;
+/// Size of the magic and the header of a DDS file
+const DDS_HEADER_LEN: usize = 128;
+/// Size of the extended DX10 header that follows the header
+const DX10_HEADER_LEN: usize = 20;
+/// Mipmaps smaller than this are all stored together in the last chunk of a texture
+const MIN_CHUNK_SIZE: usize = 0x1_0000;
+
+/// A DDS file taken apart into what a texture archive stores
+pub(crate) struct DDSTexture<'a> {
+    pub header: BA2TextureHeader,
+    /// (size, first mipmap, last mipmap) of every chunk, in the order the chunks appear in `data`
+    pub chunks: Vec<(usize, u16, u16)>,
+    /// Pixel data of every mipmap, without any headers
+    pub data:   &'a [u8],
+}
+
+/// How the pixels of a DXGI format are laid out
+enum Layout {
+    /// Compressed into 4x4 blocks of the given number of bytes
+    Block(u32),
+    /// Stored uncompressed with the given number of bits per pixel
+    Pixel(u32),
+}
+
+/// Looks up the layout of a DXGI format and the FourCC code that it's written with, if it has one
+fn format_info(dxgi_format: u8) -> Result<(Layout, Option<&'static [u8; 4]>)> {
+    let info = match dxgi_format {
+        // DXGI_FORMAT_R8G8B8A8_UNORM(_SRGB), DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_FORMAT_B8G8R8X8_UNORM,
+        // DXGI_FORMAT_B8G8R8A8_UNORM_SRGB
+        28 | 29 | 87 | 88 | 91 => (Layout::Pixel(32), None),
+        // DXGI_FORMAT_R8G8_UNORM
+        49 => (Layout::Pixel(16), None),
+        // DXGI_FORMAT_R8_UNORM
+        61 => (Layout::Pixel(8), None),
+        // DXGI_FORMAT_BC1_UNORM
+        71 => (Layout::Block(8), Some(b"DXT1")),
+        // DXGI_FORMAT_BC1_UNORM_SRGB
+        72 => (Layout::Block(8), None),
+        // DXGI_FORMAT_BC2_UNORM
+        74 => (Layout::Block(16), Some(b"DXT3")),
+        // DXGI_FORMAT_BC2_UNORM_SRGB
+        75 => (Layout::Block(16), None),
+        // DXGI_FORMAT_BC3_UNORM
+        77 => (Layout::Block(16), Some(b"DXT5")),
+        // DXGI_FORMAT_BC3_UNORM_SRGB
+        78 => (Layout::Block(16), None),
+        // DXGI_FORMAT_BC4_UNORM, DXGI_FORMAT_BC4_SNORM
+        80 | 81 => (Layout::Block(8), None),
+        // DXGI_FORMAT_BC5_UNORM, DXGI_FORMAT_BC5_SNORM, DXGI_FORMAT_BC6H_UF16, DXGI_FORMAT_BC6H_SF16,
+        // DXGI_FORMAT_BC7_UNORM, DXGI_FORMAT_BC7_UNORM_SRGB
+        83 | 84 | 95 | 96 | 98 | 99 => (Layout::Block(16), None),
+        _ => return Err(format_err!("Unsupported DXGI texture format {}", dxgi_format)),
+    };
+    Ok(info)
+}
+
+/// Builds the DDS magic and header(s) that go in front of a texture's data
+pub(crate) fn dds_header(texture: &BA2TextureHeader) -> Result<Vec<u8>> {
+    let (layout, four_cc) = format_info(texture.dxgi_format)?;
+    let width = u32::from(texture.width);
+    let height = u32::from(texture.height);
+    let is_cubemap = texture.unknown_2 & CUBEMAP_FLAG != 0;
+
+    // block compressed formats record the size of the top mipmap, uncompressed ones record the size of one row
+    let (size_flag, pitch_or_linear_size) = match layout {
+        Layout::Block(block_size) => (
+            DDSD_LINEARSIZE,
+            ((width + 3) / 4).max(1) * ((height + 3) / 4).max(1) * block_size,
+        ),
+        Layout::Pixel(bits) => (DDSD_PITCH, (width * bits + 7) / 8),
+    };
+
+    let mut header = Vec::with_capacity(148);
+    header.extend_from_slice(b"DDS ");
+    header.write_u32::<LittleEndian>(HEADER_SIZE)?;
+    header.write_u32::<LittleEndian>(DDSD_DEFAULT | size_flag)?;
+    header.write_u32::<LittleEndian>(height)?;
+    header.write_u32::<LittleEndian>(width)?;
+    header.write_u32::<LittleEndian>(pitch_or_linear_size)?;
+    // depth
+    header.write_u32::<LittleEndian>(0)?;
+    header.write_u32::<LittleEndian>(u32::from(texture.num_mipmaps))?;
+    // reserved
+    header.extend_from_slice(&[0; 44]);
+
+    // pixel format
+    header.write_u32::<LittleEndian>(PIXEL_FORMAT_SIZE)?;
+    header.write_u32::<LittleEndian>(DDPF_FOURCC)?;
+    header.extend_from_slice(four_cc.unwrap_or(b"DX10"));
+    // bit count and the four channel masks, which FourCC formats don't use
+    header.extend_from_slice(&[0; 20]);
+
+    header.write_u32::<LittleEndian>(DDSCAPS_DEFAULT)?;
+    header.write_u32::<LittleEndian>(if is_cubemap { DDSCAPS2_CUBEMAP_ALL_FACES } else { 0 })?;
+    // caps3, caps4 and reserved
+    header.extend_from_slice(&[0; 12]);
+
+    if four_cc.is_none() {
+        header.write_u32::<LittleEndian>(u32::from(texture.dxgi_format))?;
+        header.write_u32::<LittleEndian>(DIMENSION_TEXTURE2D)?;
+        header.write_u32::<LittleEndian>(if is_cubemap { MISC_TEXTURECUBE } else { 0 })?;
+        // array size
+        header.write_u32::<LittleEndian>(1)?;
+        // misc flags 2
+        header.write_u32::<LittleEndian>(0)?;
+    }
+
+    Ok(header)
+}
+
+/// Splits a DDS file into a texture record and chunks of its pixel data. Each mipmap of at least 64 KiB gets a chunk
+/// of its own and all of the smaller ones share the last chunk. Cubemaps are always stored in a single chunk, because
+/// their faces are stored one after the other with all of their mipmaps.
+pub(crate) fn parse_dds(file: &[u8]) -> Result<DDSTexture<'_>> {
+    if file.len() < DDS_HEADER_LEN || &file[..4] != b"DDS " {
+        return Err(format_err!("Not a DDS file"));
+    }
+    let height = LittleEndian::read_u32(&file[12..16]);
+    let width = LittleEndian::read_u32(&file[16..20]);
+    let num_mipmaps = LittleEndian::read_u32(&file[28..32]).max(1);
+    let pixel_flags = LittleEndian::read_u32(&file[80..84]);
+    let four_cc = &file[84..88];
+    let bit_count = LittleEndian::read_u32(&file[88..92]);
+    let red_mask = LittleEndian::read_u32(&file[92..96]);
+    let is_cubemap = LittleEndian::read_u32(&file[112..116]) & DDSCAPS2_CUBEMAP_ALL_FACES != 0;
+    if width > u32::from(u16::MAX) || height > u32::from(u16::MAX) || num_mipmaps > u32::from(u8::MAX) {
+        return Err(format_err!("A {}x{} texture with {} mipmaps is too large", width, height, num_mipmaps));
+    }
+
+    let (dxgi_format, data_start) = if pixel_flags & DDPF_FOURCC != 0 {
+        match four_cc {
+            b"DX10" => {
+                if file.len() < DDS_HEADER_LEN + DX10_HEADER_LEN {
+                    return Err(format_err!("DDS file is too short to hold a DX10 header"));
+                }
+                let dxgi_format = LittleEndian::read_u32(&file[128..132]);
+                if dxgi_format > u32::from(u8::MAX) {
+                    return Err(format_err!("Unsupported DXGI texture format {}", dxgi_format));
+                }
+                (dxgi_format as u8, DDS_HEADER_LEN + DX10_HEADER_LEN)
+            }
+            b"DXT1" => (71, DDS_HEADER_LEN),
+            b"DXT3" => (74, DDS_HEADER_LEN),
+            b"DXT5" => (77, DDS_HEADER_LEN),
+            b"ATI1" | b"BC4U" => (80, DDS_HEADER_LEN),
+            b"BC4S" => (81, DDS_HEADER_LEN),
+            b"ATI2" | b"BC5U" => (83, DDS_HEADER_LEN),
+            b"BC5S" => (84, DDS_HEADER_LEN),
+            _ => return Err(format_err!("Unsupported DDS FourCC code {:?}", four_cc)),
+        }
+    } else if pixel_flags & DDPF_RGB != 0 && bit_count == 32 {
+        // DXGI formats are named by the order of the channels from the lowest bit up
+        let dxgi_format = if red_mask == 0x0000_00ff { 28 } else { 87 };
+        (dxgi_format, DDS_HEADER_LEN)
+    } else {
+        return Err(format_err!("Unsupported DDS pixel format"));
+    };
+    let (layout, _) = format_info(dxgi_format)?;
+
+    // work out the size of every mipmap from the largest one down
+    let mut mipmap_sizes = Vec::with_capacity(num_mipmaps as usize);
+    let (mut mip_width, mut mip_height) = (u64::from(width), u64::from(height));
+    for _ in 0..num_mipmaps {
+        mipmap_sizes.push(match layout {
+            Layout::Block(block_size) => {
+                ((mip_width + 3) / 4).max(1) * ((mip_height + 3) / 4).max(1) * u64::from(block_size)
+            }
+            Layout::Pixel(bits) => (mip_width * mip_height * u64::from(bits) + 7) / 8,
+        } as usize);
+        mip_width = (mip_width / 2).max(1);
+        mip_height = (mip_height / 2).max(1);
+    }
+    let faces = if is_cubemap { 6 } else { 1 };
+    let data_len = mipmap_sizes.iter().sum::<usize>() * faces;
+    let data = file
+        .get(data_start..)
+        .and_then(|data| data.get(..data_len))
+        .ok_or_else(|| format_err!("DDS file is too short to hold all of its mipmaps"))?;
+
+    let last_mipmap = (num_mipmaps - 1) as u16;
+    let mut chunks = Vec::new();
+    if is_cubemap {
+        chunks.push((data_len, 0, last_mipmap));
+    } else {
+        for (mipmap, &size) in mipmap_sizes.iter().enumerate() {
+            let mipmap = mipmap as u16;
+            if size < MIN_CHUNK_SIZE || mipmap == last_mipmap {
+                let tail_size = mipmap_sizes[mipmap as usize..].iter().sum();
+                chunks.push((tail_size, mipmap, last_mipmap));
+                break;
+            }
+            chunks.push((size, mipmap, mipmap));
+        }
+    }
+
+    let header = BA2TextureHeader {
+        num_chunks: chunks.len(),
+        chunk_header_size: TEXTURE_CHUNK_LEN,
+        height: height as u16,
+        width: width as u16,
+        num_mipmaps: num_mipmaps as u8,
+        dxgi_format,
+        unknown: 0,
+        unknown_2: DEFAULT_TILE_MODE | if is_cubemap { CUBEMAP_FLAG } else { 0 },
+    };
+    Ok(DDSTexture { header, chunks, data })
+}
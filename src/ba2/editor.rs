@@ -0,0 +1,912 @@
+//! Editing and rewriting of BA2 archives
+//!
+//! Files that aren't touched keep their records, hashes and data chunks exactly as they were in the source archive,
+//! along with any padding in front of their data chunks, so saving an archive without any edits reproduces it byte for
+//! byte. Archives can also be created from scratch, in which case the files added to a texture archive have to be DDS
+//! files.
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use failure::ResultExt;
+
+// top-level imports
+use crate::reader::{string_to_latin1, TESFile, TESReader};
+use crate::writer::{
+    archive_path, check_new_path, copy_block, normalize_path, read_block, source_reader, write_atomically, Deduplicator,
+    SourceGaps,
+};
+use crate::{CompressionPolicy, Result, WriteReport};
+
+// BA2 imports
+use crate::ba2::dds::parse_dds;
+use crate::ba2::hash::hash_path;
+use crate::ba2::types::*;
+
+/// All BA2 general file records are 36 (0x24) bytes
+const GENERAL_FILE_LEN: u64 = 0x24;
+/// All BA2 texture header records are 24 (0x18) bytes
+const TEXTURE_HEADER_LEN: u64 = 0x18;
+/// All BA2 texture chunk records are 24 (0x18) bytes
+const TEXTURE_CHUNK_LEN: u64 = 0x18;
+/// Value that every file record and chunk record ends with
+const RECORD_MAGIC: u32 = 0xBAAD_F00D;
+/// Value of the unknown flags that Bethesda's archive tool writes for general files
+const DEFAULT_UNKNOWN_FLAGS: u32 = 0x0010_0100;
+
+/// Where the data of a single file comes from
+enum BA2Contents {
+    /// Data chunks copied byte for byte from the source archive
+    Source(Vec<BA2FileChunk>),
+    /// New uncompressed file content, which is compressed under the editor's policy when saving
+    New(Vec<u8>),
+    /// New uncompressed texture data, split into chunks of (size, first mipmap, last mipmap)
+    NewTexture {
+        data:   Vec<u8>,
+        chunks: Vec<(usize, u16, u16)>,
+    },
+    /// File content that is only loaded while saving, by handing its key to the loader passed to `save_with`
+    External(usize),
+    /// DDS file that is only loaded while saving, whose texture data is split into chunks like `NewTexture`
+    ExternalTexture {
+        key:    usize,
+        chunks: Vec<(usize, u16, u16)>,
+    },
+}
+
+/// A data chunk that was compressed under the editor's policy
+struct EncodedChunk {
+    data:              Vec<u8>,
+    /// Size of the data while compressed (0 if it isn't compressed)
+    compressed_size:   usize,
+    uncompressed_size: usize,
+}
+
+struct EditFile {
+    /// Path of the file exactly as it's stored in the name table
+    name:          String,
+    name_hash:     u32,
+    extension:     [u8; 4],
+    dir_hash:      u32,
+    unknown_flags: u32,
+    texture:       Option<BA2TextureHeader>,
+    contents:      BA2Contents,
+}
+
+/// A BA2 archive whose files can be replaced, added, removed and renamed before saving it back out
+pub struct BA2Editor {
+    /// Path on disk to the archive being edited (`None` for archives created from scratch)
+    source: Option<PathBuf>,
+    /// Header of the source archive, whose counts and offsets are recalculated on save
+    header: BA2Header,
+    files:  Vec<EditFile>,
+    /// Decides how new and re-encoded files are compressed
+    policy: CompressionPolicy,
+    /// Whether chunks with identical content are only written once
+    deduplicate: bool,
+    /// Unused space between the data chunks of the source archive, which is kept around the chunks that are copied
+    source_gaps: SourceGaps,
+}
+
+/// Where a single data chunk ends up in the saved archive
+#[derive(Clone, Copy)]
+enum PlannedChunk {
+    /// Copied straight from the source archive
+    Copy { offset: u64, size: u64 },
+    /// New or re-encoded content of chunk `chunk` of file `file`, which is only produced when it's written
+    Encode { file: usize, chunk: usize },
+}
+
+/// Everything that chunk data is read from while saving
+struct ChunkSource<'r, 'l> {
+    /// The source archive, if there is one
+    reader:   Option<&'r mut TESFile>,
+    /// Loads the content of files that were added with `add_external`
+    load:     &'l mut dyn FnMut(usize) -> Result<Vec<u8>>,
+    /// Key and content of the external texture that was loaded last, since its chunks are written one after another
+    external: Option<(usize, Vec<u8>)>,
+}
+
+impl EditFile {
+    /// Order in which files are added to the archive: by directory hash, file hash and then path
+    fn sort_key(&self) -> (u32, u32, [u8; 4], String) {
+        (self.dir_hash, self.name_hash, self.extension, normalize_path(Path::new(&self.name)))
+    }
+
+    /// Count of data chunks the file is stored in
+    fn chunk_count(&self) -> usize {
+        match self.contents {
+            BA2Contents::Source(ref chunks) => chunks.len(),
+            BA2Contents::New(_) | BA2Contents::External(_) => 1,
+            BA2Contents::NewTexture { ref chunks, .. } | BA2Contents::ExternalTexture { ref chunks, .. } => {
+                chunks.len()
+            }
+        }
+    }
+}
+
+impl<'r, 'l> ChunkSource<'r, 'l> {
+    /// Loads an external texture, or reuses it if it was the last one loaded
+    fn external_texture(&mut self, key: usize) -> Result<&[u8]> {
+        match self.external {
+            Some((loaded_key, _)) if loaded_key == key => (),
+            _ => self.external = Some((key, (self.load)(key)?)),
+        }
+        Ok(self.external.as_ref().map_or(&[], |(_, data)| data.as_slice()))
+    }
+}
+
+/// Cuts chunk `chunk` out of texture data that is split into chunks of (size, first mipmap, last mipmap)
+fn texture_chunk<'a>(data: &'a [u8], chunks: &[(usize, u16, u16)], chunk: usize) -> Result<&'a [u8]> {
+    let start: usize = chunks[..chunk].iter().map(|&(size, _, _)| size).sum();
+    let end = start + chunks[chunk].0;
+    data.get(start..end)
+        .ok_or_else(|| format_err!("Texture data ends before chunk {} at byte {}", chunk, end))
+}
+
+impl BA2Editor {
+    /// Given a file path to a BA2 file, opens it for editing
+    pub fn from_file(path: PathBuf) -> Result<BA2Editor> {
+        let archive = crate::ba2::from_file(path.clone())?;
+
+        let mut files: Vec<(PathBuf, BA2File)> = archive.file_hashmap.into_iter().collect();
+        files.sort_by_key(|(_, file)| file.index);
+        let files = files
+            .into_iter()
+            .map(|(file_name, file)| EditFile {
+                name:          file_name.to_string_lossy().into_owned(),
+                name_hash:     file.name_hash,
+                extension:     file.extension,
+                dir_hash:      file.dir_hash,
+                unknown_flags: file.unknown_flags,
+                texture:       file.header,
+                contents:      BA2Contents::Source(file.chunks),
+            })
+            .collect();
+
+        let mut editor = BA2Editor {
+            source: Some(path),
+            header: archive.header,
+            files,
+            policy: CompressionPolicy::default(),
+            deduplicate: false,
+            source_gaps: SourceGaps::default(),
+        };
+        // the data chunks sit between the file records and the name table, or the end of the file without one
+        let data_start = editor.header.version.header_len() as u64 + editor.records_len();
+        let data_end = match editor.header.name_table_offset {
+            0 => archive.reader.into_inner().seek(SeekFrom::End(0))?,
+            name_table_offset => name_table_offset,
+        };
+        let chunks = editor.files.iter().flat_map(|file| match file.contents {
+            BA2Contents::Source(ref chunks) => chunks.as_slice(),
+            _ => &[],
+        });
+        let blocks = chunks.map(|chunk| (chunk.content_offset, chunk.stored_size() as u64));
+        editor.source_gaps = SourceGaps::new(data_start, data_end, blocks);
+        Ok(editor)
+    }
+
+    /// Creates an empty archive of the given version and type. Files with identical content share their data unless
+    /// deduplication is turned off.
+    pub fn new(version: BA2Version, file_type: BA2Type) -> BA2Editor {
+        let unknown = match version {
+            BA2Version::Starfield | BA2Version::StarfieldCompressed => Some([1, 0]),
+            _ => None,
+        };
+        // Starfield compresses everything in version 3 archives as LZ4 blocks
+        let compression_format = match version {
+            BA2Version::StarfieldCompressed => Some(3),
+            _ => None,
+        };
+        BA2Editor {
+            source: None,
+            header: BA2Header {
+                version,
+                file_type,
+                file_count: 0,
+                name_table_offset: 0,
+                unknown,
+                compression_format,
+            },
+            files: Vec::new(),
+            policy: CompressionPolicy::default(),
+            deduplicate: true,
+            source_gaps: SourceGaps::default(),
+        }
+    }
+
+    /// Sets how new files are compressed when the archive is saved. Files that came from the source archive are
+    /// only compressed again if the policy doesn't reuse compressed data.
+    pub fn set_compression_policy(&mut self, policy: CompressionPolicy) {
+        self.policy = policy;
+    }
+
+    /// Sets whether chunks with identical content are written only once, with every record that uses them pointing at
+    /// the same data. This is off for archives opened from a file, so that saving them without edits reproduces the
+    /// source archive.
+    pub fn set_deduplicate(&mut self, deduplicate: bool) {
+        self.deduplicate = deduplicate;
+    }
+
+    /// Replaces the content of an existing file. Files in texture archives have to be replaced with DDS files.
+    pub fn replace(&mut self, file_path: &Path, contents: Vec<u8>) -> Result<()> {
+        let file_index = self.find(file_path)?;
+        let (texture, contents) = self.new_contents(contents)?;
+        let file = &mut self.files[file_index];
+        file.texture = texture;
+        file.contents = contents;
+        Ok(())
+    }
+
+    /// Adds a new file to the archive. Files added to texture archives have to be DDS files.
+    ///
+    /// New files are placed in front of the first file that sorts after them by directory hash, file hash and path, so
+    /// an archive created from scratch comes out the same no matter what order its files were added in. Existing files
+    /// are never reordered.
+    pub fn add(&mut self, file_path: &Path, contents: Vec<u8>) -> Result<()> {
+        self.check_new_file(file_path)?;
+        let (texture, contents) = self.new_contents(contents)?;
+        self.insert(file_path, texture, contents);
+        Ok(())
+    }
+
+    /// Adds a new file whose content isn't kept in memory, but loaded by passing `key` to the loader given to
+    /// `save_with` while the archive is saved. Texture archives call `load` once up front to read the DDS header.
+    pub(crate) fn add_external<L>(&mut self, file_path: &Path, key: usize, load: L) -> Result<()>
+    where
+        L: FnOnce() -> Result<Vec<u8>>,
+    {
+        self.check_new_file(file_path)?;
+        let (texture, contents) = match self.header.file_type {
+            BA2Type::General => (None, BA2Contents::External(key)),
+            BA2Type::Textures => {
+                let file = load()?;
+                let texture = parse_dds(&file)?;
+                (Some(texture.header), BA2Contents::ExternalTexture {
+                    key,
+                    chunks: texture.chunks,
+                })
+            }
+        };
+        self.insert(file_path, texture, contents);
+        Ok(())
+    }
+
+    /// Checks that a file can be added at `file_path`
+    fn check_new_file(&self, file_path: &Path) -> Result<()> {
+        check_new_path(file_path)?;
+        if self.find(file_path).is_ok() {
+            return Err(format_err!("File {:#?} already exists", file_path));
+        }
+        Ok(())
+    }
+
+    /// Adds a file in front of the first file that sorts after it
+    fn insert(&mut self, file_path: &Path, texture: Option<BA2TextureHeader>, contents: BA2Contents) {
+        let hashes = hash_path(file_path);
+        let sort_key = (hashes.dir_hash, hashes.name_hash, hashes.extension, normalize_path(file_path));
+        let file_index = self
+            .files
+            .iter()
+            .position(|file| file.sort_key() > sort_key)
+            .unwrap_or(self.files.len());
+        self.files.insert(file_index, EditFile {
+            name: archive_path(file_path),
+            name_hash: hashes.name_hash,
+            extension: hashes.extension,
+            dir_hash: hashes.dir_hash,
+            unknown_flags: if texture.is_some() { 0 } else { DEFAULT_UNKNOWN_FLAGS },
+            texture,
+            contents,
+        });
+    }
+
+    /// Removes a file from the archive
+    pub fn remove(&mut self, file_path: &Path) -> Result<()> {
+        let file_index = self.find(file_path)?;
+        self.files.remove(file_index);
+        Ok(())
+    }
+
+    /// Moves a file to a new path without changing its content or its position in the archive
+    pub fn rename(&mut self, from: &Path, to: &Path) -> Result<()> {
+        check_new_path(to)?;
+        if self.find(to).is_ok() {
+            return Err(format_err!("File {:#?} already exists", to));
+        }
+        let file_index = self.find(from)?;
+        let hashes = hash_path(to);
+        let file = &mut self.files[file_index];
+        file.name = archive_path(to);
+        file.name_hash = hashes.name_hash;
+        file.extension = hashes.extension;
+        file.dir_hash = hashes.dir_hash;
+        Ok(())
+    }
+
+    /// Writes the archive out to `path` and reports how much deduplication saved. Saving over the archive being edited
+    /// is allowed, but the editor must be opened again afterwards because the data it points to has moved.
+    pub fn save(&self, path: &Path) -> Result<WriteReport> {
+        self.save_with(path, |key| Err(format_err!("No content to load for external file {}", key)))
+    }
+
+    /// Saves the archive like `save`, loading the content of files added with `add_external` through `load`
+    pub(crate) fn save_with<L>(&self, path: &Path, mut load: L) -> Result<WriteReport>
+    where
+        L: FnMut(usize) -> Result<Vec<u8>>,
+    {
+        let mut report = WriteReport::default();
+        write_atomically(path, |writer| {
+            let mut source = ChunkSource {
+                reader:   None,
+                load:     &mut load,
+                external: None,
+            };
+            report = match self.source {
+                Some(ref source_path) => {
+                    let mut reader = TESReader::from_file(source_path)?;
+                    source.reader = Some(&mut reader);
+                    self.write(&mut source, writer)?
+                }
+                None => self.write(&mut source, writer)?,
+            };
+            Ok(())
+        })
+        .context(format!("Failed to save {:#?}", path))?;
+        Ok(report)
+    }
+
+    /// Turns new file content into what the archive stores for it. Texture archives store DDS files without their
+    /// headers, split into chunks by mipmap.
+    fn new_contents(&self, contents: Vec<u8>) -> Result<(Option<BA2TextureHeader>, BA2Contents)> {
+        match self.header.file_type {
+            BA2Type::General => Ok((None, BA2Contents::New(contents))),
+            BA2Type::Textures => {
+                let texture = parse_dds(&contents)?;
+                let contents = BA2Contents::NewTexture {
+                    data:   texture.data.to_vec(),
+                    chunks: texture.chunks,
+                };
+                Ok((Some(texture.header), contents))
+            }
+        }
+    }
+
+    /// Finds the index of a file path
+    fn find(&self, file_path: &Path) -> Result<usize> {
+        let file_path = normalize_path(file_path);
+        self.files
+            .iter()
+            .position(|file| normalize_path(Path::new(&file.name)) == file_path)
+            .ok_or_else(|| format_err!("File {:#?} not found", file_path))
+    }
+
+    /// Serializes the whole archive. Follows the Fallout 4 BA2 file structure (described in fallout4.rs)
+    ///
+    /// Chunks are read, compressed and written one at a time, so only a single chunk is held in memory at once. The
+    /// data is written first and the header and file records are filled in afterwards, once the offsets are known.
+    fn write<W: Write + Seek>(&self, source: &mut ChunkSource<'_, '_>, writer: &mut W) -> Result<WriteReport> {
+        let data_start = self.header.version.header_len() as u64 + self.records_len();
+
+        // plan out every chunk, in the same order as the file records. New content is compressed under the policy,
+        // along with the content of the source archive if it isn't reused.
+        let mut chunks = Vec::new();
+        // (compressed size, uncompressed size) of every chunk, which is only known for new content once it's encoded
+        let mut chunk_sizes = Vec::new();
+        for (file_index, file) in self.files.iter().enumerate() {
+            match file.contents {
+                BA2Contents::Source(ref source_chunks) if self.policy.reuse_compressed => {
+                    for chunk in source_chunks {
+                        chunks.push(PlannedChunk::Copy {
+                            offset: chunk.content_offset,
+                            size:   chunk.stored_size() as u64,
+                        });
+                        chunk_sizes.push((chunk.compressed_size, chunk.uncompressed_size));
+                    }
+                }
+                _ => {
+                    for chunk in 0..file.chunk_count() {
+                        chunks.push(PlannedChunk::Encode { file: file_index, chunk });
+                        chunk_sizes.push((0, 0));
+                    }
+                }
+            }
+        }
+
+        // Untouched chunks keep the order they had in the source archive, and chunks shared by several records stay
+        // shared. New content is written after them in file record order. When deduplicating, chunks whose content was
+        // already written point at the earlier copy instead.
+        let mut copy_order: Vec<usize> = (0..chunks.len())
+            .filter(|&i| matches!(chunks[i], PlannedChunk::Copy { .. }))
+            .collect();
+        copy_order.sort_by_key(|&i| match chunks[i] {
+            PlannedChunk::Copy { offset, .. } => offset,
+            PlannedChunk::Encode { .. } => 0,
+        });
+        let encode_order = (0..chunks.len()).filter(|&i| matches!(chunks[i], PlannedChunk::Encode { .. }));
+
+        // file data
+        writer.seek(SeekFrom::Start(data_start))?;
+        let mut chunk_offsets = vec![0; chunks.len()];
+        let mut shared_offsets: HashMap<(u64, u64), u64> = HashMap::new();
+        let mut deduplicator = Deduplicator::default();
+        let mut next_offset = data_start;
+        for i in copy_order.into_iter().chain(encode_order) {
+            // (offset, size) of the padding in front of the chunk in the source archive, which is copied along with it
+            let mut padding = (0, 0);
+            // the chunk's content, unless it can be copied straight from the source archive
+            let data = match chunks[i] {
+                PlannedChunk::Copy { offset, size } => {
+                    if let Some(&shared_offset) = shared_offsets.get(&(offset, size)) {
+                        chunk_offsets[i] = shared_offset;
+                        continue;
+                    }
+                    padding = self.source_gaps.before(offset);
+                    match self.deduplicate {
+                        true => Some(read_block(source_reader(&mut source.reader)?, offset, size)?),
+                        false => None,
+                    }
+                }
+                PlannedChunk::Encode { file, chunk } => {
+                    let encoded = self.encode_chunk(file, chunk, source)?;
+                    chunk_sizes[i] = (encoded.compressed_size, encoded.uncompressed_size);
+                    Some(encoded.data)
+                }
+            };
+            let chunk_offset = next_offset + padding.1;
+            let duplicate_offset = match data {
+                Some(ref data) if self.deduplicate => {
+                    let load = |index: usize| self.chunk_data(chunks[index], source);
+                    deduplicator.find(i, chunk_offset, data, load)?
+                }
+                _ => None,
+            };
+            chunk_offsets[i] = match duplicate_offset {
+                Some(offset) => offset,
+                None => {
+                    if padding.1 > 0 {
+                        copy_block(source_reader(&mut source.reader)?, padding.0, padding.1, writer)?;
+                    }
+                    let size = match (data, chunks[i]) {
+                        (Some(data), _) => {
+                            writer.write_all(&data)?;
+                            data.len() as u64
+                        }
+                        (None, PlannedChunk::Copy { offset, size }) => {
+                            copy_block(source_reader(&mut source.reader)?, offset, size, writer)?;
+                            size
+                        }
+                        (None, PlannedChunk::Encode { .. }) => unreachable!("encoded chunks always have content"),
+                    };
+                    next_offset = chunk_offset + size;
+                    chunk_offset
+                }
+            };
+            if let PlannedChunk::Copy { offset, size } = chunks[i] {
+                shared_offsets.insert((offset, size), chunk_offsets[i]);
+            }
+        }
+        // unused space at the end of the source's data stays in front of the name table
+        let (padding_offset, padding_size) = self.source_gaps.after();
+        if padding_size > 0 {
+            copy_block(source_reader(&mut source.reader)?, padding_offset, padding_size, writer)?;
+            next_offset += padding_size;
+        }
+
+        // name table
+        let name_table_offset = next_offset;
+        for file in &self.files {
+            let name = string_to_latin1(&file.name)?;
+            if name.len() > usize::from(u16::MAX) {
+                return Err(format_err!("File path {:?} is too long", file.name));
+            }
+            writer.write_u16::<LittleEndian>(name.len() as u16)?;
+            writer.write_all(&name)?;
+        }
+
+        // header
+        writer.seek(SeekFrom::Start(0))?;
+        let file_type = match self.header.file_type {
+            BA2Type::General => b"GNRL",
+            BA2Type::Textures => b"DX10",
+        };
+        writer.write_all(b"BTDX")?;
+        writer.write_u32::<LittleEndian>(self.header.version.number())?;
+        writer.write_all(file_type)?;
+        writer.write_u32::<LittleEndian>(self.files.len() as u32)?;
+        writer.write_u64::<LittleEndian>(name_table_offset)?;
+        if let Some(unknown) = self.header.unknown {
+            writer.write_u32::<LittleEndian>(unknown[0])?;
+            writer.write_u32::<LittleEndian>(unknown[1])?;
+        }
+        if let Some(compression_format) = self.header.compression_format {
+            writer.write_u32::<LittleEndian>(compression_format)?;
+        }
+
+        // file records
+        let size_field = |size: usize, file: &EditFile| {
+            u32::try_from(size).map_err(|_| format_err!("Chunk of {:?} is too large for a BA2 archive", file.name))
+        };
+        let mut chunk_index = 0;
+        for file in &self.files {
+            let chunk_count = file.chunk_count();
+            let file_chunks = chunk_index..chunk_index + chunk_count;
+            chunk_index += chunk_count;
+            writer.write_u32::<LittleEndian>(file.name_hash)?;
+            writer.write_all(&file.extension)?;
+            writer.write_u32::<LittleEndian>(file.dir_hash)?;
+            match file.texture {
+                Some(ref texture) => {
+                    // (first mipmap, last mipmap) of every chunk of the texture
+                    let mipmaps: Vec<(u16, u16)> = match file.contents {
+                        BA2Contents::Source(ref source_chunks) => source_chunks
+                            .iter()
+                            .map(|chunk| (chunk.mipmap_start, chunk.mipmap_end))
+                            .collect(),
+                        BA2Contents::NewTexture { ref chunks, .. }
+                        | BA2Contents::ExternalTexture { ref chunks, .. } => {
+                            chunks.iter().map(|&(_, start, end)| (start, end)).collect()
+                        }
+                        BA2Contents::New(_) | BA2Contents::External(_) => Vec::new(),
+                    };
+                    writer.write_u8(texture.unknown)?;
+                    let chunk_count = u8::try_from(chunk_count)
+                        .map_err(|_| format_err!("Texture {:?} has too many chunks", file.name))?;
+                    writer.write_u8(chunk_count)?;
+                    writer.write_u16::<LittleEndian>(texture.chunk_header_size)?;
+                    writer.write_u16::<LittleEndian>(texture.height)?;
+                    writer.write_u16::<LittleEndian>(texture.width)?;
+                    writer.write_u8(texture.num_mipmaps)?;
+                    writer.write_u8(texture.dxgi_format)?;
+                    writer.write_u16::<LittleEndian>(texture.unknown_2)?;
+                    for (i, (mipmap_start, mipmap_end)) in file_chunks.zip(mipmaps) {
+                        let (compressed_size, uncompressed_size) = chunk_sizes[i];
+                        writer.write_u64::<LittleEndian>(chunk_offsets[i])?;
+                        writer.write_u32::<LittleEndian>(size_field(compressed_size, file)?)?;
+                        writer.write_u32::<LittleEndian>(size_field(uncompressed_size, file)?)?;
+                        writer.write_u16::<LittleEndian>(mipmap_start)?;
+                        writer.write_u16::<LittleEndian>(mipmap_end)?;
+                        writer.write_u32::<LittleEndian>(RECORD_MAGIC)?;
+                    }
+                }
+                None => {
+                    if chunk_count == 0 {
+                        return Err(format_err!("General BA2 file {:?} has no data", file.name));
+                    }
+                    let (compressed_size, uncompressed_size) = chunk_sizes[file_chunks.start];
+                    writer.write_u32::<LittleEndian>(file.unknown_flags)?;
+                    writer.write_u64::<LittleEndian>(chunk_offsets[file_chunks.start])?;
+                    writer.write_u32::<LittleEndian>(size_field(compressed_size, file)?)?;
+                    writer.write_u32::<LittleEndian>(size_field(uncompressed_size, file)?)?;
+                    writer.write_u32::<LittleEndian>(RECORD_MAGIC)?;
+                }
+            }
+        }
+
+        Ok(deduplicator.report)
+    }
+
+    /// Size of the records of every file, which come right after the header
+    fn records_len(&self) -> u64 {
+        self.files
+            .iter()
+            .map(|file| match file.texture {
+                Some(_) => TEXTURE_HEADER_LEN + TEXTURE_CHUNK_LEN * file.chunk_count() as u64,
+                None => GENERAL_FILE_LEN,
+            })
+            .sum()
+    }
+
+    /// Reads the content of a chunk exactly as it is written
+    fn chunk_data(&self, chunk: PlannedChunk, source: &mut ChunkSource<'_, '_>) -> Result<Vec<u8>> {
+        match chunk {
+            PlannedChunk::Copy { offset, size } => read_block(source_reader(&mut source.reader)?, offset, size),
+            PlannedChunk::Encode { file, chunk } => Ok(self.encode_chunk(file, chunk, source)?.data),
+        }
+    }
+
+    /// Reads the uncompressed content of chunk `chunk` of file `file` and compresses it under the policy
+    fn encode_chunk(&self, file: usize, chunk: usize, source: &mut ChunkSource<'_, '_>) -> Result<EncodedChunk> {
+        let file = &self.files[file];
+        let data = match file.contents {
+            BA2Contents::Source(ref source_chunks) => {
+                let codec = self.header.compression()?;
+                source_chunks[chunk].read(source_reader(&mut source.reader)?, codec)?
+            }
+            BA2Contents::New(ref data) => data.clone(),
+            BA2Contents::NewTexture { ref data, ref chunks } => texture_chunk(data, chunks, chunk)?.to_vec(),
+            BA2Contents::External(key) => (source.load)(key)?,
+            BA2Contents::ExternalTexture { key, ref chunks } => {
+                let texture = parse_dds(source.external_texture(key)?)?;
+                if texture.chunks != *chunks {
+                    return Err(format_err!("Texture {:?} changed since it was added", file.name));
+                }
+                texture_chunk(texture.data, chunks, chunk)?.to_vec()
+            }
+        };
+        self.encode(&file.name, data)
+    }
+
+    /// Compresses the content of a single chunk under the policy
+    fn encode(&self, file_name: &str, data: Vec<u8>) -> Result<EncodedChunk> {
+        let codec = self.header.compression()?;
+        let uncompressed_size = data.len();
+        Ok(match self.policy.compress(codec, Path::new(file_name), &data)? {
+            Some(compressed) => EncodedChunk {
+                compressed_size: compressed.len(),
+                data: compressed,
+                uncompressed_size,
+            },
+            None => EncodedChunk {
+                data,
+                compressed_size: 0,
+                uncompressed_size,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Compression;
+
+    /// Builds a general archive out of `files` in the given order and returns the saved bytes
+    fn save_files(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut editor = BA2Editor::new(BA2Version::Fallout4, BA2Type::General);
+        for &(file_path, contents) in files {
+            editor.add(Path::new(file_path), contents.to_vec()).unwrap();
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.ba2");
+        editor.save(&path).unwrap();
+        std::fs::read(path).unwrap()
+    }
+
+    #[test]
+    fn insertion_order_and_separators_dont_change_the_output() {
+        let first = save_files(&[
+            ("meshes/armor/helmet.nif", b"helmet"),
+            ("materials\\armor\\helmet.bgsm", b"material"),
+            ("meshes/boots.nif", b"boots"),
+            ("scripts/helmet.pex", b"helmet"),
+        ]);
+        let second = save_files(&[
+            ("scripts\\helmet.pex", b"helmet"),
+            ("meshes\\boots.nif", b"boots"),
+            ("materials/armor/helmet.bgsm", b"material"),
+            ("meshes\\armor/helmet.nif", b"helmet"),
+        ]);
+        assert_eq!(first, second);
+    }
+
+    /// Rewrites the general archive at `path` with `gap` bytes of padding in front of the data of every file and in
+    /// front of the name table, the way archives packed by other tools can be laid out
+    fn add_padding(path: &Path, gap: usize) {
+        let archive = crate::ba2::from_file(path.to_path_buf()).unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        let name_table_offset = archive.header.name_table_offset as usize;
+        let mut files: Vec<&BA2File> = archive.file_hashmap.values().collect();
+        files.sort_by_key(|file| file.chunks[0].content_offset);
+        let mut padded = bytes[..files[0].chunks[0].content_offset as usize].to_vec();
+        for (i, file) in files.iter().enumerate() {
+            let start = file.chunks[0].content_offset as usize;
+            let end = files.get(i + 1).map_or(name_table_offset, |next| next.chunks[0].content_offset as usize);
+            padded.extend(std::iter::repeat(0xAB).take(gap));
+            // the offset comes after the hashes, the extension and the flags in the file record
+            let record = archive.header.version.header_len() + GENERAL_FILE_LEN as usize * file.index + 16;
+            let offset = padded.len() as u64;
+            padded[record..record + 8].copy_from_slice(&offset.to_le_bytes());
+            padded.extend_from_slice(&bytes[start..end]);
+        }
+        padded.extend(std::iter::repeat(0xCD).take(gap));
+        let offset = padded.len() as u64;
+        padded[16..24].copy_from_slice(&offset.to_le_bytes());
+        padded.extend_from_slice(&bytes[name_table_offset..]);
+        std::fs::write(path, padded).unwrap();
+    }
+
+    /// Saves a general archive with padding around the data of its files, returning its path
+    fn padded_archive(dir: &Path) -> PathBuf {
+        let mut editor = BA2Editor::new(BA2Version::Fallout4, BA2Type::General);
+        for &(file_path, contents) in &[
+            ("meshes/armor/helmet.nif", &b"helmet"[..]),
+            ("meshes/armor/boots.nif", &[7; 300][..]),
+            ("materials/armor/helmet.bgsm", &b"material"[..]),
+            ("scripts/helmet.pex", &b"script"[..]),
+        ] {
+            editor.add(Path::new(file_path), contents.to_vec()).unwrap();
+        }
+        let path = dir.join("padded.ba2");
+        editor.save(&path).unwrap();
+        add_padding(&path, 5);
+        path
+    }
+
+    #[test]
+    fn saving_without_edits_reproduces_the_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = padded_archive(dir.path());
+        let mut archive = crate::ba2::from_file(source.clone()).unwrap();
+        assert_eq!(archive.extract_by_name(Path::new("meshes\\armor\\boots.nif")).unwrap(), vec![7; 300]);
+
+        let copy = dir.path().join("copy.ba2");
+        BA2Editor::from_file(source.clone()).unwrap().save(&copy).unwrap();
+        assert_eq!(std::fs::read(copy).unwrap(), std::fs::read(source).unwrap());
+    }
+
+    #[test]
+    fn edits_keep_the_records_and_data_of_untouched_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = padded_archive(dir.path());
+        let mut editor = BA2Editor::from_file(source.clone()).unwrap();
+        editor.replace(Path::new("scripts/helmet.pex"), b"new script".to_vec()).unwrap();
+        editor.rename(Path::new("meshes/armor/helmet.nif"), Path::new("meshes/armor/hat.nif")).unwrap();
+        editor.remove(Path::new("meshes/armor/boots.nif")).unwrap();
+        let edited = dir.path().join("edited.ba2");
+        editor.save(&edited).unwrap();
+
+        let source_bytes = std::fs::read(&source).unwrap();
+        let edited_bytes = std::fs::read(&edited).unwrap();
+        let stored = |bytes: &[u8], file: &BA2File| {
+            let chunk = &file.chunks[0];
+            bytes[chunk.content_offset as usize..chunk.content_offset as usize + chunk.stored_size()].to_vec()
+        };
+        let source_archive = crate::ba2::from_file(source).unwrap();
+        let mut edited_archive = crate::ba2::from_file(edited).unwrap();
+        let untouched = &source_archive.file_hashmap[Path::new("materials\\armor\\helmet.bgsm")];
+        let copied = &edited_archive.file_hashmap[Path::new("materials\\armor\\helmet.bgsm")];
+        assert_eq!((copied.name_hash, copied.dir_hash), (untouched.name_hash, untouched.dir_hash));
+        assert_eq!(copied.chunks[0].compressed_size, untouched.chunks[0].compressed_size);
+        assert_eq!(stored(&edited_bytes, copied), stored(&source_bytes, untouched));
+        let helmet = &source_archive.file_hashmap[Path::new("meshes\\armor\\helmet.nif")];
+        let hat = &edited_archive.file_hashmap[Path::new("meshes\\armor\\hat.nif")];
+        assert_eq!(stored(&edited_bytes, hat), stored(&source_bytes, helmet));
+        let boots = stored(&source_bytes, &source_archive.file_hashmap[Path::new("meshes\\armor\\boots.nif")]);
+        assert!(!edited_bytes.windows(boots.len()).any(|window| window == &boots[..]));
+        assert!(!edited_archive.file_hashmap.contains_key(Path::new("meshes/armor/boots.nif")));
+        assert_eq!(edited_archive.extract_by_name(Path::new("scripts\\helmet.pex")).unwrap(), b"new script");
+        assert_eq!(edited_archive.extract_by_name(Path::new("meshes\\armor\\hat.nif")).unwrap(), b"helmet");
+    }
+
+    /// Builds a 256x256 BC3 DDS file with two mipmaps, so that its texture record gets two chunks
+    fn dds_file() -> Vec<u8> {
+        let header = BA2TextureHeader {
+            num_chunks:        0,
+            chunk_header_size: 0x18,
+            height:            256,
+            width:             256,
+            num_mipmaps:       2,
+            dxgi_format:       77,
+            unknown:           0,
+            unknown_2:         0x0800,
+        };
+        let mut file = crate::ba2::dds_header(&header).unwrap();
+        file.extend((0..0x1_0000 + 0x4000).map(|i| (i % 251) as u8));
+        file
+    }
+
+    #[test]
+    fn headers_of_every_version_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        for &version in &[
+            BA2Version::Fallout4,
+            BA2Version::Starfield,
+            BA2Version::StarfieldCompressed,
+            BA2Version::Fallout4NextGen,
+            BA2Version::Fallout4NextGen2,
+        ] {
+            for &(file_type, file_path, ref contents) in &[
+                (BA2Type::General, "meshes\\helmet.nif", b"helmet".to_vec()),
+                (BA2Type::Textures, "textures\\helmet.dds", dds_file()),
+            ] {
+                let mut editor = BA2Editor::new(version, file_type);
+                editor.add(Path::new(file_path), contents.clone()).unwrap();
+                let path = dir.path().join(format!("{}_{:?}.ba2", version.number(), file_type));
+                editor.save(&path).unwrap();
+
+                let mut archive = crate::ba2::from_file(path).unwrap();
+                assert_eq!(archive.header.version, version);
+                assert_eq!(archive.header.file_type, file_type);
+                assert_eq!(archive.header.file_count, 1);
+                assert_eq!(archive.header.unknown, editor.header.unknown);
+                assert_eq!(archive.header.compression_format, editor.header.compression_format);
+                assert_eq!(&archive.extract_by_name(Path::new(file_path)).unwrap(), contents);
+            }
+        }
+    }
+
+    #[test]
+    fn starfield_lz4_textures_extract_to_dds() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("textures.ba2");
+        let mut editor = BA2Editor::new(BA2Version::StarfieldCompressed, BA2Type::Textures);
+        editor.add(Path::new("textures/helmet.dds"), dds_file()).unwrap();
+        editor.save(&path).unwrap();
+
+        let mut archive = crate::ba2::from_file(path).unwrap();
+        let file = &archive.file_hashmap[Path::new("textures\\helmet.dds")];
+        assert_eq!(file.compression, Compression::Lz4Block);
+        assert_eq!(file.chunks.len(), 2);
+        assert!(file.chunks.iter().all(|chunk| chunk.compressed_size > 0));
+        assert_eq!(archive.extract_by_name(Path::new("textures\\helmet.dds")).unwrap(), dds_file());
+    }
+
+    #[test]
+    fn compression_format_picks_the_codec() {
+        let dir = tempfile::tempdir().unwrap();
+        let contents = b"helmet ".repeat(100);
+        for &(compression_format, compression) in &[(0, Compression::Zlib), (3, Compression::Lz4Block)] {
+            let mut editor = BA2Editor::new(BA2Version::StarfieldCompressed, BA2Type::General);
+            editor.header.compression_format = Some(compression_format);
+            editor.add(Path::new("meshes/helmet.nif"), contents.clone()).unwrap();
+            let path = dir.path().join(format!("{}.ba2", compression_format));
+            editor.save(&path).unwrap();
+
+            let mut archive = crate::ba2::from_file(path).unwrap();
+            assert_eq!(archive.header.compression().unwrap(), compression);
+            let file = &archive.file_hashmap[Path::new("meshes\\helmet.nif")];
+            assert_eq!(file.compression, compression);
+            assert!(file.chunks[0].compressed_size > 0);
+            assert_eq!(archive.extract_by_name(Path::new("meshes\\helmet.nif")).unwrap(), contents);
+        }
+
+        let mut editor = BA2Editor::new(BA2Version::StarfieldCompressed, BA2Type::General);
+        editor.add(Path::new("meshes/helmet.nif"), contents).unwrap();
+        let path = dir.path().join("unknown.ba2");
+        editor.save(&path).unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        // the compression format is the last field of a version 3 header
+        bytes[0x20..0x24].copy_from_slice(&5u32.to_le_bytes());
+        std::fs::write(&path, bytes).unwrap();
+        assert!(crate::ba2::from_file(path).is_err());
+        editor.header.compression_format = Some(5);
+        assert!(editor.save(&dir.path().join("unknown_2.ba2")).is_err());
+    }
+
+    #[test]
+    fn identical_payloads_share_one_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut lens = Vec::new();
+        for &deduplicate in &[true, false] {
+            let mut editor = BA2Editor::new(BA2Version::Fallout4, BA2Type::General);
+            editor.set_deduplicate(deduplicate);
+            for &file_path in &["meshes/helmet.nif", "meshes/armor/helmet.nif", "scripts/helmet.pex"] {
+                editor.add(Path::new(file_path), b"helmet ".repeat(40)).unwrap();
+            }
+            editor.add(Path::new("meshes/boots.nif"), b"boots ".repeat(40)).unwrap();
+            let path = dir.path().join(format!("{}.ba2", deduplicate));
+            let report = editor.save(&path).unwrap();
+            lens.push(std::fs::metadata(&path).unwrap().len());
+
+            let mut archive = crate::ba2::from_file(path).unwrap();
+            let chunk = |archive: &crate::ba2::BA2Archive, file_path: &str| {
+                let chunk = &archive.file_hashmap[Path::new(file_path)].chunks[0];
+                (chunk.content_offset, chunk.stored_size() as u64)
+            };
+            let (offset, size) = chunk(&archive, "meshes\\helmet.nif");
+            let copies = [chunk(&archive, "meshes\\armor\\helmet.nif"), chunk(&archive, "scripts\\helmet.pex")];
+            assert_ne!(chunk(&archive, "meshes\\boots.nif").0, offset);
+            assert_eq!(archive.extract_by_name(Path::new("scripts\\helmet.pex")).unwrap(), b"helmet ".repeat(40));
+            if deduplicate {
+                assert_eq!(copies, [(offset, size); 2]);
+                assert_eq!(
+                    report,
+                    WriteReport {
+                        deduplicated_blocks: 2,
+                        bytes_saved:         2 * size,
+                    }
+                );
+                lens.push(2 * size);
+            } else {
+                assert!(copies.iter().all(|&(copy_offset, _)| copy_offset != offset));
+                assert_eq!(report, WriteReport::default());
+            }
+        }
+        // the deduplicated archive is smaller by exactly what the report says was saved
+        assert_eq!(lens[0] + lens[1], lens[2]);
+    }
+}
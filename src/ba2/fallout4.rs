@@ -1,4 +1,4 @@
-use std::io::{Seek, SeekFrom};
+use std::io::{BufRead, Seek, SeekFrom};
 use std::path::PathBuf;
 
 use failure::ResultExt;
@@ -6,14 +6,14 @@ use nom::{le_u16, le_u32, le_u64, le_u8};
 
 // top-level imports
 use crate::archive::FileMap;
-use crate::reader::TESFile;
-use crate::Result;
+use crate::layout::{Region, RegionKind};
+use crate::reader::TESReader;
+use crate::{Compression, Result};
 
 // BA2 imports
 use crate::ba2::types::*;
-use crate::ba2::BA2Archive;
 
-/// All BA2 headers are 24 (0x18) bytes
+/// All BA2 headers start with the same 24 (0x18) bytes, Starfield archives add more fields after them
 const HEADER_LEN: usize = 0x18;
 /// All BA2 general file records are 36 (0x24) bytes
 const GENERAL_FILE_LEN: usize = 0x24;
@@ -22,12 +22,26 @@ const TEXTURE_HEADER_LEN: usize = 0x18;
 /// All BA2 texture chunk records are 24 (0x18) bytes
 const TEXTURE_CHUNK_LEN: usize = 0x18;
 
-/// Creates a BA2 object
-pub fn parse_ba2(path: PathBuf, reader: &mut TESFile) -> Result<BA2Archive> {
+/// Reads the header, file records and name table of a BA2
+pub fn parse_ba2<B: BufRead + Seek>(reader: &mut TESReader<B>) -> Result<(BA2Header, FileMap<BA2File>)> {
     // Read in the header
-    let header = reader
+    let mut header = reader
         .parse_exact(HEADER_LEN, fo4_header_parser)
         .context("Can't parse a Fallout 4 .ba2 header")?;
+    let header_len = header.version.header_len();
+    if header_len > HEADER_LEN {
+        let (unknown, compression_format) = reader
+            .parse_exact(header_len - HEADER_LEN, starfield_header_parser)
+            .context("Can't parse a Starfield .ba2 header")?;
+        header.unknown = Some(unknown);
+        header.compression_format = compression_format;
+    }
+
+    let compression = header.compression()?;
+
+    // Every file has at least a texture header's worth of metadata, so a file count that can't fit in the rest of the
+    // archive is rejected before anything is allocated for it
+    reader.records_len(header.file_count, TEXTURE_HEADER_LEN)?;
 
     // Seek to the name table
     reader.seek(SeekFrom::Start(header.name_table_offset))?;
@@ -40,18 +54,28 @@ pub fn parse_ba2(path: PathBuf, reader: &mut TESFile) -> Result<BA2Archive> {
     }
 
     // Seek to the beginning of the file info section
-    reader.seek(SeekFrom::Start(u64::from((HEADER_LEN) as u32)))?;
+    reader.seek(SeekFrom::Start(header_len as u64))?;
 
     // Collect metadata about all of the files in the archive
     let files: Vec<BA2File> = match header.file_type {
-        BA2Type::General => reader.parse_exact(GENERAL_FILE_LEN * header.file_count, fo4_general_files_parser)?,
+        BA2Type::General => {
+            let files_len = reader.records_len(header.file_count, GENERAL_FILE_LEN)?;
+            reader.parse_exact(files_len, fo4_general_files_parser)?
+        }
         BA2Type::Textures => {
             let mut files: Vec<BA2File> = Vec::with_capacity(header.file_count);
             for _ in 0..header.file_count {
-                let tex_header = reader.parse_exact(TEXTURE_HEADER_LEN, fo4_texture_header_parser)?;
-                let tex_chunks =
-                    reader.parse_exact(TEXTURE_CHUNK_LEN * tex_header.num_chunks, fo4_texture_chunks_parser)?;
+                let (name_hash, extension, dir_hash, tex_header) =
+                    reader.parse_exact(TEXTURE_HEADER_LEN, fo4_texture_header_parser)?;
+                let tex_chunks_len = reader.records_len(tex_header.num_chunks, TEXTURE_CHUNK_LEN)?;
+                let tex_chunks = reader.parse_exact(tex_chunks_len, fo4_texture_chunks_parser)?;
                 files.push(BA2File {
+                    index: files.len(),
+                    name_hash,
+                    extension,
+                    dir_hash,
+                    unknown_flags: 0,
+                    compression,
                     header: Some(tex_header),
                     chunks: tex_chunks,
                 });
@@ -63,15 +87,51 @@ pub fn parse_ba2(path: PathBuf, reader: &mut TESFile) -> Result<BA2Archive> {
     // Create a hashmap mapping file names => file metadata records to quickly grab file data from the BA2
     let file_iter = name_vec.into_iter().zip(files.into_iter());
     let mut file_hashmap: FileMap<BA2File> = Default::default();
-    for (file_name, file) in file_iter {
+    for (index, (file_name, mut file)) in file_iter.enumerate() {
+        file.index = index;
+        file.compression = compression;
         file_hashmap.insert(file_name, file);
     }
 
-    Ok(BA2Archive {
-        path,
-        header,
-        file_hashmap,
-    })
+    Ok((header, file_hashmap))
+}
+
+/// Finds where the header, file records and name table of a BA2 are stored. Names are read again because their stored
+/// length can differ from the length of the decoded path.
+pub(crate) fn read_structure<B: BufRead + Seek>(
+    header: &BA2Header,
+    file_hashmap: &FileMap<BA2File>,
+    reader: &mut TESReader<B>,
+) -> Result<Vec<Region>> {
+    let header_len = header.version.header_len() as u64;
+    let records_len: u64 = file_hashmap
+        .values()
+        .map(|file| match file.header {
+            Some(_) => (TEXTURE_HEADER_LEN + TEXTURE_CHUNK_LEN * file.chunks.len()) as u64,
+            None => GENERAL_FILE_LEN as u64,
+        })
+        .sum();
+
+    reader.seek(SeekFrom::Start(header.name_table_offset))?;
+    for _ in 0..header.file_count {
+        reader
+            .parse_long_bstring()
+            .context("Can't parse a Fallout 4 file path")?;
+    }
+    let names_len = reader.stream_position()? - header.name_table_offset;
+
+    Ok(vec![
+        Region::new(RegionKind::Header, 0, header_len),
+        Region::new(RegionKind::Records, header_len, records_len),
+        Region::new(RegionKind::Names, header.name_table_offset, names_len),
+    ])
+}
+
+/// Copies a four character file extension out of a record
+fn to_extension(bytes: &[u8]) -> [u8; 4] {
+    let mut extension = [0; 4];
+    extension.copy_from_slice(bytes);
+    extension
 }
 
 /// Parses metadata for the entire Fallout 4 .ba2 archive
@@ -82,7 +142,7 @@ pub fn parse_ba2(path: PathBuf, reader: &mut TESFile) -> Result<BA2Archive> {
 /// version             u32
 /// file_type           char[4]
 /// file_count          u32
-/// name_table_offset   u32
+/// name_table_offset   u64
 /// ------------------------
 /// ```
 named!(fo4_header_parser<&[u8], BA2Header>,
@@ -99,12 +159,35 @@ named!(fo4_header_parser<&[u8], BA2Header>,
                     file_type,
                     file_count: file_count as usize,
                     name_table_offset,
+                    unknown: None,
+                    compression_format: None,
                 }
             )
         )
     )
 );
 
+/// Parses the fields that Starfield archives add to the end of the header
+///
+/// Encoded format
+/// ```
+/// ------------------------
+/// unknown_1           u32
+/// unknown_2           u32
+/// compression_format  u32 (only in version 3)
+/// ------------------------
+/// ```
+named!(starfield_header_parser<&[u8], ([u32; 2], Option<u32>)>,
+    add_return_error!(ErrorKind::Custom(304),
+        do_parse!(
+            unknown_1:                   le_u32 >>
+            unknown_2:                   le_u32 >>
+            compression_format: opt!(complete!(le_u32)) >>
+            ([unknown_1, unknown_2], compression_format)
+        )
+    )
+);
+
 /// Parses all of the file metadata for a general .ba2 archive
 ///
 /// Encoded format for non-textures
@@ -124,16 +207,22 @@ named!(fo4_general_files_parser<&[u8], Vec<BA2File>>,
     many1!(complete!(
         add_return_error!(ErrorKind::Custom(301),
             do_parse!(
-                _name_hash:                             le_u32 >>
-                _extension:                           take!(4) >>
-                _dir_hash:                              le_u32 >>
-                _unknown_flags:                         le_u32 >>
+                name_hash:                              le_u32 >>
+                extension:                 map!(take!(4), to_extension) >>
+                dir_hash:                               le_u32 >>
+                unknown_flags:                          le_u32 >>
                 content_offset:                         le_u64 >>
                 compressed_size:                        le_u32 >>
                 uncompressed_size:                      le_u32 >>
                 _magic:  bits!(tag_bits!(u32, 32, 0x0DF0_ADBA)) >>
                 (
                     BA2File {
+                        index: 0,
+                        name_hash,
+                        extension,
+                        dir_hash,
+                        unknown_flags,
+                        compression: Compression::Zlib,
                         header: None,
                         chunks: {
                             let mut chunks = Vec::with_capacity(1);
@@ -141,6 +230,8 @@ named!(fo4_general_files_parser<&[u8], Vec<BA2File>>,
                                 content_offset,
                                 compressed_size: compressed_size as usize,
                                 uncompressed_size: uncompressed_size as usize,
+                                mipmap_start: 0,
+                                mipmap_end: 0,
                             });
                             chunks
                         }
@@ -169,28 +260,33 @@ named!(fo4_general_files_parser<&[u8], Vec<BA2File>>,
 /// unknown_2           u16
 /// ------------------------
 /// ```
-named!(fo4_texture_header_parser<&[u8], BA2TextureHeader>,
+named!(fo4_texture_header_parser<&[u8], (u32, [u8; 4], u32, BA2TextureHeader)>,
     add_return_error!(ErrorKind::Custom(302),
         do_parse!(
-            _name_hash:         le_u32 >>
-            _extension:       take!(4) >>
-            _dir_hash:          le_u32 >>
-            _unknown:           le_u8  >>
+            name_hash:          le_u32 >>
+            extension: map!(take!(4), to_extension) >>
+            dir_hash:           le_u32 >>
+            unknown:            le_u8  >>
             num_chunks:         le_u8  >>
             chunk_header_size:  le_u16 >>
             height:             le_u16 >>
             width:              le_u16 >>
             num_mipmaps:        le_u8  >>
             dxgi_format:        le_u8  >>
-            _unknown_2:         le_u16 >>
+            unknown_2:          le_u16 >>
             (
+                name_hash,
+                extension,
+                dir_hash,
                 BA2TextureHeader {
                     num_chunks: num_chunks as usize,
                     chunk_header_size,
                     height,
                     width,
                     num_mipmaps,
-                    dxgi_format
+                    dxgi_format,
+                    unknown,
+                    unknown_2,
                 }
             )
         )
@@ -217,17 +313,77 @@ named!(fo4_texture_chunks_parser<&[u8], Vec<BA2FileChunk>>,
                 content_offset:                         le_u64 >>
                 compressed_size:                        le_u32 >>
                 uncompressed_size:                      le_u32 >>
-                _mipmap_start:                          le_u16 >>
-                _mipmap_end:                            le_u16 >>
+                mipmap_start:                           le_u16 >>
+                mipmap_end:                             le_u16 >>
                 _magic: bits!(tag_bits!(u32, 32, 0x0DF0_ADBA)) >>
                 (
                     BA2FileChunk {
                         content_offset,
                         compressed_size: compressed_size as usize,
                         uncompressed_size: uncompressed_size as usize,
+                        mipmap_start,
+                        mipmap_end,
                     }
                 )
             )
         )
     ))
 );
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::Path;
+
+    use crate::ba2::{BA2Editor, BA2Type, BA2Version};
+
+    /// Saves a small archive of the given type and returns its bytes
+    fn archive_bytes(file_type: BA2Type) -> Vec<u8> {
+        let mut editor = BA2Editor::new(BA2Version::Fallout4, file_type);
+        let contents = match file_type {
+            BA2Type::General => b"helmet".to_vec(),
+            BA2Type::Textures => {
+                let mut file = b"DDS ".to_vec();
+                file.extend_from_slice(&[0; 124]);
+                // height, width, DXT1 format and one 4x4 block of data
+                file[12] = 4;
+                file[16] = 4;
+                file[80] = 0x4;
+                file[84..88].copy_from_slice(b"DXT1");
+                file.extend_from_slice(&[0; 8]);
+                file
+            }
+        };
+        editor.add(Path::new("helmet.dat"), contents).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.ba2");
+        editor.save(&path).unwrap();
+        std::fs::read(path).unwrap()
+    }
+
+    #[test]
+    fn huge_counts_are_errors() {
+        for &file_type in &[BA2Type::General, BA2Type::Textures] {
+            let mut bytes = archive_bytes(file_type);
+            // the file count follows the magic, version and type
+            bytes[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+            assert!(crate::ba2::from_reader(Cursor::new(bytes)).is_err());
+        }
+        let mut bytes = archive_bytes(BA2Type::Textures);
+        // the chunk count of the first texture record
+        bytes[0x18 + 13] = u8::MAX;
+        assert!(crate::ba2::from_reader(Cursor::new(bytes)).is_err());
+    }
+
+    #[test]
+    fn truncated_archives_are_errors() {
+        for &file_type in &[BA2Type::General, BA2Type::Textures] {
+            let bytes = archive_bytes(file_type);
+            // the name table is the last thing in the archive and every part of it is needed
+            for len in 0..bytes.len() {
+                assert!(crate::ba2::from_reader(Cursor::new(bytes[..len].to_vec())).is_err(), "{} bytes", len);
+            }
+            assert!(crate::ba2::from_reader(Cursor::new(bytes)).is_ok());
+        }
+    }
+}
@@ -0,0 +1,47 @@
+//! Hash functions used by BA2 archives to look up files
+
+use std::path::Path;
+
+// top-level imports
+use crate::writer::normalize_path;
+
+/// Hashes of a single file path as they are stored in its BA2 file record
+pub struct BA2PathHashes {
+    /// CRC32 of the file name without its extension
+    pub name_hash: u32,
+    /// File extension padded with '\0' to four characters
+    pub extension: [u8; 4],
+    /// CRC32 of the directory containing the file
+    pub dir_hash: u32,
+}
+
+/// Hashes a file path (e.g. `meshes\armor\cuirass.nif`). Bethesda hashes lower case names using '\\' as the separator.
+pub fn hash_path(file_path: &Path) -> BA2PathHashes {
+    let file_path = normalize_path(file_path);
+    let (dir, file_name) = match file_path.rfind('\\') {
+        Some(separator) => (&file_path[..separator], &file_path[separator + 1..]),
+        None => ("", file_path.as_str()),
+    };
+    let (stem, extension) = match file_name.rfind('.') {
+        Some(ext_start) => (&file_name[..ext_start], &file_name[ext_start + 1..]),
+        None => (file_name, ""),
+    };
+
+    let mut extension_bytes = [0; 4];
+    for (dest, &src) in extension_bytes.iter_mut().zip(extension.as_bytes()) {
+        *dest = src;
+    }
+
+    BA2PathHashes {
+        name_hash: bethesda_crc32(stem.as_bytes()),
+        extension: extension_bytes,
+        dir_hash:  bethesda_crc32(dir.as_bytes()),
+    }
+}
+
+/// Bethesda's CRC32 starts from 0 and skips the final inversion, unlike the standard CRC32
+fn bethesda_crc32(bytes: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new_with_initial(!0);
+    hasher.update(bytes);
+    !hasher.finalize()
+}
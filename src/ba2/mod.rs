@@ -1,50 +1,244 @@
-use std::io::{Read, Seek, SeekFrom};
-use std::path::PathBuf;
-
-use byteorder::{LittleEndian, WriteBytesExt};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 
+mod convert;
+mod dds;
+mod editor;
 mod fallout4;
+mod hash;
 mod types;
 
 use crate::archive::{Archive, Extract};
-use crate::reader::{TESFile, TESReader};
+use crate::entry::{Entry, EntryChunk};
+use crate::layout::{Region, Structure};
+use crate::lint::LintReport;
+use crate::reader::TESReader;
+use crate::verify::Problem;
 use crate::{Compression, Result};
 
+#[cfg(feature = "cache")]
+use crate::cache::open_cached;
+#[cfg(feature = "async")]
+use crate::prefetch::parse_async;
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncSeek};
+
 // re-export only types that can be accessed from the main BSA structure
-pub use self::types::{BA2File, BA2Header};
+pub use self::convert::from_bsa;
+pub(crate) use self::dds::dds_header;
+pub use self::editor::BA2Editor;
+pub use self::types::{BA2File, BA2Header, BA2TextureHeader, BA2Type, BA2Version};
+
+use self::types::BA2FileChunk;
 
-pub type BA2Archive = Archive<BA2Header, BA2File>;
+pub type BA2Archive<R = BufReader<File>> = Archive<BA2Header, BA2File, R>;
 
-/// Given a file path to a BSA file, opens and parses the archive into the generic BSA structure
+/// Given a file path to a BA2 file, opens and parses the archive into the generic BA2 structure
 pub fn from_file(path: PathBuf) -> Result<BA2Archive> {
-    let mut reader = TESReader::from_file(&path)?;
-    fallout4::parse_ba2(path, &mut reader)
+    from_reader(File::open(path)?)
+}
+
+/// Parses a BA2 archive out of any seekable reader, such as an archive that was downloaded into memory or one that is
+/// stored inside another file. The archive takes ownership of `reader` to read file data from, pass `&mut reader` to
+/// keep using it afterwards.
+pub fn from_reader<R: Read + Seek>(reader: R) -> Result<BA2Archive<BufReader<R>>> {
+    let mut reader = BufReader::new(reader);
+    let (header, file_hashmap) = fallout4::parse_ba2(&mut TESReader::from_reader(&mut reader)?)?;
+    Ok(BA2Archive {
+        header,
+        file_hashmap,
+        reader,
+    })
+}
+
+/// Given a file path to a BA2 file, opens the archive and reads its header and file records from `cache_dir` if they
+/// were cached while the archive had the same size and modification time. Otherwise the archive is parsed and its index
+/// is cached for next time, unless `cache_dir` can't be written to.
+#[cfg(feature = "cache")]
+pub fn from_file_cached(path: PathBuf, cache_dir: &Path) -> Result<BA2Archive> {
+    open_cached(path, cache_dir, |reader, _| fallout4::parse_ba2(reader))
+}
+
+/// Given a file path to a BA2 file, opens and parses the archive without blocking the async runtime
+#[cfg(feature = "async")]
+pub async fn from_file_async(path: PathBuf) -> Result<BA2Archive<tokio::fs::File>> {
+    from_async_reader(tokio::fs::File::open(path).await?).await
+}
+
+/// Parses the BA2 archive behind an async reader, only reading its header, file records and name table
+#[cfg(feature = "async")]
+pub async fn from_async_reader<R>(mut reader: R) -> Result<BA2Archive<R>>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    let (header, file_hashmap) = parse_async(&mut reader, &[], fallout4::parse_ba2).await?;
+    Ok(BA2Archive {
+        header,
+        file_hashmap,
+        reader,
+    })
+}
+
+impl BA2FileChunk {
+    /// Reads the data of a single chunk, decompressing it with `compression` if needed
+    fn read<B: BufRead + Seek>(&self, reader: &mut TESReader<B>, compression: Compression) -> Result<Vec<u8>> {
+        reader.seek(SeekFrom::Start(self.content_offset))?;
+        reader.ensure_remaining(self.stored_size() as u64)?;
+        let mut file_block = vec![0; self.stored_size()];
+        reader.read_exact(&mut file_block)?;
+        if self.compressed_size != 0 {
+            compression.decompress(&file_block, self.uncompressed_size as u64)
+        } else {
+            Ok(file_block)
+        }
+    }
+
+    /// Size of the chunk's data as it is stored in the archive
+    fn stored_size(&self) -> usize {
+        if self.compressed_size == 0 {
+            self.uncompressed_size
+        } else {
+            self.compressed_size
+        }
+    }
+}
+
+impl<R: BufRead + Seek> BA2Archive<R> {
+    /// Looks for mistakes in the archive that keep the game from loading files out of it
+    pub fn lint(&mut self) -> Result<LintReport> {
+        let mut report = LintReport::default();
+        // sort the file names so that the report comes out in the same order every time
+        let mut file_names: Vec<&PathBuf> = self.file_hashmap.keys().collect();
+        file_names.sort();
+        for file_name in file_names {
+            report.check_path(file_name);
+            report.check_ba2_type(self.header.file_type, file_name);
+        }
+        Ok(report)
+    }
+}
+
+impl<R: BufRead + Seek> Structure for BA2Archive<R> {
+    fn structure(&mut self) -> Result<Vec<Region>> {
+        let mut reader = TESReader::from_reader(&mut self.reader)?;
+        fallout4::read_structure(&self.header, &self.file_hashmap, &mut reader)
+    }
 }
 
 impl Extract for BA2File {
-    /// Given a file, extracts the file content from the BSA
-    fn extract(&self, reader: &mut TESFile) -> Result<Vec<u8>> {
-        match self.header {
-            Some(_) => unimplemented!("Extraction is currently unimplemented for BA2 texture files"),
-            None => {
-                let general_file = &self.chunks[0];
-                reader.seek(SeekFrom::Start(general_file.content_offset))?;
-                let buffer_len = if general_file.compressed_size == 0 {
-                    general_file.uncompressed_size
-                } else {
-                    general_file.compressed_size
-                };
-                if general_file.compressed_size != 0 {
-                    let mut file_block = vec![0; buffer_len + 4];
-                    reader.read_exact(&mut file_block[4..])?;
-                    file_block.write_u64::<LittleEndian>(general_file.uncompressed_size as u64)?;
-                    Compression::Zlib.decompress_buffer(&file_block)
-                } else {
-                    let mut file_block = vec![0; buffer_len];
-                    reader.read_exact(&mut file_block)?;
-                    Ok(file_block)
+    /// Given a file, extracts the file content from the BA2. Textures are rebuilt into DDS files from their chunks.
+    fn extract<B: BufRead + Seek>(&self, reader: &mut TESReader<B>) -> Result<Vec<u8>> {
+        match (&self.header, self.chunks.first()) {
+            (Some(texture), _) => {
+                let mut contents = dds::dds_header(texture)?;
+                for chunk in &self.chunks {
+                    contents.extend_from_slice(&chunk.read(reader, self.compression)?);
                 }
+                Ok(contents)
+            }
+            (None, Some(general_file)) => general_file.read(reader, self.compression),
+            (None, None) => Err(format_err!("General BA2 file has no data")),
+        }
+    }
+
+    fn entry<B: BufRead + Seek>(&self, _reader: &mut TESReader<B>, file_path: &Path) -> Result<Entry> {
+        let chunks: Vec<EntryChunk> = self
+            .chunks
+            .iter()
+            .map(|chunk| EntryChunk {
+                offset: chunk.content_offset,
+                stored_size: chunk.stored_size() as u64,
+                compressed_size: if chunk.compressed_size == 0 {
+                    None
+                } else {
+                    Some(chunk.compressed_size as u64)
+                },
+                uncompressed_size: chunk.uncompressed_size as u64,
+                mipmaps: self
+                    .header
+                    .as_ref()
+                    .map(|_| (chunk.mipmap_start, chunk.mipmap_end)),
+            })
+            .collect();
+
+        // each chunk is compressed on its own, but in practice either all or none of them are
+        let compression = if chunks.iter().any(|chunk| chunk.compressed_size.is_some()) {
+            self.compression
+        } else {
+            Compression::None
+        };
+
+        Ok(Entry {
+            path: file_path.to_path_buf(),
+            index: self.index,
+            name_hash: u64::from(self.name_hash),
+            dir_hash: Some(u64::from(self.dir_hash)),
+            extension: Some(self.extension),
+            compression,
+            compression_toggled: false,
+            has_name: false,
+            texture: self.header.clone(),
+            chunks,
+        })
+    }
+
+    fn data_blocks(&self) -> Vec<(u64, u64)> {
+        self.chunks
+            .iter()
+            .map(|chunk| (chunk.content_offset, chunk.stored_size() as u64))
+            .collect()
+    }
+
+    fn verify<B: BufRead + Seek>(&self, reader: &mut TESReader<B>, _file_path: &Path) -> Vec<Problem> {
+        // every chunk is compressed on its own, so each one is checked separately
+        let mut problems = Vec::new();
+        for chunk in &self.chunks {
+            match chunk.read(reader, self.compression) {
+                Ok(contents) if contents.len() != chunk.uncompressed_size => problems.push(Problem::SizeMismatch {
+                    expected: chunk.uncompressed_size as u64,
+                    actual:   contents.len() as u64,
+                }),
+                Ok(_) => (),
+                Err(e) => problems.push(Problem::Unreadable(e.to_string())),
+            }
+        }
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn archives_can_be_parsed_from_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.ba2");
+        let mut editor = BA2Editor::new(BA2Version::StarfieldCompressed, BA2Type::General);
+        editor.add(Path::new("meshes/helmet.nif"), b"helmet ".repeat(20)).unwrap();
+        editor.add(Path::new("scripts/quest.pex"), b"script".to_vec()).unwrap();
+        editor.save(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+
+        let mut from_disk = from_file(path).unwrap();
+        let mut cursor = Cursor::new(bytes.clone());
+        {
+            let mut from_memory = from_reader(&mut cursor).unwrap();
+            assert_eq!(from_memory.header.version, BA2Version::StarfieldCompressed);
+            assert_eq!(from_memory.header.compression_format, from_disk.header.compression_format);
+            assert_eq!(from_memory.header.name_table_offset, from_disk.header.name_table_offset);
+            assert_eq!(from_memory.header.file_count, 2);
+            for file_path in &["meshes\\helmet.nif", "scripts\\quest.pex"] {
+                let file_path = Path::new(file_path);
+                assert_eq!(
+                    from_memory.extract_by_name(file_path).unwrap(),
+                    from_disk.extract_by_name(file_path).unwrap()
+                );
             }
         }
+        // the cursor was only borrowed, so it can still be used
+        assert_eq!(cursor.into_inner(), bytes);
     }
 }
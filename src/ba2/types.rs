@@ -1,9 +1,13 @@
 use nom::le_u32;
 
+// top-level imports
+use crate::{Compression, Result};
+
 /// Metadata for the whole archive.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct BA2Header {
-    /// Version of the file (should always be 0x1)
+    /// Version of the file
     pub version: BA2Version,
     /// Type of this BA2 archive
     pub file_type: BA2Type,
@@ -11,9 +15,25 @@ pub struct BA2Header {
     pub file_count: usize,
     /// Offset to the start of the file names
     pub name_table_offset: u64,
+    /// Two fields of unknown meaning that only Starfield archives have
+    pub unknown: Option<[u32; 2]>,
+    /// Method used to compress file data (0 for zlib, 3 for LZ4 block), only recorded by version 3 archives
+    pub compression_format: Option<u32>,
 }
 
-#[derive(Debug)]
+impl BA2Header {
+    /// Codec used by every compressed chunk in the archive. Only version 3 archives record it, all others use zlib.
+    pub fn compression(&self) -> Result<Compression> {
+        match self.compression_format {
+            None | Some(0) => Ok(Compression::Zlib),
+            Some(3) => Ok(Compression::Lz4Block),
+            Some(format) => Err(format_err!("Unknown BA2 compression format {}", format)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct BA2TextureHeader {
     /// Number of file chunks
     pub num_chunks: usize,
@@ -27,26 +47,49 @@ pub struct BA2TextureHeader {
     pub num_mipmaps: u8,
     /// The DXGI encoding format for the texture
     pub dxgi_format: u8,
+    /// Byte preceding the chunk count, kept so that the header can be written back out unchanged
+    pub(crate) unknown: u8,
+    /// Flags following the DXGI format (bit 0 marks a cubemap) and the tile mode
+    pub(crate) unknown_2: u16,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct BA2FileChunk {
     /// Offset from the start of the file to this chunk's data
     pub content_offset: u64,
-    /// Size of contents while zlib compressed (if 0, then the file isn't compressed)
+    /// Size of contents while compressed (if 0, then the file isn't compressed)
     pub compressed_size: usize,
     /// Size of contents while uncompresed
     pub uncompressed_size: usize,
+    /// First mipmap stored in this chunk (always 0 for general files)
+    pub mipmap_start: u16,
+    /// Last mipmap stored in this chunk (always 0 for general files)
+    pub mipmap_end: u16,
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct BA2File {
+    /// Position of this file's record among all of the file records in the archive
+    pub index: usize,
+    /// CRC32 hash of the file name without its extension
+    pub name_hash: u32,
+    /// File extension padded with '\0' to four characters
+    pub extension: [u8; 4],
+    /// CRC32 hash of the directory containing this file
+    pub dir_hash: u32,
+    /// Flags of general files whose meaning is unknown, kept so that the record can be written back out unchanged
+    pub(crate) unknown_flags: u32,
+    /// Codec used by the compressed chunks of this file
+    pub compression: Compression,
     pub header: Option<BA2TextureHeader>,
     pub chunks: Vec<BA2FileChunk>,
 }
 
 /// The type of files contained in the BA2 archive
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum BA2Type {
     /// Encoded as "GNRL"
     General,
@@ -59,12 +102,47 @@ named!(pub type_parser<BA2Type>, alt!(
     tag!("DX10")        => { |_| BA2Type::Textures }
 ));
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum BA2Version {
     /// Fallout 4 files (0x1)
     Fallout4,
+    /// Starfield files (0x2)
+    Starfield,
+    /// Starfield files with a compression format in the header (0x3)
+    StarfieldCompressed,
+    /// Fallout 4 files from the next-gen update (0x7)
+    Fallout4NextGen,
+    /// Fallout 4 files from the next-gen update (0x8)
+    Fallout4NextGen2,
+}
+
+impl BA2Version {
+    /// Number that the version is encoded as
+    pub fn number(self) -> u32 {
+        match self {
+            BA2Version::Fallout4 => 0x1,
+            BA2Version::Starfield => 0x2,
+            BA2Version::StarfieldCompressed => 0x3,
+            BA2Version::Fallout4NextGen => 0x7,
+            BA2Version::Fallout4NextGen2 => 0x8,
+        }
+    }
+
+    /// Size of the whole header, which grows with the extra fields of Starfield archives
+    pub(crate) fn header_len(self) -> usize {
+        match self {
+            BA2Version::Starfield => 0x20,
+            BA2Version::StarfieldCompressed => 0x24,
+            _ => 0x18,
+        }
+    }
 }
 
 named!(pub version_parser<BA2Version>, switch!(le_u32,
-    0x1 => value!(BA2Version::Fallout4)
+    0x1 => value!(BA2Version::Fallout4) |
+    0x2 => value!(BA2Version::Starfield) |
+    0x3 => value!(BA2Version::StarfieldCompressed) |
+    0x7 => value!(BA2Version::Fallout4NextGen) |
+    0x8 => value!(BA2Version::Fallout4NextGen2)
 ));
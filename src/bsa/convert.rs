@@ -0,0 +1,160 @@
+//! Conversion of Oblivion-style BSA archives between the versions used by different games
+//!
+//! Data blocks are copied as they are whenever the target version encodes them the same way, and are otherwise
+//! decompressed and compressed again with the target version's codec (zlib or LZ4).
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+// top-level imports
+use crate::Result;
+
+// bsa imports
+use crate::bsa::editor::BSAEditor;
+use crate::bsa::types::Version;
+
+/// Files that were converted, but probably won't work in the target game
+#[derive(Debug)]
+pub struct ConvertWarning {
+    /// Why the files won't work
+    pub reason: &'static str,
+    /// Every file the warning applies to
+    pub files:  Vec<PathBuf>,
+}
+
+impl fmt::Display for ConvertWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({} files", self.reason, self.files.len())?;
+        if let Some(file_path) = self.files.first() {
+            write!(f, ", e.g. {:#?}", file_path)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Converts the archive at `source` into the file format of `target` and writes it out to `output`. Returns a warning
+/// for each kind of content that doesn't carry over to the target game.
+pub fn convert(source: PathBuf, target: Version, output: &Path) -> Result<Vec<ConvertWarning>> {
+    let mut editor = BSAEditor::from_file(source)?;
+    let rules = incompatible_extensions(editor.version(), target);
+    editor.set_version(target)?;
+    editor.save(output)?;
+
+    let mut warnings: BTreeMap<&str, ConvertWarning> = BTreeMap::new();
+    for file_path in editor.file_paths() {
+        let extension = file_path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if let Some(&(rule_extension, reason)) = rules.iter().find(|(rule_extension, _)| *rule_extension == extension) {
+            warnings
+                .entry(rule_extension)
+                .or_insert_with(|| ConvertWarning {
+                    reason,
+                    files: Vec::new(),
+                })
+                .files
+                .push(file_path);
+        }
+    }
+    Ok(warnings.into_values().collect())
+}
+
+/// (extension, reason) pairs for content that doesn't work after converting between two versions
+fn incompatible_extensions(from: Version, to: Version) -> Vec<(&'static str, &'static str)> {
+    match (from, to) {
+        (Version::OBLIVION, Version::SKYRIM) | (Version::OBLIVION, Version::SKYRIMSE) => vec![
+            ("nif", "Oblivion meshes have to be converted before Skyrim can load them"),
+            ("kf", "Skyrim uses Havok behaviors (.hkx) instead of .kf animations"),
+            ("spt", "Skyrim doesn't support SpeedTree (.spt) trees"),
+        ],
+        (Version::SKYRIM, Version::SKYRIMSE) => vec![
+            ("nif", "Skyrim meshes should be optimized for Skyrim Special Edition"),
+            ("hkx", "32-bit Havok files have to be converted to 64-bit for Skyrim Special Edition"),
+        ],
+        (Version::SKYRIMSE, Version::SKYRIM) => vec![
+            ("nif", "Skyrim Special Edition meshes can't be loaded by the original Skyrim"),
+            ("hkx", "64-bit Havok files have to be converted to 32-bit for the original Skyrim"),
+        ],
+        (Version::SKYRIM, Version::OBLIVION) | (Version::SKYRIMSE, Version::OBLIVION) => vec![
+            ("nif", "Skyrim meshes can't be loaded by Oblivion"),
+            ("hkx", "Oblivion doesn't support Havok behavior files"),
+            ("pex", "Oblivion doesn't support Papyrus scripts"),
+            ("fuz", "Oblivion doesn't support .fuz voice files"),
+        ],
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bsa::BSAArchive;
+    use crate::Compression;
+
+    /// Extracts every file of `archive`, sorted by path
+    fn contents(archive: &mut BSAArchive) -> Vec<(PathBuf, Vec<u8>)> {
+        let mut file_paths: Vec<PathBuf> = archive.file_hashmap.keys().cloned().collect();
+        file_paths.sort();
+        file_paths
+            .into_iter()
+            .map(|file_path| {
+                let contents = archive.extract_by_name(&file_path).unwrap();
+                (file_path, contents)
+            })
+            .collect()
+    }
+
+    /// (reason, files) of every warning
+    fn summary(warnings: Vec<ConvertWarning>) -> Vec<(&'static str, Vec<PathBuf>)> {
+        warnings.into_iter().map(|warning| (warning.reason, warning.files)).collect()
+    }
+
+    #[test]
+    fn converting_to_oblivion_and_back_keeps_the_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut editor = BSAEditor::new(Version::SKYRIMSE).unwrap();
+        for &(file_path, contents) in &[
+            ("meshes/armor/helmet.nif", "helmet mesh"),
+            ("meshes/actors/idle.hkx", "behavior"),
+            ("scripts/quest.pex", "script"),
+            ("textures/armor/helmet.dds", "texture"),
+            ("readme.txt", "readme"),
+        ] {
+            editor.add(Path::new(file_path), contents.repeat(20).into_bytes()).unwrap();
+        }
+        let source = dir.path().join("source.bsa");
+        editor.save(&source).unwrap();
+
+        let oblivion = dir.path().join("oblivion.bsa");
+        let warnings = convert(source.clone(), Version::OBLIVION, &oblivion).unwrap();
+        assert_eq!(
+            summary(warnings),
+            vec![
+                ("Oblivion doesn't support Havok behavior files", vec![PathBuf::from("meshes/actors/idle.hkx")]),
+                ("Skyrim meshes can't be loaded by Oblivion", vec![PathBuf::from("meshes/armor/helmet.nif")]),
+                ("Oblivion doesn't support Papyrus scripts", vec![PathBuf::from("scripts/quest.pex")]),
+            ]
+        );
+        let mut source_archive = crate::bsa::from_file(source).unwrap();
+        let mut oblivion_archive = crate::bsa::from_file(oblivion.clone()).unwrap();
+        assert_eq!(oblivion_archive.header.version, Version::OBLIVION);
+        assert!(oblivion_archive.file_hashmap.values().all(|file| file.compression == Compression::Zlib));
+        assert_eq!(contents(&mut oblivion_archive), contents(&mut source_archive));
+
+        let back = dir.path().join("back.bsa");
+        let warnings = convert(oblivion, Version::SKYRIMSE, &back).unwrap();
+        assert_eq!(
+            summary(warnings),
+            vec![(
+                "Oblivion meshes have to be converted before Skyrim can load them",
+                vec![PathBuf::from("meshes/armor/helmet.nif")]
+            )]
+        );
+        let mut back_archive = crate::bsa::from_file(back).unwrap();
+        assert_eq!(back_archive.header.version, Version::SKYRIMSE);
+        assert!(back_archive.file_hashmap.values().all(|file| file.compression == Compression::Lz4));
+        assert_eq!(contents(&mut back_archive), contents(&mut source_archive));
+    }
+}
@@ -0,0 +1,1062 @@
+//! Editing and rewriting of Oblivion-style BSA archives
+//!
+//! Files that aren't touched keep their records, hashes and data blocks exactly as they were in the source archive,
+//! along with any padding in front of their data blocks, so saving an archive without any edits reproduces it byte for
+//! byte.
+use std::collections::HashMap;
+#[cfg(feature = "manifest")]
+use std::collections::HashSet;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use failure::ResultExt;
+
+// top-level imports
+use crate::archive::Extract;
+use crate::reader::{string_to_latin1, TESFile, TESReader};
+use crate::writer::{
+    check_new_path, copy_block, normalize_path, read_block, source_reader, write_atomically, Deduplicator, SourceGaps,
+};
+use crate::{Compression, CompressionPolicy, Result, WriteReport};
+
+// bsa imports
+use crate::bsa::hash::{hash_file_name, hash_folder_name};
+use crate::bsa::oblivion::{self, OBLayout};
+use crate::bsa::types::{ArchiveFlags, BSAFile, FileFlags, Version};
+
+/// Size of the header including the file magic
+const HEADER_LEN: u64 = 0x24;
+/// Size of a single file record
+const FILE_RECORD_LEN: u64 = 0x10;
+/// Size of a single Oblivion-style folder record
+const OB_FOLDER_RECORD_LEN: u64 = 0x10;
+/// Size of a single Skyrim Special Edition-style folder record
+const SSE_FOLDER_RECORD_LEN: u64 = 0x18;
+/// Bit in the size field of a file record that inverts the archive's default compression
+const COMPRESSION_BIT: u32 = 0x4000_0000;
+/// Bits in the size field of a file record that aren't part of the size
+const SIZE_FLAG_BITS: u32 = 0xc000_0000;
+
+/// Where the data of a single file comes from
+enum BSAContents {
+    /// A data block copied byte for byte from the source archive
+    Source {
+        /// Size field of the file record, including the compression bit
+        raw_size: u32,
+        /// Offset from file byte zero of the source archive to the data block
+        offset:   u32,
+        /// Whether the file was renamed, meaning that an embedded file name has to be rewritten
+        renamed:  bool,
+    },
+    /// New uncompressed file content, which is compressed under the editor's policy when saving
+    New(Vec<u8>),
+}
+
+struct EditFile {
+    /// Name of the file without its folder
+    name:      String,
+    name_hash: u64,
+    contents:  BSAContents,
+}
+
+struct EditFolder {
+    /// Name of the folder using '\\' as the separator
+    name:      String,
+    name_hash: u64,
+    /// Padding following the file count (Skyrim Special Edition only)
+    unknown:   [u8; 4],
+    /// Upper half of the 64-bit offset (Skyrim Special Edition only)
+    unknown2:  [u8; 4],
+    files:     Vec<EditFile>,
+}
+
+/// An Oblivion-style BSA archive whose files can be replaced, added, removed and renamed before saving it back out
+pub struct BSAEditor {
+    /// Path on disk to the archive being edited (`None` for archives created from scratch)
+    source:        Option<PathBuf>,
+    version:       Version,
+    offset:        u32,
+    archive_flags: ArchiveFlags,
+    file_flags:    FileFlags,
+    /// Whether the file flags are worked out from the files when saving instead of using `file_flags`
+    infer_file_flags: bool,
+    unknown_bytes: [u8; 2],
+    folders:       Vec<EditFolder>,
+    /// Decides how new and re-encoded files are compressed
+    policy:        CompressionPolicy,
+    /// Version of the source archive, which decides how its data blocks are encoded
+    source_version:  Version,
+    /// Whether the data blocks of the source archive start with an embedded file name
+    source_has_name: bool,
+    /// Whether data blocks with identical content are only written once
+    deduplicate:     bool,
+    /// Unused space between the data blocks of the source archive, which is kept around the blocks that are copied
+    source_gaps:     SourceGaps,
+}
+
+/// Where the data of a single file ends up in the saved archive
+enum PlannedBlock<'a> {
+    /// Copied straight from the source archive
+    Copy { offset: u64, size: u64 },
+    /// An embedded file name followed by data from the source archive which came after the old embedded name
+    Renamed { name: Vec<u8>, offset: u64, size: u64 },
+    /// An optional embedded file name followed by new or re-encoded file content
+    New { name: Vec<u8>, data: &'a [u8] },
+}
+
+impl<'a> PlannedBlock<'a> {
+    fn size(&self) -> u64 {
+        match self {
+            PlannedBlock::Copy { size, .. } => *size,
+            PlannedBlock::Renamed { name, size, .. } => name.len() as u64 + *size,
+            PlannedBlock::New { name, data } => (name.len() + data.len()) as u64,
+        }
+    }
+
+    /// Reads the content of the block exactly as it will be written
+    fn read(&self, reader: &mut Option<&mut TESFile>) -> Result<Vec<u8>> {
+        match *self {
+            PlannedBlock::Copy { offset, size } => read_block(source_reader(reader)?, offset, size),
+            PlannedBlock::Renamed { ref name, offset, size } => {
+                let mut block = name.clone();
+                block.extend(read_block(source_reader(reader)?, offset, size)?);
+                Ok(block)
+            }
+            PlannedBlock::New { ref name, data } => {
+                let mut block = name.clone();
+                block.extend_from_slice(data);
+                Ok(block)
+            }
+        }
+    }
+}
+
+impl BSAEditor {
+    /// Given a file path to an Oblivion-style BSA file, opens it for editing. Xbox 360 archives can't be edited.
+    pub fn from_file(path: PathBuf) -> Result<BSAEditor> {
+        let mut reader = TESReader::from_file(&path)?;
+
+        let mut file_magic = [0; 4];
+        reader
+            .read_exact(&mut file_magic)
+            .context("Unable to read BSA file identifier")?;
+        if &file_magic != b"BSA\0" {
+            return Err(format_err!("Only Oblivion-style BSA files can be edited"));
+        }
+
+        let OBLayout {
+            header,
+            folders,
+            file_names,
+        } = oblivion::read_layout(&mut reader)?;
+        // their records are big-endian and their files may be compressed with XMem, neither of which can be written
+        if header.archive_flags.intersects(ArchiveFlags::XBOX_360_ARCHIVE | ArchiveFlags::XMEM_CODEC) {
+            return Err(format_err!("Xbox 360 BSA files can't be edited"));
+        }
+        let data_start = reader.stream_position()?;
+        let data_end = reader.seek(SeekFrom::End(0))?;
+        let blocks = folders.iter().flat_map(|folder| &folder.file_records).map(|file_record| {
+            (u64::from(file_record.offset), u64::from(file_record.raw_size & !SIZE_FLAG_BITS))
+        });
+        let source_gaps = SourceGaps::new(data_start, data_end, blocks);
+
+        let mut file_names = file_names.into_iter();
+        let mut edit_folders = Vec::with_capacity(folders.len());
+        for folder in folders {
+            let mut files = Vec::with_capacity(folder.file_records.len());
+            for file_record in folder.file_records {
+                let name = file_names
+                    .next()
+                    .ok_or_else(|| format_err!("There are fewer file names than file records"))?;
+                files.push(EditFile {
+                    name,
+                    name_hash: file_record.name_hash,
+                    contents: BSAContents::Source {
+                        raw_size: file_record.raw_size,
+                        offset:   file_record.offset,
+                        renamed:  false,
+                    },
+                });
+            }
+            edit_folders.push(EditFolder {
+                name: folder.name,
+                name_hash: folder.name_hash,
+                unknown: folder.unknown,
+                unknown2: folder.unknown2,
+                files,
+            });
+        }
+
+        // archives that were written uncompressed are usually that way on purpose, so new files follow suit
+        let policy = if header.archive_flags.contains(ArchiveFlags::COMPRESSED_ARCHIVE) {
+            CompressionPolicy::default()
+        } else {
+            CompressionPolicy::uncompressed()
+        };
+
+        Ok(BSAEditor {
+            source: Some(path),
+            version: header.version,
+            offset: header.offset,
+            archive_flags: header.archive_flags,
+            file_flags: header.file_flags,
+            infer_file_flags: false,
+            unknown_bytes: header.unknown_bytes,
+            folders: edit_folders,
+            policy,
+            source_version: header.version,
+            source_has_name: has_embedded_names(header.version, header.archive_flags),
+            deduplicate: false,
+            source_gaps,
+        })
+    }
+
+    /// Creates an empty archive of the given version. Its files are compressed with the codec of the version, files
+    /// with identical content share their data and its file flags are worked out from its files unless the policy,
+    /// deduplication or flags are changed.
+    pub fn new(version: Version) -> Result<BSAEditor> {
+        if version == Version::MORROWIND {
+            return Err(format_err!("Morrowind BSA files can't be written"));
+        }
+        Ok(BSAEditor {
+            source: None,
+            version,
+            offset: HEADER_LEN as u32,
+            archive_flags: ArchiveFlags::INCLUDE_DIR_NAMES
+                | ArchiveFlags::INCLUDE_FILE_NAMES
+                | ArchiveFlags::COMPRESSED_ARCHIVE,
+            file_flags: FileFlags::empty(),
+            infer_file_flags: true,
+            unknown_bytes: [0; 2],
+            folders: Vec::new(),
+            policy: CompressionPolicy::default(),
+            source_version: version,
+            source_has_name: false,
+            deduplicate: true,
+            source_gaps: SourceGaps::default(),
+        })
+    }
+
+    /// Changes the version the archive is saved as. Data blocks whose encoding differs between the two versions are
+    /// decompressed and compressed again on save.
+    pub fn set_version(&mut self, version: Version) -> Result<()> {
+        if version == Version::MORROWIND {
+            return Err(format_err!("Morrowind BSA files can't be written"));
+        }
+        if self.version == Version::OBLIVION && version != Version::OBLIVION {
+            // Oblivion uses these bits for something else, and later versions would read file names from the data
+            self.archive_flags
+                .remove(ArchiveFlags::EMBED_FILE_NAMES | ArchiveFlags::UNKNOWN_OBLIVION_FLAG);
+        }
+        self.version = version;
+        Ok(())
+    }
+
+    /// Version the archive will be saved as
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Archive flags the archive will be saved with
+    pub fn archive_flags(&self) -> ArchiveFlags {
+        self.archive_flags
+    }
+
+    /// Changes the archive flags the archive is saved with. Folder and file names are always written, so the flags
+    /// that include them are kept no matter what. Xbox 360 archives can't be written, and an archive opened from a file
+    /// keeps its default compression because the records of its files are relative to it.
+    pub fn set_archive_flags(&mut self, archive_flags: ArchiveFlags) -> Result<()> {
+        if archive_flags.intersects(ArchiveFlags::XBOX_360_ARCHIVE | ArchiveFlags::XMEM_CODEC) {
+            return Err(format_err!("Xbox 360 BSA files can't be written"));
+        }
+        let compression_changed = (archive_flags ^ self.archive_flags).contains(ArchiveFlags::COMPRESSED_ARCHIVE);
+        if self.source.is_some() && compression_changed {
+            return Err(format_err!("The default compression of an existing archive can't be changed"));
+        }
+        self.archive_flags = archive_flags | ArchiveFlags::INCLUDE_DIR_NAMES | ArchiveFlags::INCLUDE_FILE_NAMES;
+        Ok(())
+    }
+
+    /// File flags the archive will be saved with
+    pub fn file_flags(&self) -> FileFlags {
+        if self.infer_file_flags {
+            FileFlags::from_paths(self.file_paths())
+        } else {
+            self.file_flags
+        }
+    }
+
+    /// Changes the file flags the archive is saved with, which tell the game what kinds of files are inside of it. The
+    /// flags are no longer worked out from the files after this.
+    pub fn set_file_flags(&mut self, file_flags: FileFlags) {
+        self.file_flags = file_flags;
+        self.infer_file_flags = false;
+    }
+
+    /// Paths of every file in the archive, in file record order
+    pub(crate) fn file_paths(&self) -> Vec<PathBuf> {
+        let mut file_paths = Vec::new();
+        for folder in &self.folders {
+            // folder names use '\\' as a separator, which only Windows understands
+            #[cfg(not(windows))]
+            let folder_name = folder.name.replace("\\", "/");
+            #[cfg(windows)]
+            let folder_name = &folder.name;
+
+            for file in &folder.files {
+                file_paths.push(Path::new(&folder_name).join(&file.name));
+            }
+        }
+        file_paths
+    }
+
+    /// Sets how new files are compressed when the archive is saved. Files that came from the source archive are
+    /// only compressed again if the policy doesn't reuse compressed data.
+    pub fn set_compression_policy(&mut self, policy: CompressionPolicy) {
+        self.policy = policy;
+    }
+
+    /// Sets whether data blocks with identical content are written only once, with every file record that uses them
+    /// pointing at the same data. This is off by default so that saving an archive without edits reproduces the
+    /// source archive. Archives that embed file names in their data blocks never share them, since the name is
+    /// different for every file.
+    pub fn set_deduplicate(&mut self, deduplicate: bool) {
+        self.deduplicate = deduplicate;
+    }
+
+    /// Replaces the content of an existing file
+    pub fn replace(&mut self, file_path: &Path, contents: Vec<u8>) -> Result<()> {
+        let (folder_index, file_index) = self.find(file_path)?;
+        self.folders[folder_index].files[file_index].contents = BSAContents::New(contents);
+        Ok(())
+    }
+
+    /// Adds a new file to the archive
+    pub fn add(&mut self, file_path: &Path, contents: Vec<u8>) -> Result<()> {
+        self.insert(file_path, BSAContents::New(contents))
+    }
+
+    /// Removes a file from the archive, along with its folder if the folder is left empty
+    pub fn remove(&mut self, file_path: &Path) -> Result<()> {
+        self.take(file_path).map(|_| ())
+    }
+
+    /// Moves a file to a new path without changing its content
+    pub fn rename(&mut self, from: &Path, to: &Path) -> Result<()> {
+        if self.find(to).is_ok() {
+            return Err(format_err!("File {:#?} already exists", to));
+        }
+        let contents = match self.take(from)? {
+            BSAContents::Source { raw_size, offset, .. } => BSAContents::Source {
+                raw_size,
+                offset,
+                renamed: true,
+            },
+            new_contents => new_contents,
+        };
+        self.insert(to, contents)
+    }
+
+    /// Writes the archive out to `path` and reports how much deduplication saved. Saving over the archive being edited
+    /// is allowed, but the editor must be opened again afterwards because the data it points to has moved.
+    pub fn save(&self, path: &Path) -> Result<WriteReport> {
+        let mut report = WriteReport::default();
+        write_atomically(path, |writer| {
+            report = match self.source {
+                Some(ref source) => {
+                    let mut reader = TESReader::from_file(source)?;
+                    self.write(Some(&mut reader), writer)?
+                }
+                None => self.write(None, writer)?,
+            };
+            Ok(())
+        })
+        .context(format!("Failed to save {:#?}", path))?;
+        Ok(report)
+    }
+
+    /// Splits a path into the normalized names of its folder and file
+    fn split_path(file_path: &Path) -> (String, String) {
+        let normalized = normalize_path(file_path);
+        match normalized.rfind('\\') {
+            Some(separator) => (
+                normalized[..separator].to_string(),
+                normalized[separator + 1..].to_string(),
+            ),
+            None => (String::new(), normalized),
+        }
+    }
+
+    /// Finds the (folder, file) indices of a file path
+    fn find(&self, file_path: &Path) -> Result<(usize, usize)> {
+        let (folder_name, file_name) = BSAEditor::split_path(file_path);
+        self.folders
+            .iter()
+            .position(|folder| folder.name.eq_ignore_ascii_case(&folder_name))
+            .and_then(|folder_index| {
+                self.folders[folder_index]
+                    .files
+                    .iter()
+                    .position(|file| file.name.eq_ignore_ascii_case(&file_name))
+                    .map(|file_index| (folder_index, file_index))
+            })
+            .ok_or_else(|| format_err!("File {:#?} not found", file_path))
+    }
+
+    /// Removes a file and returns its contents
+    fn take(&mut self, file_path: &Path) -> Result<BSAContents> {
+        let (folder_index, file_index) = self.find(file_path)?;
+        let file = self.folders[folder_index].files.remove(file_index);
+        if self.folders[folder_index].files.is_empty() {
+            self.folders.remove(folder_index);
+        }
+        Ok(file.contents)
+    }
+
+    /// Adds a file, keeping folders and files sorted by hash so that the game can still look them up. Names break ties
+    /// between equal hashes, so an archive created from scratch comes out the same no matter what order its files were
+    /// added in. Existing records are never reordered.
+    fn insert(&mut self, file_path: &Path, contents: BSAContents) -> Result<()> {
+        check_new_path(file_path)?;
+        if self.find(file_path).is_ok() {
+            return Err(format_err!("File {:#?} already exists", file_path));
+        }
+        let (folder_name, file_name) = BSAEditor::split_path(file_path);
+
+        let folder_index = match self
+            .folders
+            .iter()
+            .position(|folder| folder.name.eq_ignore_ascii_case(&folder_name))
+        {
+            Some(folder_index) => folder_index,
+            None => {
+                let name_hash = hash_folder_name(&folder_name);
+                let folder_index = self
+                    .folders
+                    .iter()
+                    .position(|folder| {
+                        (folder.name_hash, folder.name.to_ascii_lowercase()) > (name_hash, folder_name.clone())
+                    })
+                    .unwrap_or(self.folders.len());
+                self.folders.insert(
+                    folder_index,
+                    EditFolder {
+                        name: folder_name,
+                        name_hash,
+                        unknown: [0; 4],
+                        unknown2: [0; 4],
+                        files: Vec::new(),
+                    },
+                );
+                folder_index
+            }
+        };
+
+        let files = &mut self.folders[folder_index].files;
+        let name_hash = hash_file_name(&file_name);
+        let file_index = files
+            .iter()
+            .position(|file| (file.name_hash, file.name.to_ascii_lowercase()) > (name_hash, file_name.clone()))
+            .unwrap_or(files.len());
+        files.insert(
+            file_index,
+            EditFile {
+                name: file_name,
+                name_hash,
+                contents,
+            },
+        );
+        Ok(())
+    }
+
+    /// Serializes the whole archive. Follows the Oblivion BSA file structure (described in oblivion.rs)
+    fn write<W: Write>(&self, mut reader: Option<&mut TESFile>, writer: &mut W) -> Result<WriteReport> {
+        let has_name = has_embedded_names(self.version, self.archive_flags);
+        let folder_record_len = if self.version == Version::SKYRIMSE {
+            SSE_FOLDER_RECORD_LEN
+        } else {
+            OB_FOLDER_RECORD_LEN
+        };
+
+        let folder_names = self
+            .folders
+            .iter()
+            .map(|folder| string_to_latin1(&folder.name))
+            .collect::<Result<Vec<Vec<u8>>>>()?;
+        let file_names = self
+            .folders
+            .iter()
+            .flat_map(|folder| folder.files.iter())
+            .map(|file| string_to_latin1(&file.name))
+            .collect::<Result<Vec<Vec<u8>>>>()?;
+        let file_count = file_names.len();
+        let total_folder_name_length: usize = folder_names.iter().map(|name| name.len() + 1).sum();
+        let total_file_name_length: usize = file_names.iter().map(|name| name.len() + 1).sum();
+
+        // work out where every section of the archive starts
+        let folder_blocks_start = HEADER_LEN + folder_record_len * self.folders.len() as u64;
+        let folder_blocks_len: u64 = self
+            .folders
+            .iter()
+            .zip(&folder_names)
+            .map(|(folder, name)| name.len() as u64 + 2 + FILE_RECORD_LEN * folder.files.len() as u64)
+            .sum();
+        let data_start = folder_blocks_start + folder_blocks_len + total_file_name_length as u64;
+
+        // Compress new content under the policy, along with the content of the source archive if it isn't reused or
+        // its encoding doesn't fit the version being written. Every encoded block is paired with whether it ended up
+        // compressed.
+        let default_compressed = self.archive_flags.contains(ArchiveFlags::COMPRESSED_ARCHIVE);
+        let codec_changed = codec_for(self.source_version) != codec_for(self.version);
+        let mut encoded: Vec<Option<(Vec<u8>, bool)>> = Vec::with_capacity(file_count);
+        for folder in &self.folders {
+            for file in &folder.files {
+                let data = match file.contents {
+                    BSAContents::Source { raw_size, offset, .. } => {
+                        let is_compressed = default_compressed != (raw_size & COMPRESSION_BIT != 0);
+                        if !self.policy.reuse_compressed
+                            || has_name != self.source_has_name
+                            || (is_compressed && codec_changed)
+                        {
+                            Some(self.decode_source(source_reader(&mut reader)?, raw_size, offset, is_compressed)?)
+                        } else {
+                            None
+                        }
+                    }
+                    BSAContents::New(ref data) => Some(data.clone()),
+                };
+                encoded.push(match data {
+                    Some(data) => Some(self.encode(&file.name, data)?),
+                    None => None,
+                });
+            }
+        }
+
+        // plan out the data blocks of every file, in the same order as the file records
+        let mut blocks = Vec::with_capacity(file_count);
+        let mut encoded_iter = encoded.iter();
+        for folder in &self.folders {
+            for file in &folder.files {
+                let encoded_data = encoded_iter.next().and_then(Option::as_ref);
+                let embedded_name = if has_name {
+                    embedded_name(&folder.name, &file.name)?
+                } else {
+                    Vec::new()
+                };
+                let block = match (encoded_data, &file.contents) {
+                    (Some((data, _)), _) => PlannedBlock::New {
+                        name: embedded_name,
+                        data,
+                    },
+                    (
+                        None,
+                        &BSAContents::Source {
+                            raw_size,
+                            offset,
+                            renamed,
+                        },
+                    ) => {
+                        let offset = u64::from(offset);
+                        let size = u64::from(raw_size & !SIZE_FLAG_BITS);
+                        if renamed && has_name {
+                            // skip over the old embedded name, which is a byte-length prefixed bstring
+                            let reader = source_reader(&mut reader)?;
+                            reader.seek(SeekFrom::Start(offset))?;
+                            let mut name_len = [0; 1];
+                            reader.read_exact(&mut name_len)?;
+                            let old_name_len = u64::from(name_len[0]) + 1;
+                            PlannedBlock::Renamed {
+                                name:   embedded_name,
+                                offset: offset + old_name_len,
+                                size:   size.saturating_sub(old_name_len),
+                            }
+                        } else {
+                            PlannedBlock::Copy { offset, size }
+                        }
+                    }
+                    (None, BSAContents::New(_)) => unreachable!("new content is always encoded"),
+                };
+                blocks.push(block);
+            }
+        }
+
+        // Untouched blocks keep the order they had in the source archive along with the padding in front of them, and
+        // blocks shared by several file records stay shared. Everything else is written after them in file record
+        // order. When deduplicating, blocks whose content was already written point at the earlier copy instead.
+        let mut copy_order: Vec<usize> = (0..blocks.len())
+            .filter(|&i| matches!(blocks[i], PlannedBlock::Copy { .. }))
+            .collect();
+        copy_order.sort_by_key(|&i| match blocks[i] {
+            PlannedBlock::Copy { offset, .. } => offset,
+            _ => 0,
+        });
+        let other_order = (0..blocks.len()).filter(|&i| !matches!(blocks[i], PlannedBlock::Copy { .. }));
+
+        let mut block_offsets = vec![0; blocks.len()];
+        // index of every block that's written, along with the (offset, size) of the padding copied in front of it
+        let mut write_order = Vec::with_capacity(blocks.len());
+        let mut shared_offsets: HashMap<(u64, u64), u64> = HashMap::new();
+        let mut deduplicator = Deduplicator::default();
+        let mut next_offset = data_start;
+        for i in copy_order.into_iter().chain(other_order) {
+            let padding = match blocks[i] {
+                PlannedBlock::Copy { offset, size } => {
+                    if let Some(&shared_offset) = shared_offsets.get(&(offset, size)) {
+                        block_offsets[i] = shared_offset;
+                        continue;
+                    }
+                    self.source_gaps.before(offset)
+                }
+                _ => (0, 0),
+            };
+            let mut block_offset = next_offset + padding.1;
+            let mut duplicate = false;
+            if self.deduplicate && !has_name {
+                let data = blocks[i].read(&mut reader)?;
+                let load = |index: usize| blocks[index].read(&mut reader);
+                if let Some(offset) = deduplicator.find(i, block_offset, &data, load)? {
+                    block_offset = offset;
+                    duplicate = true;
+                }
+            }
+            block_offsets[i] = block_offset;
+            if let PlannedBlock::Copy { offset, size } = blocks[i] {
+                shared_offsets.insert((offset, size), block_offset);
+            }
+            if !duplicate {
+                write_order.push((i, padding));
+                next_offset = block_offset + blocks[i].size();
+            }
+        }
+        // unused space at the end of the source stays at the end
+        let trailing_padding = self.source_gaps.after();
+        next_offset += trailing_padding.1;
+        if next_offset > u64::from(u32::MAX) {
+            return Err(format_err!("Archive would be {} bytes, which is too large for a BSA", next_offset));
+        }
+
+        // header
+        let version = match self.version {
+            Version::OBLIVION => 0x67,
+            Version::SKYRIM => 0x68,
+            Version::SKYRIMSE => 0x69,
+            Version::MORROWIND => return Err(format_err!("Morrowind BSA files can't be written")),
+        };
+        writer.write_all(b"BSA\0")?;
+        writer.write_u32::<LittleEndian>(version)?;
+        writer.write_u32::<LittleEndian>(self.offset)?;
+        writer.write_u32::<LittleEndian>(self.archive_flags.bits())?;
+        writer.write_u32::<LittleEndian>(self.folders.len() as u32)?;
+        writer.write_u32::<LittleEndian>(file_count as u32)?;
+        writer.write_u32::<LittleEndian>(total_folder_name_length as u32)?;
+        writer.write_u32::<LittleEndian>(total_file_name_length as u32)?;
+        writer.write_u16::<LittleEndian>(self.file_flags().bits())?;
+        writer.write_all(&self.unknown_bytes)?;
+
+        // folder records, whose offsets point to the folder's block plus the length of the file name block
+        let mut folder_block_offset = folder_blocks_start;
+        for (folder, name) in self.folders.iter().zip(&folder_names) {
+            let offset = (folder_block_offset + total_file_name_length as u64) as u32;
+            writer.write_u64::<LittleEndian>(folder.name_hash)?;
+            writer.write_u32::<LittleEndian>(folder.files.len() as u32)?;
+            if self.version == Version::SKYRIMSE {
+                writer.write_all(&folder.unknown)?;
+                writer.write_u32::<LittleEndian>(offset)?;
+                writer.write_all(&folder.unknown2)?;
+            } else {
+                writer.write_u32::<LittleEndian>(offset)?;
+            }
+            folder_block_offset += name.len() as u64 + 2 + FILE_RECORD_LEN * folder.files.len() as u64;
+        }
+
+        // folder blocks: a bzstring folder name followed by the records of the files in the folder
+        let mut block_index = 0;
+        for (folder, name) in self.folders.iter().zip(&folder_names) {
+            if name.len() >= 0xff {
+                return Err(format_err!("Folder name {:?} is too long", folder.name));
+            }
+            writer.write_u8(name.len() as u8 + 1)?;
+            writer.write_all(name)?;
+            writer.write_u8(0)?;
+            for file in &folder.files {
+                let size = blocks[block_index].size();
+                if size > u64::from(!SIZE_FLAG_BITS) {
+                    return Err(format_err!("File {:?} is too large for a BSA", file.name));
+                }
+                let size_flags = match (&encoded[block_index], &file.contents) {
+                    // the compression bit marks files whose compression differs from the archive's default
+                    (Some((_, is_compressed)), _) if *is_compressed != default_compressed => COMPRESSION_BIT,
+                    (Some(_), _) => 0,
+                    (None, BSAContents::Source { raw_size, .. }) => raw_size & SIZE_FLAG_BITS,
+                    (None, BSAContents::New(_)) => unreachable!("new content is always encoded"),
+                };
+                writer.write_u64::<LittleEndian>(file.name_hash)?;
+                writer.write_u32::<LittleEndian>(size as u32 | size_flags)?;
+                writer.write_u32::<LittleEndian>(block_offsets[block_index] as u32)?;
+                block_index += 1;
+            }
+        }
+
+        // file name block
+        for name in &file_names {
+            writer.write_all(name)?;
+            writer.write_u8(0)?;
+        }
+
+        // file data
+        for (i, (padding_offset, padding_size)) in write_order {
+            if padding_size > 0 {
+                copy_block(source_reader(&mut reader)?, padding_offset, padding_size, writer)?;
+            }
+            match blocks[i] {
+                PlannedBlock::Copy { offset, size } => copy_block(source_reader(&mut reader)?, offset, size, writer)?,
+                PlannedBlock::Renamed {
+                    ref name,
+                    offset,
+                    size,
+                } => {
+                    writer.write_all(name)?;
+                    copy_block(source_reader(&mut reader)?, offset, size, writer)?;
+                }
+                PlannedBlock::New { ref name, data } => {
+                    writer.write_all(name)?;
+                    writer.write_all(data)?;
+                }
+            }
+        }
+        if trailing_padding.1 > 0 {
+            copy_block(source_reader(&mut reader)?, trailing_padding.0, trailing_padding.1, writer)?;
+        }
+
+        Ok(deduplicator.report)
+    }
+
+    /// Reads the uncompressed content of a file in the source archive
+    fn decode_source(
+        &self,
+        reader: &mut TESFile,
+        raw_size: u32,
+        offset: u32,
+        is_compressed: bool,
+    ) -> Result<Vec<u8>> {
+        let compression = if is_compressed {
+            codec_for(self.source_version)
+        } else {
+            Compression::None
+        };
+        let source_file = BSAFile {
+            index: 0,
+            name_hash: 0,
+            folder_hash: None,
+            has_name: self.source_has_name,
+            compression,
+            compression_toggled: false,
+            size: raw_size & !SIZE_FLAG_BITS,
+            offset,
+        };
+        source_file.extract(reader)
+    }
+
+    /// Compresses a file's content under the policy, returning the data block (without an embedded name) and whether
+    /// it ended up compressed
+    fn encode(&self, file_name: &str, data: Vec<u8>) -> Result<(Vec<u8>, bool)> {
+        match self
+            .policy
+            .compress_buffer(codec_for(self.version), Path::new(file_name), &data)?
+        {
+            Some(block) => Ok((block, true)),
+            None => Ok((data, false)),
+        }
+    }
+}
+
+/// Codec used by compressed files in a version of the format
+fn codec_for(version: Version) -> Compression {
+    match version {
+        Version::SKYRIMSE => Compression::Lz4,
+        _ => Compression::Zlib,
+    }
+}
+
+/// Whether data blocks start with an embedded file name. Oblivion sets the flag but never embeds names.
+fn has_embedded_names(version: Version, archive_flags: ArchiveFlags) -> bool {
+    archive_flags.contains(ArchiveFlags::EMBED_FILE_NAMES) && version != Version::OBLIVION
+}
+
+/// Works out how many bytes an archive spends on anything other than file content: its header, records and names,
+/// along with the embedded name and original size that go in front of the data of each file. `files` pairs the path of
+/// every file with whether it's compressed.
+#[cfg(feature = "manifest")]
+pub(crate) fn overhead_len<P, I>(version: Version, archive_flags: ArchiveFlags, files: I) -> u64
+where
+    P: AsRef<Path>,
+    I: IntoIterator<Item = (P, bool)>,
+{
+    let folder_record_len = if version == Version::SKYRIMSE {
+        SSE_FOLDER_RECORD_LEN
+    } else {
+        OB_FOLDER_RECORD_LEN
+    };
+    let has_name = has_embedded_names(version, archive_flags);
+
+    let mut folder_names = HashSet::new();
+    let mut len = HEADER_LEN;
+    for (file_path, compressed) in files {
+        let (folder_name, file_name) = BSAEditor::split_path(file_path.as_ref());
+        // file record and name, plus the name's terminator
+        len += FILE_RECORD_LEN + file_name.len() as u64 + 1;
+        if has_name {
+            // a length byte and the full path, where files in the root folder have no separator
+            let separator_len = if folder_name.is_empty() { 0 } else { 1 };
+            len += 1 + folder_name.len() as u64 + separator_len + file_name.len() as u64;
+        }
+        if compressed {
+            len += 4;
+        }
+        if !folder_names.contains(&folder_name) {
+            // folder record and name, plus the name's length byte and terminator
+            len += folder_record_len + folder_name.len() as u64 + 2;
+            folder_names.insert(folder_name);
+        }
+    }
+    len
+}
+
+/// Builds the bstring containing the full path of a file that goes in front of its data
+fn embedded_name(folder_name: &str, file_name: &str) -> Result<Vec<u8>> {
+    let full_path = if folder_name.is_empty() {
+        file_name.to_string()
+    } else {
+        format!("{}\\{}", folder_name, file_name)
+    };
+    let mut name = string_to_latin1(&full_path)?;
+    if name.len() > 0xff {
+        return Err(format_err!("File path {:?} is too long to embed", full_path));
+    }
+    name.insert(0, name.len() as u8);
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds an archive out of `files` in the given order and returns the saved bytes
+    fn save_files(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut editor = BSAEditor::new(Version::SKYRIMSE).unwrap();
+        for &(file_path, contents) in files {
+            editor.add(Path::new(file_path), contents.to_vec()).unwrap();
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.bsa");
+        editor.save(&path).unwrap();
+        std::fs::read(path).unwrap()
+    }
+
+    #[test]
+    fn insertion_order_and_separators_dont_change_the_output() {
+        let first = save_files(&[
+            ("meshes/armor/helmet.nif", b"helmet"),
+            ("textures\\armor\\helmet.dds", b"texture"),
+            ("meshes/boots.nif", b"boots"),
+            ("readme.txt", b"helmet"),
+        ]);
+        let second = save_files(&[
+            ("readme.txt", b"helmet"),
+            ("meshes\\boots.nif", b"boots"),
+            ("textures/armor/helmet.dds", b"texture"),
+            ("meshes\\armor/helmet.nif", b"helmet"),
+        ]);
+        assert_eq!(first, second);
+    }
+
+    /// Rewrites the archive at `path` with `gap` bytes of padding in front of the data of every file and after the last
+    /// one, the way archives packed by other tools can be laid out
+    fn add_padding(path: &Path, gap: usize) {
+        let archive = crate::bsa::from_file(path.to_path_buf()).unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        let mut files: Vec<&BSAFile> = archive.file_hashmap.values().collect();
+        files.sort_by_key(|file| file.offset);
+        let mut padded = bytes[..files[0].offset as usize].to_vec();
+        for (i, file) in files.iter().enumerate() {
+            let end = files.get(i + 1).map_or(bytes.len(), |next| next.offset as usize);
+            padded.extend(std::iter::repeat(0xAB).take(gap));
+            // the offset is the last field of the file record, which starts with the name hash
+            let record = bytes.windows(8).position(|window| window == file.name_hash.to_le_bytes()).unwrap();
+            let offset = padded.len() as u32;
+            padded[record + 12..record + 16].copy_from_slice(&offset.to_le_bytes());
+            padded.extend_from_slice(&bytes[file.offset as usize..end]);
+        }
+        padded.extend(std::iter::repeat(0xCD).take(gap));
+        std::fs::write(path, padded).unwrap();
+    }
+
+    /// Saves an archive with files in several folders and padding around their data, returning its path
+    fn padded_archive(dir: &Path) -> PathBuf {
+        let mut editor = BSAEditor::new(Version::SKYRIMSE).unwrap();
+        for &(file_path, contents) in &[
+            ("meshes/armor/helmet.nif", &b"helmet"[..]),
+            ("meshes/armor/boots.nif", &[7; 300][..]),
+            ("textures/armor/helmet.dds", &b"texture"[..]),
+            ("readme.txt", &b"readme"[..]),
+        ] {
+            editor.add(Path::new(file_path), contents.to_vec()).unwrap();
+        }
+        let path = dir.join("padded.bsa");
+        editor.save(&path).unwrap();
+        add_padding(&path, 5);
+        path
+    }
+
+    #[test]
+    fn saving_without_edits_reproduces_the_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = padded_archive(dir.path());
+        let mut archive = crate::bsa::from_file(source.clone()).unwrap();
+        assert_eq!(archive.extract_by_name(Path::new("meshes/armor/boots.nif")).unwrap(), vec![7; 300]);
+
+        let copy = dir.path().join("copy.bsa");
+        BSAEditor::from_file(source.clone()).unwrap().save(&copy).unwrap();
+        assert_eq!(std::fs::read(copy).unwrap(), std::fs::read(source).unwrap());
+    }
+
+    #[test]
+    fn edits_keep_the_records_and_data_of_untouched_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = padded_archive(dir.path());
+        let mut editor = BSAEditor::from_file(source.clone()).unwrap();
+        editor.replace(Path::new("readme.txt"), b"new readme".to_vec()).unwrap();
+        editor.rename(Path::new("meshes/armor/helmet.nif"), Path::new("meshes/armor/hat.nif")).unwrap();
+        editor.remove(Path::new("meshes/armor/boots.nif")).unwrap();
+        let edited = dir.path().join("edited.bsa");
+        editor.save(&edited).unwrap();
+
+        let source_bytes = std::fs::read(&source).unwrap();
+        let edited_bytes = std::fs::read(&edited).unwrap();
+        let stored =
+            |bytes: &[u8], file: &BSAFile| bytes[file.offset as usize..(file.offset + file.size) as usize].to_vec();
+        let source_archive = crate::bsa::from_file(source).unwrap();
+        let mut edited_archive = crate::bsa::from_file(edited).unwrap();
+        let untouched = &source_archive.file_hashmap[Path::new("textures/armor/helmet.dds")];
+        let copied = &edited_archive.file_hashmap[Path::new("textures/armor/helmet.dds")];
+        assert_eq!((copied.name_hash, copied.size), (untouched.name_hash, untouched.size));
+        assert_eq!(stored(&edited_bytes, copied), stored(&source_bytes, untouched));
+        let helmet = &source_archive.file_hashmap[Path::new("meshes/armor/helmet.nif")];
+        let hat = &edited_archive.file_hashmap[Path::new("meshes/armor/hat.nif")];
+        assert_eq!(stored(&edited_bytes, hat), stored(&source_bytes, helmet));
+        let boots = stored(&source_bytes, &source_archive.file_hashmap[Path::new("meshes/armor/boots.nif")]);
+        assert!(!edited_bytes.windows(boots.len()).any(|window| window == &boots[..]));
+        assert!(!edited_archive.file_hashmap.contains_key(Path::new("meshes/armor/boots.nif")));
+        assert_eq!(edited_archive.extract_by_name(Path::new("readme.txt")).unwrap(), b"new readme");
+        assert_eq!(edited_archive.extract_by_name(Path::new("meshes/armor/hat.nif")).unwrap(), b"helmet");
+    }
+
+    #[test]
+    fn xbox_archives_cant_be_edited() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = padded_archive(dir.path());
+        let mut bytes = std::fs::read(&source).unwrap();
+        // the archive flags come after the magic, version and folder record offset
+        bytes[12] |= ArchiveFlags::XBOX_360_ARCHIVE.bits() as u8;
+        std::fs::write(&source, bytes).unwrap();
+        let error = BSAEditor::from_file(source).err().unwrap();
+        assert!(error.to_string().contains("Xbox 360"), "{}", error);
+    }
+
+    #[test]
+    #[cfg(feature = "manifest")]
+    fn overhead_len_is_everything_but_the_file_content() {
+        let files: &[(&str, &[u8])] = &[
+            ("meshes/armor/helmet.nif", b"helmet"),
+            ("meshes/armor/boots.nif", b"boots"),
+            ("sound/fx/clank.wav", b"clank"),
+            ("readme.txt", b"readme"),
+        ];
+        for &(version, archive_flags) in &[
+            (Version::SKYRIMSE, ArchiveFlags::INCLUDE_DIR_NAMES | ArchiveFlags::INCLUDE_FILE_NAMES),
+            (
+                Version::SKYRIM,
+                ArchiveFlags::INCLUDE_DIR_NAMES | ArchiveFlags::INCLUDE_FILE_NAMES | ArchiveFlags::EMBED_FILE_NAMES,
+            ),
+        ] {
+            let mut editor = BSAEditor::new(version).unwrap();
+            editor.set_archive_flags(archive_flags).unwrap();
+            editor.set_compression_policy(CompressionPolicy {
+                default_rule: crate::CompressionRule::Never,
+                ..CompressionPolicy::default()
+            });
+            for &(file_path, contents) in files {
+                editor.add(Path::new(file_path), contents.to_vec()).unwrap();
+            }
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("test.bsa");
+            editor.save(&path).unwrap();
+
+            let content_len: usize = files.iter().map(|(_, contents)| contents.len()).sum();
+            let file_paths = files.iter().map(|&(file_path, _)| (file_path, false));
+            let archive_len = std::fs::metadata(path).unwrap().len();
+            assert_eq!(overhead_len(version, archive_flags, file_paths) + content_len as u64, archive_len);
+        }
+    }
+
+    /// Saves the given files with deduplication turned on or off, returning the report and the parsed archive
+    fn save_duplicates(
+        dir: &Path,
+        archive_flags: ArchiveFlags,
+        deduplicate: bool,
+    ) -> (WriteReport, crate::bsa::BSAArchive, u64) {
+        let mut editor = BSAEditor::new(Version::SKYRIM).unwrap();
+        editor.set_archive_flags(archive_flags).unwrap();
+        editor.set_deduplicate(deduplicate);
+        for &file_path in &["meshes/helmet.nif", "meshes/armor/helmet.nif", "textures/helmet.nif"] {
+            editor.add(Path::new(file_path), b"helmet ".repeat(40)).unwrap();
+        }
+        editor.add(Path::new("meshes/boots.nif"), b"boots ".repeat(40)).unwrap();
+        let path = dir.join(format!("{}_{}.bsa", archive_flags.bits(), deduplicate));
+        let report = editor.save(&path).unwrap();
+        let archive_len = std::fs::metadata(&path).unwrap().len();
+        (report, crate::bsa::from_file(path).unwrap(), archive_len)
+    }
+
+    #[test]
+    fn identical_payloads_share_one_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive_flags = ArchiveFlags::INCLUDE_DIR_NAMES | ArchiveFlags::INCLUDE_FILE_NAMES;
+        let (report, mut archive, archive_len) = save_duplicates(dir.path(), archive_flags, true);
+        let helmet = &archive.file_hashmap[Path::new("meshes/helmet.nif")];
+        let (offset, size) = (helmet.offset, helmet.size);
+        for file_path in &["meshes/armor/helmet.nif", "textures/helmet.nif"] {
+            let copy = &archive.file_hashmap[Path::new(file_path)];
+            assert_eq!((copy.offset, copy.size), (offset, size));
+            assert_eq!(archive.extract_by_name(Path::new(file_path)).unwrap(), b"helmet ".repeat(40));
+        }
+        assert_ne!(archive.file_hashmap[Path::new("meshes/boots.nif")].offset, offset);
+        assert_eq!(
+            report,
+            WriteReport {
+                deduplicated_blocks: 2,
+                bytes_saved:         2 * u64::from(size),
+            }
+        );
+
+        let (report, archive, full_len) = save_duplicates(dir.path(), archive_flags, false);
+        assert_eq!(report, WriteReport::default());
+        assert_eq!(archive.file_hashmap[Path::new("textures/helmet.nif")].size, size);
+        assert_eq!(full_len - archive_len, 2 * u64::from(size));
+    }
+
+    #[test]
+    fn embedded_names_are_never_shared() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive_flags =
+            ArchiveFlags::INCLUDE_DIR_NAMES | ArchiveFlags::INCLUDE_FILE_NAMES | ArchiveFlags::EMBED_FILE_NAMES;
+        let (report, mut archive, _) = save_duplicates(dir.path(), archive_flags, true);
+        assert_eq!(report, WriteReport::default());
+        let mut offsets: Vec<u32> = archive.file_hashmap.values().map(|file| file.offset).collect();
+        offsets.sort();
+        offsets.dedup();
+        assert_eq!(offsets.len(), 4);
+        assert!(archive.verify_contents().unwrap().is_ok());
+    }
+}
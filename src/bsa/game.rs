@@ -0,0 +1,155 @@
+//! Detection of which game a BSA archive belongs to
+//!
+//! Fallout 3, Fallout New Vegas and Skyrim all write version 0x68 archives, so the version alone can't tell them apart.
+//! Instead the master files next to the archive are checked first, followed by the archive's name and the kinds of
+//! files it contains. The master files are looked for once when an archive is opened from a path, and handed to the
+//! parser as the game of the data folder.
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// bsa imports
+use crate::bsa::types::{FileFlags, Version};
+
+/// The game that a BSA archive was made for
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Game {
+    Morrowind,
+    Oblivion,
+    /// Fallout 3, or Fallout New Vegas when the data path doesn't tell them apart
+    Fallout3,
+    FalloutNewVegas,
+    /// Skyrim (Original + Legendary Edition)
+    Skyrim,
+    SkyrimSE,
+}
+
+/// Extensions of files that only Skyrim archives contain (compiled and source scripts, voices, animations)
+const SKYRIM_EXTENSIONS: [&str; 5] = ["pex", "psc", "fuz", "hkx", "seq"];
+/// Extensions of files that only Fallout 3 and New Vegas archives contain (voices, SpeedTree and FaceGen data)
+const FALLOUT_EXTENSIONS: [&str; 4] = ["ogg", "spt", "egm", "egt"];
+
+/// Works out which game an archive of the given version was made for. `data_game` is the game whose master file is
+/// in the same folder as the archive, if one was found there.
+pub(crate) fn detect_game<'a, I>(
+    path: &Path,
+    data_game: Option<Game>,
+    version: Version,
+    file_flags: FileFlags,
+    file_paths: I,
+) -> Game
+where
+    I: IntoIterator<Item = &'a PathBuf>,
+{
+    match version {
+        Version::MORROWIND => return Game::Morrowind,
+        Version::OBLIVION => return Game::Oblivion,
+        Version::SKYRIMSE => return Game::SkyrimSE,
+        Version::SKYRIM => (),
+    }
+
+    // Fallout archives are named "Fallout - <Category>.bsa" in both Fallout 3 and New Vegas, so only the master file
+    // next to them can tell the two apart
+    let archive_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if archive_name.starts_with("fallout - ") {
+        return match data_game {
+            Some(Game::FalloutNewVegas) => Game::FalloutNewVegas,
+            _ => Game::Fallout3,
+        };
+    }
+
+    if let Some(game) = data_game {
+        return game;
+    } else if archive_name.starts_with("skyrim - ") {
+        return Game::Skyrim;
+    }
+
+    // Skyrim's trees are plain meshes, only the older games pack SpeedTree (.spt) files under the trees flag
+    if file_flags.intersects(FileFlags::TREES) {
+        return Game::Fallout3;
+    }
+
+    for file_path in file_paths {
+        let extension = file_path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if SKYRIM_EXTENSIONS.contains(&extension.as_str()) {
+            return Game::Skyrim;
+        } else if FALLOUT_EXTENSIONS.contains(&extension.as_str()) {
+            return Game::Fallout3;
+        }
+    }
+
+    // Nothing pointed at Fallout, and Skyrim is by far the most common source of these archives
+    Game::Skyrim
+}
+
+/// Looks for a game's master file in the directory containing the archive at `path`
+pub(crate) fn game_from_data_dir(path: &Path) -> Option<Game> {
+    fs::read_dir(data_dir(path)?)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .find_map(|entry| game_from_master(&entry.file_name()))
+}
+
+/// Looks for a game's master file in the directory containing the archive at `path` without blocking the async runtime
+#[cfg(feature = "async")]
+pub(crate) async fn game_from_data_dir_async(path: &Path) -> Option<Game> {
+    let mut entries = tokio::fs::read_dir(data_dir(path)?).await.ok()?;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if let Some(game) = game_from_master(&entry.file_name()) {
+            return Some(game);
+        }
+    }
+    None
+}
+
+/// The directory that an archive is stored in
+fn data_dir(path: &Path) -> Option<&Path> {
+    let data_dir = path.parent()?;
+    Some(if data_dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        data_dir
+    })
+}
+
+/// The game that a master file belongs to
+fn game_from_master(file_name: &OsStr) -> Option<Game> {
+    match file_name.to_string_lossy().to_lowercase().as_str() {
+        "skyrim.esm" => Some(Game::Skyrim),
+        "falloutnv.esm" => Some(Game::FalloutNewVegas),
+        "fallout3.esm" => Some(Game::Fallout3),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fallout_archives_need_the_data_folder_to_tell_the_games_apart() {
+        let path = Path::new("Data/Fallout - Sound.bsa");
+        let detect = |data_game| detect_game(path, data_game, Version::SKYRIM, FileFlags::SOUNDS, &[]);
+        assert_eq!(detect(None), Game::Fallout3);
+        assert_eq!(detect(Some(Game::Fallout3)), Game::Fallout3);
+        assert_eq!(detect(Some(Game::FalloutNewVegas)), Game::FalloutNewVegas);
+        // a stray Skyrim master doesn't turn a Fallout archive into a Skyrim one
+        assert_eq!(detect(Some(Game::Skyrim)), Game::Fallout3);
+    }
+
+    #[test]
+    fn data_folder_wins_over_file_contents() {
+        let file_paths = [PathBuf::from("scripts/quest.pex")];
+        let path = Path::new("Data/Mod.bsa");
+        let detect = |data_game| detect_game(path, data_game, Version::SKYRIM, FileFlags::MISC, &file_paths);
+        assert_eq!(detect(None), Game::Skyrim);
+        assert_eq!(detect(Some(Game::FalloutNewVegas)), Game::FalloutNewVegas);
+    }
+}
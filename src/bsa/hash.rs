@@ -0,0 +1,63 @@
+//! Hash functions used by Oblivion-style BSA archives to look up folders and files
+//!
+//! BSA hash documentation credit:    <http://en.uesp.net/wiki/Tes4Mod:Hash_Calculation>
+//!
+//! BSA hash documentation license:   <https://creativecommons.org/licenses/by-sa/2.5/>
+
+/// Multiplier used when hashing the middle of a name and its extension
+const HASH_MULTIPLIER: u32 = 0x1003f;
+
+/// Hashes a folder path (e.g. `meshes\armor\iron`)
+pub fn hash_folder_name(folder_name: &str) -> u64 {
+    let folder_name = normalize(folder_name);
+    hash_bytes(folder_name.as_bytes(), b"")
+}
+
+/// Hashes a file name without its folder (e.g. `cuirass.nif`)
+pub fn hash_file_name(file_name: &str) -> u64 {
+    let file_name = normalize(file_name);
+    match file_name.rfind('.') {
+        Some(ext_start) => {
+            let (stem, extension) = file_name.split_at(ext_start);
+            hash_bytes(stem.as_bytes(), extension.as_bytes())
+        }
+        None => hash_bytes(file_name.as_bytes(), b""),
+    }
+}
+
+/// Bethesda hashes lower case names using '\\' as the path separator
+fn normalize(name: &str) -> String {
+    name.to_ascii_lowercase().replace("/", "\\")
+}
+
+/// Hashes a name which has already been split into its stem and its extension (including the '.')
+fn hash_bytes(stem: &[u8], extension: &[u8]) -> u64 {
+    let len = stem.len();
+
+    // the low 32 bits are built from the first and last two characters along with the length
+    let mut low: u32 = 0;
+    if len > 0 {
+        low = u32::from(stem[len - 1]) | ((len as u32) << 16) | (u32::from(stem[0]) << 24);
+        if len > 2 {
+            low |= u32::from(stem[len - 2]) << 8;
+        }
+    }
+    low |= match extension {
+        b".kf" => 0x80,
+        b".nif" => 0x8000,
+        b".dds" => 0x8080,
+        b".wav" => 0x8000_0000,
+        _ => 0,
+    };
+
+    // the high 32 bits are a rolling hash of the middle of the name plus a rolling hash of the extension
+    let rolling_hash = |bytes: &[u8]| {
+        bytes
+            .iter()
+            .fold(0u32, |hash, &c| hash.wrapping_mul(HASH_MULTIPLIER).wrapping_add(u32::from(c)))
+    };
+    let middle = if len > 3 { rolling_hash(&stem[1..len - 2]) } else { 0 };
+    let high = middle.wrapping_add(rolling_hash(extension));
+
+    (u64::from(high) << 32) | u64::from(low)
+}
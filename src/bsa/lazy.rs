@@ -0,0 +1,139 @@
+//! Lazy lookups of single files in Oblivion-style BSA archives
+//!
+//! Opening an archive the usual way reads every file record and file name up front, which is wasted work when only a
+//! handful of files are needed out of an archive with a hundred thousand of them. A lazy archive only reads the header
+//! and folder records when it's opened. Each lookup hashes the path and binary searches the folder records and then the
+//! file records of that one folder, the same way the game finds its files.
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek};
+use std::iter;
+use std::path::{Path, PathBuf};
+
+use failure::ResultExt;
+
+// top-level imports
+use crate::archive::Extract;
+use crate::reader::{latin1_to_string, TESReader};
+use crate::Result;
+
+// bsa imports
+use crate::bsa::game::{detect_game, game_from_data_dir, Game};
+use crate::bsa::hash::{hash_file_name, hash_folder_name};
+use crate::bsa::oblivion::{find_file, read_folder_index, OBBSAHeader, OBFolderIndex};
+use crate::bsa::types::{BSAFile, BSAHeader};
+
+/// An Oblivion-style BSA archive whose file records are only read when a file is looked up
+pub struct LazyBSA<R = BufReader<File>> {
+    /// Header containing metadata for the entire archive. The game is only guessed from the version and path of the
+    /// archive, because the file names aren't read.
+    pub header: BSAHeader,
+    ob_header:  OBBSAHeader,
+    /// Folder records sorted by the hash of the folder name
+    folders:    Vec<OBFolderIndex>,
+    reader:     R,
+}
+
+/// Given a file path to a BSA file, opens it and reads only what is needed to look up single files in it
+pub fn lazy_from_file(path: PathBuf) -> Result<LazyBSA> {
+    let reader = BufReader::new(File::open(&path)?);
+    open_lazy(reader, &path, game_from_data_dir(&path))
+}
+
+/// Opens the BSA archive behind any seekable reader for lookups of single files, taking ownership of `reader`
+pub fn lazy_from_reader<R: Read + Seek>(reader: R) -> Result<LazyBSA<BufReader<R>>> {
+    open_lazy(BufReader::new(reader), Path::new(""), None)
+}
+
+fn open_lazy<R: BufRead + Seek>(mut reader: R, path: &Path, data_game: Option<Game>) -> Result<LazyBSA<R>> {
+    let (ob_header, folders) = {
+        let mut reader = TESReader::from_reader(&mut reader)?;
+        let mut file_magic = [0; 4];
+        reader
+            .read_exact(&mut file_magic)
+            .context("Unable to read BSA file identifier")?;
+        if &file_magic != b"BSA\0" {
+            return Err(format_err!(
+                "Only Oblivion-style BSA files can be opened lazily, found the file identifier {:?}",
+                latin1_to_string(&file_magic)
+            ));
+        }
+        read_folder_index(&mut reader)?
+    };
+
+    let header = BSAHeader {
+        version: ob_header.version,
+        archive_flags: ob_header.archive_flags,
+        file_flags: ob_header.file_flags,
+        file_count: ob_header.file_count,
+        game: detect_game(path, data_game, ob_header.version, ob_header.file_flags, iter::empty()),
+    };
+    Ok(LazyBSA {
+        header,
+        ob_header,
+        folders,
+        reader,
+    })
+}
+
+impl<R: BufRead + Seek> LazyBSA<R> {
+    /// Given a file path, looks up the file record of that file without reading any other folder's records
+    pub fn find(&mut self, file_path: &Path) -> Result<Option<BSAFile>> {
+        let file_path = file_path.to_string_lossy();
+        let (folder_name, file_name) = match file_path.rfind(['/', '\\']) {
+            Some(separator) => (&file_path[..separator], &file_path[separator + 1..]),
+            None => ("", file_path.as_ref()),
+        };
+        find_file(
+            &mut TESReader::from_reader(&mut self.reader)?,
+            &self.ob_header,
+            &self.folders,
+            hash_folder_name(folder_name),
+            hash_file_name(file_name),
+        )
+    }
+
+    /// Given a file path, extracts the file content from the BSA
+    pub fn extract_by_name(&mut self, file_path: &Path) -> Result<Vec<u8>> {
+        let file_record = self
+            .find(file_path)?
+            .ok_or_else(|| format_err!("File {:#?} not found", file_path))?;
+        file_record.extract(&mut TESReader::from_reader(&mut self.reader)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsa::{BSAEditor, Version};
+
+    #[test]
+    fn find_matches_the_full_parse() {
+        let dir = tempfile::tempdir().unwrap();
+        for &version in &[Version::OBLIVION, Version::SKYRIMSE] {
+            let mut editor = BSAEditor::new(version).unwrap();
+            for i in 0..40 {
+                let file_path = format!("meshes/folder{}/sub{}/file{}.nif", i % 7, i % 3, i);
+                editor.add(Path::new(&file_path), file_path.as_bytes().to_vec()).unwrap();
+            }
+            editor.add(Path::new("readme.txt"), b"readme".to_vec()).unwrap();
+            let path = dir.path().join("test.bsa");
+            editor.save(&path).unwrap();
+
+            let mut archive = crate::bsa::from_file(path.clone()).unwrap();
+            let mut lazy = lazy_from_file(path).unwrap();
+            assert_eq!(lazy.header.file_count, archive.header.file_count);
+            let file_paths: Vec<PathBuf> = archive.file_hashmap.keys().cloned().collect();
+            for file_path in file_paths {
+                let expected = &archive.file_hashmap[&file_path];
+                let found = lazy.find(&file_path).unwrap().unwrap();
+                assert_eq!(
+                    (found.index, found.name_hash, found.folder_hash, found.size, found.offset),
+                    (expected.index, expected.name_hash, expected.folder_hash, expected.size, expected.offset),
+                );
+                assert_eq!(lazy.extract_by_name(&file_path).unwrap(), archive.extract_by_name(&file_path).unwrap());
+            }
+            assert!(lazy.find(Path::new("meshes/folder1/sub1/missing.nif")).unwrap().is_none());
+            assert!(lazy.find(Path::new("missing/file1.nif")).unwrap().is_none());
+        }
+    }
+}
@@ -1,57 +1,320 @@
-use std::io::{Read, Seek, SeekFrom};
-use std::path::PathBuf;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 
+use byteorder::{ByteOrder, LittleEndian};
 use failure::ResultExt;
 
+mod convert;
+mod editor;
+mod game;
+mod hash;
+mod lazy;
 mod morrowind;
 mod oblivion;
 mod types;
 
-use crate::archive::{Archive, Extract};
-use crate::reader::{latin1_to_string, TESFile, TESReader};
+use crate::archive::{Archive, Extract, FileMap};
+use crate::entry::{Entry, EntryChunk};
+use crate::layout::{Region, Structure};
+use crate::lint::LintReport;
+use crate::reader::{latin1_to_string, TESReader};
+use crate::verify::{embedded_name_matches, Problem};
 use crate::{Compression, Result};
 
+// bsa imports
+use crate::bsa::game::game_from_data_dir;
+
+#[cfg(feature = "cache")]
+use crate::cache::open_cached;
+#[cfg(feature = "async")]
+use crate::bsa::game::game_from_data_dir_async;
+#[cfg(feature = "async")]
+use crate::prefetch::parse_async;
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncSeek};
+
 // reexports for documentation
-pub use self::types::{BSAFile, BSAHeader};
+pub use self::convert::{convert, ConvertWarning};
+pub use self::editor::BSAEditor;
+#[cfg(feature = "manifest")]
+pub(crate) use self::editor::overhead_len;
+pub use self::game::Game;
+pub use self::lazy::{lazy_from_file, lazy_from_reader, LazyBSA};
+pub use self::types::{ArchiveFlags, BSAFile, BSAHeader, FileFlags, Version};
 
-pub type BSAArchive = Archive<BSAHeader, BSAFile>;
+pub type BSAArchive<R = BufReader<File>> = Archive<BSAHeader, BSAFile, R>;
 
 /// Given a file path to a BSA file, opens and parses the archive into the generic BSA structure
 pub fn from_file(path: PathBuf) -> Result<BSAArchive> {
-    let mut reader = TESReader::from_file(&path)?;
+    let mut reader = BufReader::new(File::open(&path)?);
+    let data_game = game_from_data_dir(&path);
+    let (header, file_hashmap) = read_index(&mut TESReader::from_reader(&mut reader)?, &path, data_game)?;
+    Ok(BSAArchive {
+        header,
+        file_hashmap,
+        reader,
+    })
+}
+
+/// Parses a BSA archive out of any seekable reader, such as an archive that was downloaded into memory or one that is
+/// stored inside another file. The archive takes ownership of `reader` to read file data from, pass `&mut reader` to
+/// keep using it afterwards.
+pub fn from_reader<R: Read + Seek>(reader: R) -> Result<BSAArchive<BufReader<R>>> {
+    let mut reader = BufReader::new(reader);
+    let (header, file_hashmap) = read_index(&mut TESReader::from_reader(&mut reader)?, Path::new(""), None)?;
+    Ok(BSAArchive {
+        header,
+        file_hashmap,
+        reader,
+    })
+}
+
+/// Given a file path to a BSA file, opens the archive and reads its header and file records from `cache_dir` if they
+/// were cached while the archive had the same size and modification time. Otherwise the archive is parsed and its index
+/// is cached for next time, unless `cache_dir` can't be written to.
+#[cfg(feature = "cache")]
+pub fn from_file_cached(path: PathBuf, cache_dir: &Path) -> Result<BSAArchive> {
+    // the data folder is only looked at when the cache can't be used
+    open_cached(path, cache_dir, |reader, path| read_index(reader, path, game_from_data_dir(path)))
+}
 
+/// Given a file path to a BSA file, opens and parses the archive without blocking the async runtime
+#[cfg(feature = "async")]
+pub async fn from_file_async(path: PathBuf) -> Result<BSAArchive<tokio::fs::File>> {
+    let mut reader = tokio::fs::File::open(&path).await?;
+    // the data folder is looked at once up front, rather than on every retry of the parser
+    let data_game = game_from_data_dir_async(&path).await;
+    let (header, file_hashmap) =
+        parse_async(&mut reader, &[], |prefetched| read_index(prefetched, &path, data_game)).await?;
+    Ok(BSAArchive {
+        header,
+        file_hashmap,
+        reader,
+    })
+}
+
+/// Parses the BSA archive behind an async reader, only reading the parts of it in front of the file data
+#[cfg(feature = "async")]
+pub async fn from_async_reader<R>(mut reader: R) -> Result<BSAArchive<R>>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    let (header, file_hashmap) =
+        parse_async(&mut reader, &[], |prefetched| read_index(prefetched, Path::new(""), None)).await?;
+    Ok(BSAArchive {
+        header,
+        file_hashmap,
+        reader,
+    })
+}
+
+/// Identifies the style of a BSA archive by its file magic and reads its header and file records. `path` is where the
+/// archive is stored on disk, if it is, and `data_game` is the game whose master file was found next to it. Both help
+/// to tell which game the archive was made for.
+fn read_index<B: BufRead + Seek>(
+    reader: &mut TESReader<B>,
+    path: &Path,
+    data_game: Option<Game>,
+) -> Result<(BSAHeader, FileMap<BSAFile>)> {
     let mut file_magic = [0; 4];
     reader
         .read_exact(&mut file_magic)
         .context("Unable to read BSA file identifier")?;
     let magic_str = latin1_to_string(&file_magic);
     match magic_str.as_ref() {
-        "BSA\0" => oblivion::parse_bsa(path, &mut reader),
-        "\x00\x01\x00\x00" => morrowind::parse_bsa(path, &mut reader),
-        _ => unimplemented!("Unknown file id parsed"),
+        "BSA\0" => oblivion::parse_bsa(reader, path, data_game),
+        "\x00\x01\x00\x00" => morrowind::parse_bsa(reader),
+        _ => Err(format_err!("Unknown BSA file identifier {:?}", file_magic)),
     }
 }
 
-impl Extract for BSAFile {
-    /// Given a file, extracts the file content from the BSA
-    fn extract(&self, reader: &mut TESFile) -> Result<Vec<u8>> {
+impl BSAFile {
+    /// Reads the raw data block of this file, splitting off the name embedded at the start of it (if there is one)
+    fn read_block<B: BufRead + Seek>(&self, reader: &mut TESReader<B>) -> Result<(Option<String>, Vec<u8>)> {
         reader.seek(SeekFrom::Start(u64::from(self.offset)))?;
+        reader.ensure_remaining(u64::from(self.size))?;
         let mut file_block = vec![0; self.size as usize];
         reader.read_exact(&mut file_block)?;
 
+        if self.has_name {
+            // the embedded name is a bstring: byte-length prefixed and NOT '\0' terminated
+            let bstring_len = match file_block.first() {
+                Some(&name_len) if usize::from(name_len) < file_block.len() => usize::from(name_len) + 1,
+                _ => return Err(format_err!("Embedded file name doesn't fit in {} bytes", file_block.len())),
+            };
+            let data = file_block.split_off(bstring_len);
+            Ok((Some(latin1_to_string(&file_block[1..])), data))
+        } else {
+            Ok((None, file_block))
+        }
+    }
+}
+
+impl<R: BufRead + Seek> BSAArchive<R> {
+    /// Looks for mistakes in the archive that keep the game from loading files out of it, under the rules of the game
+    /// it was made for. That game is only a guess for version 0x68 archives, which Fallout 3, Fallout New Vegas and
+    /// Skyrim all use, so `lint_as` checks them for a game of the caller's choosing instead.
+    pub fn lint(&mut self) -> Result<LintReport> {
+        let game = self.header.game;
+        self.lint_as(game)
+    }
+
+    /// Looks for mistakes like `lint` does, but under the rules of `game` instead of the game in the header
+    pub fn lint_as(&mut self, game: Game) -> Result<LintReport> {
+        let mut report = LintReport::default();
+        report.check_archive_len(self.reader.seek(SeekFrom::End(0))?, false);
+        // Morrowind archives have no flags
+        if self.header.version != Version::MORROWIND {
+            report.check_file_flags(self.header.file_flags, self.file_hashmap.keys());
+            report.check_embedded_names(game, self.header.archive_flags, self.file_hashmap.keys());
+        }
+
+        // sort the file names so that the report comes out in the same order every time
+        let mut file_names: Vec<&PathBuf> = self.file_hashmap.keys().collect();
+        file_names.sort();
+        for file_name in file_names {
+            let compressed = self.file_hashmap[file_name].compression != Compression::None;
+            report.check_path(file_name);
+            report.check_compression(game, file_name, compressed);
+        }
+        Ok(report)
+    }
+}
+
+impl<R: BufRead + Seek> Structure for BSAArchive<R> {
+    fn structure(&mut self) -> Result<Vec<Region>> {
+        let mut reader = TESReader::from_reader(&mut self.reader)?;
+        match self.header.version {
+            Version::MORROWIND => morrowind::read_structure(&mut reader),
+            _ => oblivion::read_structure(&mut reader),
+        }
+    }
+}
+
+impl Extract for BSAFile {
+    /// Given a file, extracts the file content from the BSA
+    fn extract<B: BufRead + Seek>(&self, reader: &mut TESReader<B>) -> Result<Vec<u8>> {
+        let (_, data) = self.read_block(reader)?;
         if self.compression != Compression::None {
-            if self.has_name {
-                let compressed_slice_offset = (file_block[0] + 1) as usize;
-                self.compression
-                    .decompress_buffer(&file_block[compressed_slice_offset..])
-            } else {
-                self.compression.decompress_buffer(&file_block)
-            }
-        } else if self.has_name {
-            let bstring_len: usize = (file_block[0] + 1) as usize;
-            Ok(file_block[bstring_len..].to_vec())
+            self.compression.decompress_buffer(&data)
         } else {
-            Ok(file_block)
+            Ok(data)
+        }
+    }
+
+    fn entry<B: BufRead + Seek>(&self, reader: &mut TESReader<B>, file_path: &Path) -> Result<Entry> {
+        let mut data_size = u64::from(self.size);
+        if self.has_name || self.compression != Compression::None {
+            reader.seek(SeekFrom::Start(u64::from(self.offset)))?;
+        }
+
+        // the embedded name is a bstring which has to be skipped over to get to the data
+        if self.has_name {
+            let mut name_len = [0; 1];
+            reader.read_exact(&mut name_len)?;
+            reader.seek(SeekFrom::Current(i64::from(name_len[0])))?;
+            data_size = data_size.saturating_sub(u64::from(name_len[0]) + 1);
+        }
+
+        // compressed data is always prefixed with the length of the data once it has been decompressed
+        let (compressed_size, uncompressed_size) = if self.compression != Compression::None {
+            let mut length = [0; 4];
+            reader.read_exact(&mut length)?;
+            (
+                Some(data_size.saturating_sub(4)),
+                u64::from(LittleEndian::read_u32(&length)),
+            )
+        } else {
+            (None, data_size)
+        };
+
+        Ok(Entry {
+            path: file_path.to_path_buf(),
+            index: self.index,
+            name_hash: self.name_hash,
+            dir_hash: self.folder_hash,
+            extension: None,
+            compression: self.compression,
+            compression_toggled: self.compression_toggled,
+            has_name: self.has_name,
+            texture: None,
+            chunks: vec![EntryChunk {
+                offset: u64::from(self.offset),
+                stored_size: u64::from(self.size),
+                compressed_size,
+                uncompressed_size,
+                mipmaps: None,
+            }],
+        })
+    }
+
+    fn data_blocks(&self) -> Vec<(u64, u64)> {
+        vec![(u64::from(self.offset), u64::from(self.size))]
+    }
+
+    fn verify<B: BufRead + Seek>(&self, reader: &mut TESReader<B>, file_path: &Path) -> Vec<Problem> {
+        let (name, data) = match self.read_block(reader) {
+            Ok(block) => block,
+            Err(e) => return vec![Problem::Unreadable(e.to_string())],
+        };
+
+        let mut problems = Vec::new();
+        if let Some(embedded) = name {
+            if !embedded_name_matches(&embedded, file_path) {
+                problems.push(Problem::NameMismatch { embedded });
+            }
+        }
+
+        // compressed data is always prefixed with the length of the data once it has been decompressed
+        if self.compression != Compression::None {
+            match self.compression.decompress_buffer(&data) {
+                Ok(contents) => {
+                    let expected = u64::from(LittleEndian::read_u32(&data[..4]));
+                    let actual = contents.len() as u64;
+                    if expected != actual {
+                        problems.push(Problem::SizeMismatch { expected, actual });
+                    }
+                }
+                Err(e) => problems.push(Problem::Unreadable(e.to_string())),
+            }
+        }
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn archives_can_be_parsed_from_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.bsa");
+        let mut editor = BSAEditor::new(Version::SKYRIMSE).unwrap();
+        editor.add(Path::new("meshes/helmet.nif"), b"helmet ".repeat(20)).unwrap();
+        editor.add(Path::new("readme.txt"), b"readme".to_vec()).unwrap();
+        editor.save(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+
+        let mut from_disk = from_file(path).unwrap();
+        let mut cursor = Cursor::new(bytes.clone());
+        {
+            let mut from_memory = from_reader(&mut cursor).unwrap();
+            assert_eq!(from_memory.header.version, from_disk.header.version);
+            assert_eq!(from_memory.header.archive_flags, from_disk.header.archive_flags);
+            assert_eq!(from_memory.header.file_count, 2);
+            assert_eq!(from_memory.header.game, from_disk.header.game);
+            for file_path in &["meshes/helmet.nif", "readme.txt"] {
+                let file_path = Path::new(file_path);
+                assert_eq!(
+                    from_memory.extract_by_name(file_path).unwrap(),
+                    from_disk.extract_by_name(file_path).unwrap()
+                );
+            }
         }
+        // the cursor was only borrowed, so it can still be used
+        assert_eq!(cursor.into_inner(), bytes);
     }
 }
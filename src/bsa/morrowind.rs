@@ -18,28 +18,32 @@
 //! | raw_data           | (raw data)                       | Raw file data. Uncompressed and unseparated.
 //! --------------------------------------------------------------------------------------------------------------
 //! ```
-use std::io::{Seek, SeekFrom};
+use std::convert::TryFrom;
+use std::io::{BufRead, Seek, SeekFrom};
 use std::path::PathBuf;
 
 use failure::ResultExt;
-use nom::le_u32;
+use nom::{le_u32, le_u64};
 
 // top-level imports
 use crate::archive::FileMap;
-use crate::bsa::BSAArchive;
-use crate::reader::TESFile;
+use crate::layout::{Region, RegionKind};
+use crate::reader::TESReader;
 use crate::{Compression, Result};
 
 // bsa imports
+use crate::bsa::game::Game;
 use crate::bsa::types::{ArchiveFlags, BSAFile, BSAHeader, FileFlags, Version};
 
 /// All Morrowind-style BSA headers are 8 (0x8) bytes after parsing the file magic
 const SERIALIZED_HEADER_LEN: usize = 0x8;
+/// The header is preceded by a four byte file magic
+const MAGIC_LEN: usize = 0x4;
 /// All Morrowind-style file records are 8 (0x8) bytes
 const SERIALIZED_FILE_RECORD_LEN: usize = 0x8;
 
-/// Creates a BSA object
-pub fn parse_bsa(path: PathBuf, reader: &mut TESFile) -> Result<BSAArchive> {
+/// Reads the header and file records of a Morrowind-style BSA
+pub fn parse_bsa<B: BufRead + Seek>(reader: &mut TESReader<B>) -> Result<(BSAHeader, FileMap<BSAFile>)> {
     // Follows the Morrowind BSA file structure (described at the top of the file)
 
     // Read in the header
@@ -48,21 +52,32 @@ pub fn parse_bsa(path: PathBuf, reader: &mut TESFile) -> Result<BSAArchive> {
         .context("Can't parse Morrowind style BSA header")?;
 
     // Read in the file records which contain the file size and file offset
+    let file_records_len = reader.records_len(header.file_count, SERIALIZED_FILE_RECORD_LEN)?;
     let file_records = reader
-        .parse_exact(SERIALIZED_FILE_RECORD_LEN * header.file_count, parse_file_records)
+        .parse_exact(file_records_len, parse_file_records)
         .context("Failed to parse Morrowind file records")?;
 
     // skip over the file name offset block as we don't need it
-    reader.seek(SeekFrom::Current((4 * header.file_count) as i64))?;
+    let name_offsets_len = reader.records_len(header.file_count, 4)?;
+    reader.seek(SeekFrom::Current(name_offsets_len as i64))?;
 
     // get all of the file names by reading and parsing the bstring block
-    let name_block_size = header.hash_offset - (12 * header.file_count); // calculation taken from BSA documentation
+    let name_block_size = header
+        .hash_offset
+        .checked_sub(12 * header.file_count) // calculation taken from BSA documentation
+        .ok_or_else(|| format_err!("Hash offset {} is before the end of the file records", header.hash_offset))?;
     let file_names = reader
         .parse_bstring_block(name_block_size)
         .context("Failed to read file name block")?;
 
+    // the hash block directly follows the name block
+    let hashes_len = reader.records_len(header.file_count, 8)?;
+    let file_hashes = reader
+        .parse_exact(hashes_len, parse_file_hashes)
+        .context("Failed to parse Morrowind file name hashes")?;
+
     // Create a hashmap mapping file names => file metadata records to quickly grab file data from the BSA
-    let file_hashmap = create_file_hashmap(&header, file_records, file_names);
+    let file_hashmap = create_file_hashmap(&header, file_records, file_names, file_hashes)?;
 
     // Convert the header to a BSA header
     let bsa_header = BSAHeader {
@@ -70,39 +85,63 @@ pub fn parse_bsa(path: PathBuf, reader: &mut TESFile) -> Result<BSAArchive> {
         archive_flags: ArchiveFlags::empty(),
         file_flags:    FileFlags::empty(),
         file_count:    header.file_count,
+        game:          Game::Morrowind,
     };
 
-    Ok(BSAArchive {
-        path,
-        header: bsa_header,
-        file_hashmap,
-    })
+    Ok((bsa_header, file_hashmap))
+}
+
+/// Reads the header of a Morrowind-style BSA again to find where its records, file names and hashes are stored
+pub(crate) fn read_structure<B: BufRead + Seek>(reader: &mut TESReader<B>) -> Result<Vec<Region>> {
+    reader.seek(SeekFrom::Start(MAGIC_LEN as u64))?;
+    let header = reader
+        .parse_exact(SERIALIZED_HEADER_LEN, mw_bsa_header_parser)
+        .context("Can't parse Morrowind style BSA header")?;
+
+    // the hash offset is counted from the end of the header, like every other offset in the archive
+    let header_len = (MAGIC_LEN + SERIALIZED_HEADER_LEN) as u64;
+    let file_count = header.file_count as u64;
+    let names_start = header_len + 12 * file_count;
+    let hashes_start = header_len + header.hash_offset as u64;
+    Ok(vec![
+        Region::new(RegionKind::Header, 0, header_len),
+        Region::new(RegionKind::Records, header_len, 12 * file_count),
+        Region::new(RegionKind::Names, names_start, hashes_start.saturating_sub(names_start)),
+        Region::new(RegionKind::Hashes, hashes_start, 8 * file_count),
+    ])
 }
 
 fn create_file_hashmap(
     header: &MWBSAHeader,
     file_records: Vec<MWFileRecord>,
     file_names: Vec<String>,
-) -> FileMap<BSAFile> {
-    // Zips the vector of file names up with the previous iterator
-    let file_record_iter = file_records.into_iter().zip(file_names);
+    file_hashes: Vec<u64>,
+) -> Result<FileMap<BSAFile>> {
+    // Zips the vector of file names and hashes up with the previous iterator
+    let file_record_iter = file_records.into_iter().zip(file_names).zip(file_hashes);
 
-    // calculate the file data offset
-    let file_data_offset: u32 = (header.hash_offset + (8 * header.file_count) + SERIALIZED_HEADER_LEN) as u32;
+    // calculate the file data offset (the file count was already checked against the file size, so this can't overflow)
+    let file_data_offset =
+        header.hash_offset as u64 + (8 * header.file_count + MAGIC_LEN + SERIALIZED_HEADER_LEN) as u64;
 
     // Iterates over each file and inserts it into a new hashmap
     let mut file_hashmap: FileMap<BSAFile> = Default::default();
-    for (file_record, file_name) in file_record_iter {
+    for (index, ((file_record, file_name), name_hash)) in file_record_iter.enumerate() {
+        let offset = file_data_offset + u64::from(file_record.offset);
         let bsa_file = BSAFile {
-            has_name:    false,
+            index,
+            name_hash,
+            folder_hash: None,
+            has_name: false,
             compression: Compression::None,
-            size:        file_record.size,
-            offset:      file_data_offset + file_record.offset,
+            compression_toggled: false,
+            size: file_record.size,
+            offset: u32::try_from(offset).map_err(|_| format_err!("File offset {} is out of range", offset))?,
         };
         file_hashmap.insert(PathBuf::from(file_name), bsa_file);
     }
 
-    file_hashmap
+    Ok(file_hashmap)
 }
 
 /// Metadata for the whole archive.
@@ -169,3 +208,39 @@ named!(parse_file_records<&[u8], Vec<MWFileRecord>>,
         )
     ))
 );
+
+named!(parse_file_hashes<&[u8], Vec<u64>>,
+    many0!(complete!(
+        add_return_error!(ErrorKind::Custom(202), le_u64)
+    ))
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A Morrowind BSA holding "a.txt" ("hello") and "b.txt" ("world")
+    const MW_BSA: &[u8] = &[
+        0x00, 0x01, 0x00, 0x00, 0x24, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, //
+        0x00, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+        0x06, 0x00, 0x00, 0x00, b'a', b'.', b't', b'x', b't', 0x00, b'b', b'.', b't', b'x', b't', 0x00, //
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+        b'h', b'e', b'l', b'l', b'o', b'w', b'o', b'r', b'l', b'd',
+    ];
+
+    #[test]
+    fn file_data_follows_the_hashes() {
+        let header_end = MAGIC_LEN + SERIALIZED_HEADER_LEN;
+        let (_, header) = mw_bsa_header_parser(&MW_BSA[MAGIC_LEN..header_end]).unwrap();
+        let records_end = header_end + SERIALIZED_FILE_RECORD_LEN * header.file_count;
+        let (_, file_records) = parse_file_records(&MW_BSA[header_end..records_end]).unwrap();
+        let file_names = vec![String::from("a.txt"), String::from("b.txt")];
+
+        let file_hashmap = create_file_hashmap(&header, file_records, file_names, vec![0, 0]).unwrap();
+        for (file_name, contents) in &[("a.txt", b"hello"), ("b.txt", b"world")] {
+            let bsa_file = &file_hashmap[&PathBuf::from(file_name)];
+            let start = bsa_file.offset as usize;
+            assert_eq!(&MW_BSA[start..start + bsa_file.size as usize], &contents[..]);
+        }
+    }
+}
@@ -17,20 +17,22 @@
 //! | files             | RawFileBlock[file_count]          | Raw file data that is optionally compressed
 //! --------------------------------------------------------------------------------------------------------------
 //! ```
+use std::io::{BufRead, Seek, SeekFrom};
 use std::iter;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 
 use failure::ResultExt;
 use nom::{le_u32, le_u64};
 
 // top-level imports
 use crate::archive::FileMap;
-use crate::reader::TESFile;
+use crate::layout::{Region, RegionKind};
+use crate::reader::TESReader;
 use crate::{Compression, Result};
 
 // bsa imports
+use crate::bsa::game::{detect_game, Game};
 use crate::bsa::types::*;
-use crate::bsa::BSAArchive;
 
 /// All Oblivion-style BSA headers are the same size in serialized form, 32 (0x20), after parsing the file magic
 const SERIALIZED_HEADER_LEN: usize = 0x20;
@@ -41,8 +43,43 @@ const SERIALIZED_OB_FOLDER_RECORD_LEN: usize = 0x10;
 /// The Skyrim Special Edition-style BSA folder records has a unique size in serialized form, 24 (0x18)
 const SERIALIZED_SSE_FOLDER_RECORD_LEN: usize = 0x18;
 
-/// Creates a BSA object
-pub fn parse_bsa(path: PathBuf, mut reader: &mut TESFile) -> Result<BSAArchive> {
+/// Everything stored in front of the file data of an Oblivion-style BSA, in the order it appears in the archive
+pub(crate) struct OBLayout {
+    pub header:     OBBSAHeader,
+    pub folders:    Vec<OBFolderRecord>,
+    pub file_names: Vec<String>,
+}
+
+/// Reads the header and file records of an Oblivion-style BSA
+pub fn parse_bsa<B: BufRead + Seek>(
+    reader: &mut TESReader<B>,
+    path: &Path,
+    data_game: Option<Game>,
+) -> Result<(BSAHeader, FileMap<BSAFile>)> {
+    let OBLayout {
+        header,
+        folders,
+        file_names,
+    } = read_layout(reader)?;
+
+    // Create a hashmap mapping file names => file metadata records to quickly grab file data from the BSA
+    let file_hashmap = create_file_hashmap(&header, folders, file_names);
+
+    // Convert the header to a BSA header
+    let game = detect_game(path, data_game, header.version, header.file_flags, file_hashmap.keys());
+    let bsa_header = BSAHeader {
+        version: header.version,
+        archive_flags: header.archive_flags,
+        file_flags: header.file_flags,
+        file_count: header.file_count,
+        game,
+    };
+
+    Ok((bsa_header, file_hashmap))
+}
+
+/// Reads the header, folder records, file records and file names of an Oblivion-style BSA
+pub(crate) fn read_layout<B: BufRead + Seek>(mut reader: &mut TESReader<B>) -> Result<OBLayout> {
     // Follows the Oblivion BSA file structure (described at the top of the file)
 
     // Read in the header
@@ -60,46 +97,128 @@ pub fn parse_bsa(path: PathBuf, mut reader: &mut TESFile) -> Result<BSAArchive>
             .parse_bstring_block(header.total_file_name_length as usize)
             .context("Failed to read file name block")?
     } else {
-        unimplemented!("Parsing BSA files without the INCLUDE_FILE_NAMES archive flag is currently unsupported");
+        return Err(format_err!(
+            "Parsing BSA files without the INCLUDE_FILE_NAMES archive flag is currently unsupported"
+        ));
     };
 
-    // Create a hashmap mapping file names => file metadata records to quickly grab file data from the BSA
-    let file_hashmap = create_file_hashmap(&header, folders, file_names);
+    Ok(OBLayout {
+        header,
+        folders,
+        file_names,
+    })
+}
 
-    // Convert the header to a BSA header
-    let bsa_header = BSAHeader {
-        version:       header.version,
-        archive_flags: header.archive_flags,
-        file_flags:    header.file_flags,
-        file_count:    header.file_count,
+/// Reads the layout of an Oblivion-style BSA again to find where its header, records and file names are stored
+pub(crate) fn read_structure<B: BufRead + Seek>(reader: &mut TESReader<B>) -> Result<Vec<Region>> {
+    reader.seek(SeekFrom::Start(4))?;
+    let OBLayout { header, .. } = read_layout(reader)?;
+    let names_end = reader.stream_position()?;
+    let names_start = names_end - u64::from(header.total_file_name_length);
+
+    let header_len = (4 + SERIALIZED_HEADER_LEN) as u64;
+    let folder_record_len = if header.version == Version::SKYRIMSE {
+        SERIALIZED_SSE_FOLDER_RECORD_LEN
+    } else {
+        SERIALIZED_OB_FOLDER_RECORD_LEN
     };
+    let folder_records_end = header_len + (header.folder_count * folder_record_len) as u64;
+    Ok(vec![
+        Region::new(RegionKind::Header, 0, header_len),
+        Region::new(RegionKind::Records, header_len, folder_records_end - header_len),
+        Region::new(RegionKind::Records, folder_records_end, names_start - folder_records_end),
+        Region::new(RegionKind::Names, names_start, names_end - names_start),
+    ])
+}
 
-    Ok(BSAArchive {
-        path,
-        header: bsa_header,
-        file_hashmap,
-    })
+/// Reads the header and folder records of an Oblivion-style BSA, leaving the file records and names to be read later
+pub(crate) fn read_folder_index<B: BufRead + Seek>(
+    reader: &mut TESReader<B>,
+) -> Result<(OBBSAHeader, Vec<OBFolderIndex>)> {
+    let header = reader
+        .parse_exact(SERIALIZED_HEADER_LEN, ob_bsa_header_parser)
+        .context("Can't parse Oblivion style BSA header")?;
+    let folder_metadata =
+        read_folder_metadata(reader, header.folder_count, &header).context("Failed to read folder records")?;
+
+    let mut folders = Vec::with_capacity(folder_metadata.len());
+    let mut first_index: usize = 0;
+    for metadata in folder_metadata {
+        // the offsets count the file name block as if it was stored in front of the folder names and file records
+        let offset = u64::from(metadata.offset)
+            .checked_sub(u64::from(header.total_file_name_length))
+            .ok_or_else(|| format_err!("Folder offset {} points in front of the archive", metadata.offset))?;
+        folders.push(OBFolderIndex {
+            name_hash: metadata.name_hash,
+            count: metadata.count,
+            first_index,
+            offset,
+        });
+        first_index = first_index.saturating_add(metadata.count);
+    }
+
+    Ok((header, folders))
+}
+
+/// Looks up a single file by the hashes of its folder and name, only reading the file records of the folder it is in.
+/// Folders and files are sorted by hash, which is how the game looks them up as well.
+pub(crate) fn find_file<B: BufRead + Seek>(
+    reader: &mut TESReader<B>,
+    header: &OBBSAHeader,
+    folders: &[OBFolderIndex],
+    folder_hash: u64,
+    name_hash: u64,
+) -> Result<Option<BSAFile>> {
+    let folder = match folders.binary_search_by_key(&folder_hash, |folder| folder.name_hash) {
+        Ok(position) => &folders[position],
+        Err(_) => return Ok(None),
+    };
+    if folder.count == 0 {
+        return Ok(None);
+    }
+
+    // the folder name in front of the file records is read the same way as in read_file_record_blocks
+    reader.seek(SeekFrom::Start(folder.offset))?;
+    reader.parse_bzstring().context("Failed parsing a folder name")?;
+    let file_records_len = reader.records_len(folder.count, SERIALIZED_FILE_RECORD_LEN)?;
+    let file_records = reader
+        .parse_exact(file_records_len, ob_file_records_parser)
+        .context("Failed parsing file records")?;
+
+    let file = file_records
+        .binary_search_by_key(&name_hash, |file_record| file_record.name_hash)
+        .ok()
+        .map(|position| bsa_file(header, folder.first_index + position, folder_hash, &file_records[position]));
+    Ok(file)
 }
 
-fn read_file_record_blocks(
-    reader: &mut TESFile,
+/// Reads the folder metadata block which tells us how many files are in each folder
+fn read_folder_metadata<B: BufRead + Seek>(
+    reader: &mut TESReader<B>,
     num_folders: usize,
     header: &OBBSAHeader,
-) -> Result<Vec<OBFolderRecord>> {
-    // Read the folder metadata block which tells us how many files are in each folder
+) -> Result<Vec<OBFolderMetadata>> {
     // Skyrim Special Edition has a different header from the other formats
     let folder_metadata = if header.version == Version::SKYRIMSE {
+        let metadata_len = reader.records_len(num_folders, SERIALIZED_SSE_FOLDER_RECORD_LEN)?;
         reader
-            .parse_exact(
-                SERIALIZED_SSE_FOLDER_RECORD_LEN * num_folders,
-                sse_folder_metadata_parser,
-            )
+            .parse_exact(metadata_len, sse_folder_metadata_parser)
             .context("Failed parsing the SSE-style folder metadata block")?
     } else {
+        let metadata_len = reader.records_len(num_folders, SERIALIZED_OB_FOLDER_RECORD_LEN)?;
         reader
-            .parse_exact(SERIALIZED_OB_FOLDER_RECORD_LEN * num_folders, ob_folder_metadata_parser)
+            .parse_exact(metadata_len, ob_folder_metadata_parser)
             .context("Failed parsing the Oblivion-style folder metadata block")?
     };
+    Ok(folder_metadata)
+}
+
+fn read_file_record_blocks<B: BufRead + Seek>(
+    reader: &mut TESReader<B>,
+    num_folders: usize,
+    header: &OBBSAHeader,
+) -> Result<Vec<OBFolderRecord>> {
+    let folder_metadata = read_folder_metadata(reader, num_folders, header)?;
 
     let mut file_record_blocks: Vec<OBFolderRecord> = Vec::with_capacity(num_folders);
     for metadata in folder_metadata {
@@ -107,11 +226,18 @@ fn read_file_record_blocks(
         let name = reader.parse_bzstring().context("Failed parsing a folder name")?;
 
         // Read out the file records
+        let file_records_len = reader.records_len(metadata.count, SERIALIZED_FILE_RECORD_LEN)?;
         let file_records = reader
-            .parse_exact(SERIALIZED_FILE_RECORD_LEN * metadata.count, ob_file_records_parser)
+            .parse_exact(file_records_len, ob_file_records_parser)
             .context("Failed parsing file records")?;
 
-        file_record_blocks.push(OBFolderRecord { name, file_records });
+        file_record_blocks.push(OBFolderRecord {
+            name_hash: metadata.name_hash,
+            unknown: metadata.unknown,
+            unknown2: metadata.unknown2,
+            name,
+            file_records,
+        });
     }
 
     Ok(file_record_blocks)
@@ -125,47 +251,15 @@ fn create_file_hashmap(
     // Converts the vector of BSAFolderRecords into an iterator of (folder_name, file_record) to be more easily consumed
     let folder_file_iter = folders
         .into_iter()
-        .flat_map(|folder| iter::repeat(folder.name).zip(folder.file_records.into_iter()));
+        .flat_map(|folder| iter::repeat((folder.name, folder.name_hash)).zip(folder.file_records.into_iter()));
 
     // Zips the vector of file names up with the previous iterator
     let folder_file_name_iter = file_names.into_iter().zip(folder_file_iter);
 
     // Iterates over each file and inserts it into a new hashmap
     let mut file_hashmap: FileMap<BSAFile> = Default::default();
-    for (file_name, (folder_name, file_record)) in folder_file_name_iter {
-        // Documentation on the Unofficial Elder Scrolls Pages (UESP) wiki seems to be wrong.
-        // Even if the EMBED_FILE_NAMES flag is set on the archive, the file names are not found
-        // in the individual file blocks. Therefore we always say false for Oblivion BSAs
-        let has_name =
-            header.archive_flags.contains(ArchiveFlags::EMBED_FILE_NAMES) && header.version != Version::OBLIVION;
-
-        let mut is_compressed = header.archive_flags.contains(ArchiveFlags::COMPRESSED_ARCHIVE);
-        is_compressed = if !file_record.uses_default_compression {
-            !is_compressed
-        } else {
-            is_compressed
-        };
-
-        // For Skyrim Special Edition, Bethesda replaced Zlib compression with LZ4 compression.
-        // Personal opinion: this is probably because LZ4 is multithread capable and thus lended
-        // itself well to the newer console generation that has multiple cores to help load assets
-        // and lower in-game load screens which have plagued console performance in the past
-        let compression = if is_compressed {
-            match header.version {
-                Version::OBLIVION | Version::SKYRIM => Compression::Zlib,
-                Version::SKYRIMSE => Compression::Lz4,
-                _ => Compression::None,
-            }
-        } else {
-            Compression::None
-        };
-
-        let bsa_file = BSAFile {
-            has_name,
-            compression,
-            size: file_record.size,
-            offset: file_record.offset,
-        };
+    for (index, (file_name, ((folder_name, folder_hash), file_record))) in folder_file_name_iter.enumerate() {
+        let bsa_file = bsa_file(header, index, folder_hash, &file_record);
 
         // a name string in a OBFolderRecord stores paths with '\\' which is valid in Windows but not
         // in Linux. this String::replace call will transform the slashes into something usable
@@ -178,6 +272,50 @@ fn create_file_hashmap(
     file_hashmap
 }
 
+/// Builds the metadata of a single file out of its file record, the hash of the folder containing it and its position
+/// among all of the file records in the archive
+fn bsa_file(header: &OBBSAHeader, index: usize, folder_hash: u64, file_record: &OBFileRecord) -> BSAFile {
+    // Documentation on the Unofficial Elder Scrolls Pages (UESP) wiki seems to be wrong.
+    // Even if the EMBED_FILE_NAMES flag is set on the archive, the file names are not found
+    // in the individual file blocks. Therefore we always say false for Oblivion BSAs
+    let has_name = header.archive_flags.contains(ArchiveFlags::EMBED_FILE_NAMES) && header.version != Version::OBLIVION;
+
+    let mut is_compressed = header.archive_flags.contains(ArchiveFlags::COMPRESSED_ARCHIVE);
+    is_compressed = if !file_record.uses_default_compression {
+        !is_compressed
+    } else {
+        is_compressed
+    };
+
+    // For Skyrim Special Edition, Bethesda replaced Zlib compression with LZ4 compression.
+    // Personal opinion: this is probably because LZ4 is multithread capable and thus lended
+    // itself well to the newer console generation that has multiple cores to help load assets
+    // and lower in-game load screens which have plagued console performance in the past
+    // Xbox 360 archives can use XMem instead, which is Microsoft's own codec
+    let compression = if is_compressed && header.archive_flags.contains(ArchiveFlags::XMEM_CODEC) {
+        Compression::XMem
+    } else if is_compressed {
+        match header.version {
+            Version::OBLIVION | Version::SKYRIM => Compression::Zlib,
+            Version::SKYRIMSE => Compression::Lz4,
+            _ => Compression::None,
+        }
+    } else {
+        Compression::None
+    };
+
+    BSAFile {
+        index,
+        name_hash: file_record.name_hash,
+        folder_hash: Some(folder_hash),
+        has_name,
+        compression,
+        compression_toggled: !file_record.uses_default_compression,
+        size: file_record.size,
+        offset: file_record.offset,
+    }
+}
+
 /// Metadata for the whole archive.
 ///
 /// Used by Oblivion, Fallout 3, Fallout New Vegas, Skyrim, and Skyrim Special Edition
@@ -196,41 +334,47 @@ fn create_file_hashmap(
 /// file_flags                u32
 /// ----------------------------------
 /// ```
-struct OBBSAHeader {
+pub(crate) struct OBBSAHeader {
     /// A single byte indicating the version of the file-format
-    version: Version,
+    pub version: Version,
+    /// Offset to the folder records (always 0x24)
+    pub offset: u32,
     /// A list of archive flags indicating how to interpret following records and data
-    archive_flags: ArchiveFlags,
+    pub archive_flags: ArchiveFlags,
     /// Count of all folders in the archive
-    folder_count: usize,
+    pub folder_count: usize,
     /// Count of all files in the archive
-    file_count: usize,
+    pub file_count: usize,
     /// Total length of all file names, including \0's.
-    total_file_name_length: u32,
+    pub total_file_name_length: u32,
     /// List of flags specifying the type of files containing within the archive
-    file_flags: FileFlags,
+    pub file_flags: FileFlags,
+    /// Two bytes of padding following the file flags
+    pub unknown_bytes: [u8; 2],
 }
 
 named!(ob_bsa_header_parser<&[u8], OBBSAHeader>,
     add_return_error!(ErrorKind::Custom(100),
         do_parse!(
             version:            version_parser >>
-            _offset:                  take!(4) >>
+            offset:                     le_u32 >>
             archive_flags: parse_archive_flags >>
             folder_count:               le_u32 >>
             file_count:                 le_u32 >>
             _total_folder_name_length:take!(4) >>
             total_file_name_length:     le_u32 >>
             file_flags:       parse_file_flags >>
-            _unknown_bytes:           take!(2) >>
+            unknown_bytes:            take!(2) >>
             (
                 OBBSAHeader {
                     version,
+                    offset,
                     archive_flags,
                     folder_count: folder_count as usize,
                     file_count: file_count as usize,
                     total_file_name_length,
                     file_flags,
+                    unknown_bytes: [unknown_bytes[0], unknown_bytes[1]],
                 }
             )
         )
@@ -259,20 +403,32 @@ named!(ob_bsa_header_parser<&[u8], OBBSAHeader>,
 /// ------------------
 /// ```
 struct OBFolderMetadata {
+    /// Hash of the folder name
+    name_hash: u64,
     /// Number of files contained in this folder
     count: usize,
+    /// Offset to the folder's name and file records, plus the total length of the file name block
+    offset: u32,
+    /// Padding following the file count (Skyrim Special Edition only)
+    unknown: [u8; 4],
+    /// Upper half of the 64-bit offset (Skyrim Special Edition only)
+    unknown2: [u8; 4],
 }
 
 named!(ob_folder_metadata_parser<&[u8], Vec<OBFolderMetadata>>,
     many0!(complete!(
         add_return_error!(ErrorKind::Custom(101),
             do_parse!(
-                _name_hash:     le_u64 >>
+                name_hash:      le_u64 >>
                 file_count:     le_u32 >>
-                _offset:        le_u32 >>
+                offset:         le_u32 >>
                 (
                     OBFolderMetadata {
-                        count: file_count as usize
+                        name_hash,
+                        count: file_count as usize,
+                        offset,
+                        unknown: [0; 4],
+                        unknown2: [0; 4],
                     }
                 )
             )
@@ -284,14 +440,18 @@ named!(sse_folder_metadata_parser<&[u8], Vec<OBFolderMetadata>>,
     many0!(complete!(
         add_return_error!(ErrorKind::Custom(102),
             do_parse!(
-                _name_hash:     le_u64 >>
+                name_hash:      le_u64 >>
                 file_count:     le_u32 >>
-                _unknown:     take!(4) >>
-                _offset:        le_u32 >>
-                _unknown2:    take!(4) >>
+                unknown:      take!(4) >>
+                offset:         le_u32 >>
+                unknown2:     take!(4) >>
                 (
                     OBFolderMetadata {
-                        count: file_count as usize
+                        name_hash,
+                        count: file_count as usize,
+                        offset,
+                        unknown: [unknown[0], unknown[1], unknown[2], unknown[3]],
+                        unknown2: [unknown2[0], unknown2[1], unknown2[2], unknown2[3]],
                     }
                 )
             )
@@ -318,13 +478,31 @@ named!(sse_folder_metadata_parser<&[u8], Vec<OBFolderMetadata>>,
 ///
 /// [`ArchiveFlags`]: struct.ArchiveFlags.html
 /// [`INCLUDE_DIR_NAMES`]: struct.ArchiveFlags.html#associatedconstant.INCLUDE_DIR_NAMES
-struct OBFolderRecord {
+pub(crate) struct OBFolderRecord {
+    /// Hash of the folder name
+    pub name_hash: u64,
+    /// Padding following the file count (Skyrim Special Edition only)
+    pub unknown: [u8; 4],
+    /// Upper half of the 64-bit offset (Skyrim Special Edition only)
+    pub unknown2: [u8; 4],
     /// Name of the folder
-    name: String,
+    pub name: String,
     /// A variable number of file records determined by the count field in [`BSAFileRecord`]
     ///
     /// [`BSAFileRecord`]: struct.BSAFileRecord.html
-    file_records: Vec<OBFileRecord>,
+    pub file_records: Vec<OBFileRecord>,
+}
+
+/// A folder record that is kept in memory so that the records of the files in the folder can be read on demand
+pub(crate) struct OBFolderIndex {
+    /// Hash of the folder name
+    pub name_hash: u64,
+    /// Number of files contained in this folder
+    pub count: usize,
+    /// Position of the folder's first file record among all of the file records in the archive
+    pub first_index: usize,
+    /// Offset from file byte zero to the folder's name and file records
+    pub offset: u64,
 }
 
 /// Metadata for a single file
@@ -337,29 +515,35 @@ struct OBFolderRecord {
 /// | offset      | u32   |
 /// -----------------------
 /// ```
-struct OBFileRecord {
+pub(crate) struct OBFileRecord {
+    /// Hash of the file name
+    pub name_hash: u64,
     /// Decides whether or not the file is compressed
-    uses_default_compression: bool,
+    pub uses_default_compression: bool,
     /// Size of the file data
-    size: u32,
+    pub size: u32,
+    /// The size field exactly as it was stored, including the compression bit
+    pub raw_size: u32,
     /// Offset from file byte zero to the raw file data
-    offset: u32,
+    pub offset: u32,
 }
 
 named!(ob_file_records_parser<&[u8], Vec<OBFileRecord>>,
     many1!(complete!(
         add_return_error!(ErrorKind::Custom(103),
             do_parse!(
-                _name_hash:   take!(8) >>
+                name_hash:      le_u64 >>
                 size:           le_u32 >>
                 offset:         le_u32 >>
                 (
                     OBFileRecord {
+                        name_hash,
                         // If the (1<<30) bit of the size field is set to 1:
                         //   * and [`ArchiveFlags`]::[`COMPRESSED_ARCHIVE`] is set, this file is not compressed
                         //   * and [`ArchiveFlags`]::[`COMPRESSED_ARCHIVE`] is not set, this file is compressed
                         uses_default_compression: !(((size & 0x4000_0000) >> 30) == 1),
                         size:                         size & 0x3fff_ffff,
+                        raw_size: size,
                         offset,
                     }
                 )
@@ -367,3 +551,50 @@ named!(ob_file_records_parser<&[u8], Vec<OBFileRecord>>,
         )
     ))
 );
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::Path;
+
+    use crate::bsa::{BSAEditor, Version};
+
+    /// Saves a small archive and returns its bytes along with the offset of the first file's data
+    fn archive_bytes() -> (Vec<u8>, usize) {
+        let mut editor = BSAEditor::new(Version::SKYRIMSE).unwrap();
+        editor.add(Path::new("meshes/helmet.nif"), b"helmet".to_vec()).unwrap();
+        editor.add(Path::new("textures/helmet.dds"), b"texture".to_vec()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.bsa");
+        editor.save(&path).unwrap();
+        let archive = crate::bsa::from_file(path.clone()).unwrap();
+        let data_start = archive.file_hashmap.values().map(|file| file.offset).min().unwrap();
+        (std::fs::read(path).unwrap(), data_start as usize)
+    }
+
+    #[test]
+    fn huge_counts_are_errors() {
+        let (bytes, _) = archive_bytes();
+        // the folder count and total file name length in the header, then the file count of the first folder record,
+        // which follows the folder hash
+        for field in &[16, 28, 36 + 8] {
+            let mut bytes = bytes.clone();
+            bytes[*field..*field + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+            assert!(crate::bsa::from_reader(Cursor::new(bytes)).is_err(), "field at {}", field);
+        }
+    }
+
+    #[test]
+    fn truncated_archives_are_errors() {
+        let (bytes, data_start) = archive_bytes();
+        for len in 0..bytes.len() {
+            match crate::bsa::from_reader(Cursor::new(bytes[..len].to_vec())) {
+                Ok(mut archive) => {
+                    assert!(len >= data_start, "truncated to {} bytes", len);
+                    assert!(!archive.verify_contents().unwrap().is_ok());
+                }
+                Err(_) => assert!(len < data_start, "truncated to {} bytes", len),
+            }
+        }
+    }
+}
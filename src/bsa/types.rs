@@ -1,9 +1,64 @@
+use std::path::Path;
+
 use nom::{le_u16, le_u32};
 
+#[cfg(feature = "serde")]
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Implements the serde traits for a set of flags. Human-readable formats get a list of the names of the flags that are
+/// set, so that they can be read and diffed, while binary formats get the bits.
+#[cfg(feature = "serde")]
+macro_rules! serde_flags {
+    ($flags:ident, $bits:ty, [$($flag:ident),*]) => {
+        impl $flags {
+            /// Every flag along with its name
+            const NAMED: &'static [(&'static str, $flags)] = &[$((stringify!($flag), $flags::$flag)),*];
+        }
+
+        impl Serialize for $flags {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                if serializer.is_human_readable() {
+                    serializer.collect_seq(
+                        $flags::NAMED
+                            .iter()
+                            .filter(|(_, flag)| self.contains(*flag))
+                            .map(|(name, _)| name),
+                    )
+                } else {
+                    self.bits().serialize(serializer)
+                }
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $flags {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                if deserializer.is_human_readable() {
+                    let mut flags = $flags::empty();
+                    for name in Vec::<String>::deserialize(deserializer)? {
+                        let flag = $flags::NAMED
+                            .iter()
+                            .find(|(flag_name, _)| flag_name.eq_ignore_ascii_case(&name))
+                            .map(|&(_, flag)| flag)
+                            .ok_or_else(|| de::Error::custom(format!("Unknown {} {:?}", stringify!($flags), name)))?;
+                        flags.insert(flag);
+                    }
+                    Ok(flags)
+                } else {
+                    let bits = <$bits>::deserialize(deserializer)?;
+                    $flags::from_bits(bits)
+                        .ok_or_else(|| de::Error::custom(format!("Unknown {} {:#x}", stringify!($flags), bits)))
+                }
+            }
+        }
+    };
+}
+
+use crate::bsa::game::Game;
 use crate::Compression;
 
 /// Metadata for the whole archive
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct BSAHeader {
     /// A single byte indicating the version of the file-format
     pub version: Version,
@@ -13,14 +68,25 @@ pub struct BSAHeader {
     pub file_flags: FileFlags,
     /// Count of all files in the archive
     pub file_count: usize,
+    /// Game the archive was made for, which is only a best guess for version 0x68 archives
+    pub game: Game,
 }
 
 /// Metadata for a single file
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct BSAFile {
+    /// Position of this file's record among all of the file records in the archive
+    pub index: usize,
+    /// Hash of the file name
+    pub name_hash: u64,
+    /// Hash of the name of the folder containing this file (Morrowind BSAs don't have folders)
+    pub folder_hash: Option<u64>,
     pub has_name: bool,
     /// Decides whether or not the file is compressed
     pub compression: Compression,
+    /// Whether the compression bit in the size field was set, inverting the archive's default compression
+    pub compression_toggled: bool,
     /// Size of the file data
     pub size: u32,
     /// Offset from file byte zero to the raw file data
@@ -28,7 +94,8 @@ pub struct BSAFile {
 }
 
 /// Flag used to indicate what version of the BSA spec this file conforms to
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Version {
     /// Morrowind BSAs don't map to a version, so 0x0 was chosen at random
     MORROWIND,
@@ -79,6 +146,21 @@ named!(
     add_return_error!(ErrorKind::Custom(1), map_opt!(le_u32, ArchiveFlags::from_bits))
 );
 
+#[cfg(feature = "serde")]
+serde_flags!(ArchiveFlags, u32, [
+    INCLUDE_DIR_NAMES,
+    INCLUDE_FILE_NAMES,
+    COMPRESSED_ARCHIVE,
+    RETAIN_DIR_NAMES,
+    RETAIN_FILE_NAMES,
+    RETAIN_FILE_NAME_OFFSETS,
+    XBOX_360_ARCHIVE,
+    RETAIN_STARTUP_STRINGS,
+    EMBED_FILE_NAMES,
+    XMEM_CODEC,
+    UNKNOWN_OBLIVION_FLAG
+]);
+
 bitflags! {
     /// Flags used to indicate the category of files contained in the archive
     pub struct FileFlags: u16 {
@@ -103,7 +185,132 @@ bitflags! {
     }
 }
 
+impl FileFlags {
+    /// Works out the flag that a single file needs from its extension, following the extensions listed on each flag.
+    /// Files with any other extension are categorized by the top-level folder they're in, and anything that still
+    /// doesn't fit falls under `MISC`. The text extensions of `SHADERS` (.txt/.html/.bat) are common in every folder,
+    /// so they're left to the folder as well.
+    pub fn for_file(file_path: &Path) -> FileFlags {
+        let file_path = file_path.to_string_lossy().to_lowercase();
+        let components: Vec<&str> = file_path.split(['/', '\\']).filter(|name| !name.is_empty()).collect();
+        let (file_name, folders) = match components.split_last() {
+            Some((file_name, folders)) => (*file_name, folders),
+            None => return FileFlags::empty(),
+        };
+        let extension = file_name.rsplit_once('.').map_or("", |(_, extension)| extension);
+
+        match extension {
+            "nif" => FileFlags::MESHES,
+            "dds" => FileFlags::TEXTURES,
+            // Flash files are menus, except for the ones that hold the fonts of the interface
+            "swf" if file_name.starts_with("fonts") || folders.contains(&"fonts") => FileFlags::FONTS,
+            "xml" | "swf" => FileFlags::MENUS,
+            "xwm" | "wav" => FileFlags::SOUNDS,
+            "mp3" | "fuz" => FileFlags::VOICES,
+            "fxp" | "scc" => FileFlags::SHADERS,
+            "spt" | "btt" | "btr" => FileFlags::TREES,
+            "tex" | "fnt" => FileFlags::FONTS,
+            "gid" | "pex" => FileFlags::MISC,
+            _ => match folders {
+                ["meshes", ..] => FileFlags::MESHES,
+                ["textures", ..] => FileFlags::TEXTURES,
+                ["interface", ..] | ["menus", ..] => FileFlags::MENUS,
+                ["sound", "voice", ..] => FileFlags::VOICES,
+                ["sound", ..] | ["music", ..] => FileFlags::SOUNDS,
+                ["shaders", ..] => FileFlags::SHADERS,
+                ["trees", ..] => FileFlags::TREES,
+                ["fonts", ..] => FileFlags::FONTS,
+                _ => FileFlags::MISC,
+            },
+        }
+    }
+
+    /// Works out the flags that an archive holding the given files needs
+    pub fn from_paths<P, I>(file_paths: I) -> FileFlags
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = P>,
+    {
+        file_paths
+            .into_iter()
+            .fold(FileFlags::empty(), |flags, file_path| flags | FileFlags::for_file(file_path.as_ref()))
+    }
+}
+
 named!(
     pub parse_file_flags<FileFlags>,
     add_return_error!(ErrorKind::Custom(2), map_opt!(le_u16, FileFlags::from_bits))
 );
+
+#[cfg(feature = "serde")]
+serde_flags!(FileFlags, u16, [MESHES, TEXTURES, MENUS, SOUNDS, VOICES, SHADERS, TREES, FONTS, MISC]);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn for_file_uses_the_extension_first() {
+        assert_eq!(FileFlags::for_file(Path::new("meshes/armor/helmet.nif")), FileFlags::MESHES);
+        assert_eq!(FileFlags::for_file(Path::new("Textures\\Armor\\Helmet.DDS")), FileFlags::TEXTURES);
+        assert_eq!(FileFlags::for_file(Path::new("sound/voice/mod.esp/line.fuz")), FileFlags::VOICES);
+        assert_eq!(FileFlags::for_file(Path::new("scripts/quest.pex")), FileFlags::MISC);
+        assert_eq!(FileFlags::for_file(Path::new("interface/fonts_en.swf")), FileFlags::FONTS);
+        assert_eq!(FileFlags::for_file(Path::new("interface/map.swf")), FileFlags::MENUS);
+        assert_eq!(FileFlags::for_file(Path::new("")), FileFlags::empty());
+    }
+
+    #[test]
+    fn for_file_falls_back_to_the_folder() {
+        assert_eq!(FileFlags::for_file(Path::new("meshes/readme.txt")), FileFlags::MESHES);
+        assert_eq!(FileFlags::for_file(Path::new("interface/credits.html")), FileFlags::MENUS);
+        assert_eq!(FileFlags::for_file(Path::new("shaders/readme.txt")), FileFlags::SHADERS);
+        assert_eq!(FileFlags::for_file(Path::new("sound/voice/mod.esp/line.lip")), FileFlags::VOICES);
+        assert_eq!(FileFlags::for_file(Path::new("readme.txt")), FileFlags::MISC);
+        assert_eq!(FileFlags::for_file(Path::new("tools/build.bat")), FileFlags::MISC);
+    }
+
+    #[test]
+    fn from_paths_combines_the_flags_of_every_file() {
+        let file_paths = ["meshes/a.nif", "textures/a.dds", "docs/readme.txt", "meshes/b.nif"];
+        assert_eq!(FileFlags::from_paths(file_paths), FileFlags::MESHES | FileFlags::TEXTURES | FileFlags::MISC);
+        assert_eq!(FileFlags::from_paths(Vec::<&Path>::new()), FileFlags::empty());
+    }
+
+    #[test]
+    #[cfg(feature = "manifest")]
+    fn flags_serialize_as_lists_of_names() {
+        let archive_flags = ArchiveFlags::INCLUDE_DIR_NAMES | ArchiveFlags::COMPRESSED_ARCHIVE;
+        let json = serde_json::to_string(&archive_flags).unwrap();
+        assert_eq!(json, r#"["INCLUDE_DIR_NAMES","COMPRESSED_ARCHIVE"]"#);
+        assert_eq!(serde_json::from_str::<ArchiveFlags>(&json).unwrap(), archive_flags);
+
+        let file_flags = FileFlags::MESHES | FileFlags::TEXTURES | FileFlags::MISC;
+        let json = serde_json::to_string(&file_flags).unwrap();
+        assert_eq!(json, r#"["MESHES","TEXTURES","MISC"]"#);
+        assert_eq!(serde_json::from_str::<FileFlags>(&json).unwrap(), file_flags);
+        assert_eq!(serde_json::to_string(&FileFlags::empty()).unwrap(), "[]");
+
+        // names are matched regardless of case
+        let file_flags = serde_json::from_str::<FileFlags>(r#"["meshes","Sounds"]"#).unwrap();
+        assert_eq!(file_flags, FileFlags::MESHES | FileFlags::SOUNDS);
+    }
+
+    #[test]
+    #[cfg(feature = "manifest")]
+    fn unknown_flag_names_are_errors() {
+        let error = serde_json::from_str::<FileFlags>(r#"["MESHES","MODELS"]"#).unwrap_err();
+        assert!(error.to_string().contains("Unknown FileFlags \"MODELS\""), "{}", error);
+        assert!(serde_json::from_str::<ArchiveFlags>(r#"["TEXTURES"]"#).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "cache")]
+    fn binary_formats_store_the_bits() {
+        let archive_flags = ArchiveFlags::INCLUDE_FILE_NAMES | ArchiveFlags::EMBED_FILE_NAMES;
+        let bytes = bincode::serialize(&archive_flags).unwrap();
+        assert_eq!(bytes, archive_flags.bits().to_le_bytes());
+        assert_eq!(bincode::deserialize::<ArchiveFlags>(&bytes).unwrap(), archive_flags);
+        assert!(bincode::deserialize::<FileFlags>(&u16::MAX.to_le_bytes()).is_err());
+    }
+}
@@ -0,0 +1,162 @@
+//! On-disk cache of the parsed indexes of archives
+//!
+//! Reading the header and file records of an archive back from a compact binary copy is much faster than parsing them
+//! out of the archive again. Each archive gets its own file in the cache directory, which is only used while the
+//! archive still has the path, size and modification time it had when it was cached. Anything else parses the archive
+//! again and replaces the cached copy.
+use std::fs;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tempfile::NamedTempFile;
+use twox_hash::XxHash;
+
+// top-level imports
+use crate::archive::{Archive, FileMap};
+use crate::reader::TESReader;
+use crate::Result;
+
+/// Bumped whenever the layout of the cached types changes, so that older caches are parsed again rather than misread
+const CACHE_VERSION: u32 = 1;
+
+/// Identifies the exact archive that an index was read from
+#[derive(Serialize, Deserialize, PartialEq)]
+struct CacheKey {
+    /// Canonical path to the archive
+    path:     PathBuf,
+    /// Size of the archive in bytes
+    len:      u64,
+    /// Modification time of the archive, in seconds and nanoseconds since the Unix epoch
+    modified: (u64, u32),
+}
+
+/// Everything that is written to a cache file, borrowed from the archive it was read from
+#[derive(Serialize)]
+struct CachedIndexRef<'a, H, F> {
+    version:      u32,
+    key:          &'a CacheKey,
+    header:       &'a H,
+    file_hashmap: &'a FileMap<F>,
+}
+
+/// Everything that is read back from a cache file, in the same order as `CachedIndexRef`
+#[derive(Deserialize)]
+struct CachedIndex<H, F> {
+    version:      u32,
+    key:          CacheKey,
+    header:       H,
+    file_hashmap: FileMap<F>,
+}
+
+/// Opens the archive at `path`, reading its index from `cache_dir` if it's cached there and otherwise reading it with
+/// `parse` and caching it for next time if the cache can be written
+pub(crate) fn open_cached<H, F, P>(path: PathBuf, cache_dir: &Path, parse: P) -> Result<Archive<H, F>>
+where
+    H: Serialize + DeserializeOwned,
+    F: Serialize + DeserializeOwned,
+    P: FnOnce(&mut TESReader<&mut BufReader<File>>, &Path) -> Result<(H, FileMap<F>)>,
+{
+    let path = fs::canonicalize(path)?;
+    let mut reader = BufReader::new(File::open(&path)?);
+
+    // the key comes from the opened file so that it describes the same data that is parsed
+    let metadata = reader.get_ref().metadata()?;
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+    let key = CacheKey {
+        path,
+        len: metadata.len(),
+        modified: (modified.as_secs(), modified.subsec_nanos()),
+    };
+    let cache_path = cache_dir.join(cache_file_name(&key.path));
+
+    // a cache that can't be read is no worse than having no cache at all
+    if let Some(cached) = read_cache::<H, F>(&cache_path) {
+        if cached.version == CACHE_VERSION && cached.key == key {
+            return Ok(Archive {
+                header: cached.header,
+                file_hashmap: cached.file_hashmap,
+                reader,
+            });
+        }
+    }
+
+    let (header, file_hashmap) = parse(&mut TESReader::from_reader(&mut reader)?, &key.path)?;
+    let cached = CachedIndexRef {
+        version:      CACHE_VERSION,
+        key:          &key,
+        header:       &header,
+        file_hashmap: &file_hashmap,
+    };
+    // the archive was parsed either way, so a cache that can't be written (e.g. in a read-only directory) only means
+    // that it's parsed again next time
+    let _ = write_cache(cache_dir, &cache_path, &cached);
+
+    Ok(Archive {
+        header,
+        file_hashmap,
+        reader,
+    })
+}
+
+/// Encoding used for cache files, which stores integers in as few bytes as possible
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+/// Names the cache file of an archive after a hash of its path
+fn cache_file_name(path: &Path) -> String {
+    let mut hasher = XxHash::default();
+    hasher.write(path.to_string_lossy().as_bytes());
+    format!("{:016x}.idx", hasher.finish())
+}
+
+fn read_cache<H, F>(cache_path: &Path) -> Option<CachedIndex<H, F>>
+where
+    H: DeserializeOwned,
+    F: DeserializeOwned,
+{
+    let contents = fs::read(cache_path).ok()?;
+    bincode_options().deserialize(&contents).ok()
+}
+
+/// Writes a cache file through a temporary file, so that other processes never see a partially written cache
+fn write_cache<H, F>(cache_dir: &Path, cache_path: &Path, cached: &CachedIndexRef<H, F>) -> Result<()>
+where
+    H: Serialize,
+    F: Serialize,
+{
+    fs::create_dir_all(cache_dir)?;
+    let mut temp_file = NamedTempFile::new_in(cache_dir)?;
+    {
+        let mut writer = BufWriter::new(temp_file.as_file_mut());
+        bincode_options().serialize_into(&mut writer, cached)?;
+        writer.flush()?;
+    }
+    temp_file.persist(cache_path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unwritable_cache_still_opens_the_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive_path = dir.path().join("test.bsa");
+        fs::write(&archive_path, b"BSA\0").unwrap();
+        // a file where the cache directory should be can't be written into
+        let cache_dir = dir.path().join("cache");
+        fs::write(&cache_dir, b"").unwrap();
+
+        let archive = open_cached(archive_path, &cache_dir, |_, _| Ok((7_u32, FileMap::<u32>::default()))).unwrap();
+        assert_eq!(archive.header, 7);
+        assert!(archive.file_hashmap.is_empty());
+    }
+}
@@ -0,0 +1,57 @@
+use std::path::PathBuf;
+
+use crate::ba2::{dds_header, BA2TextureHeader};
+use crate::{Compression, Result};
+
+/// Metadata for a single file, in the same shape for every archive format
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Entry {
+    /// Path of the file inside the archive
+    pub path: PathBuf,
+    /// Position of the file's record among all of the file records in the archive
+    pub index: usize,
+    /// Hash of the file name (a CRC32 of the file name without its extension for BA2 archives)
+    pub name_hash: u64,
+    /// Hash of the folder containing the file (Morrowind BSAs don't have folders)
+    pub dir_hash: Option<u64>,
+    /// File extension padded with '\0' to four characters (only recorded by BA2 archives)
+    pub extension: Option<[u8; 4]>,
+    /// Compression used by the file's data
+    pub compression: Compression,
+    /// Whether the file's compression is the inverse of the archive's default (only used by BSA archives)
+    pub compression_toggled: bool,
+    /// Whether the file's data starts with its full path
+    pub has_name: bool,
+    /// Texture metadata for files in a DX10 BA2 archive
+    pub texture: Option<BA2TextureHeader>,
+    /// Every block of data belonging to the file
+    pub chunks: Vec<EntryChunk>,
+}
+
+impl Entry {
+    /// Size of the file once it's extracted, including the DDS header that gets written in front of textures
+    pub fn extracted_len(&self) -> Result<u64> {
+        let header_len = match self.texture {
+            Some(ref texture) => dds_header(texture)?.len() as u64,
+            None => 0,
+        };
+        Ok(header_len + self.chunks.iter().map(|chunk| chunk.uncompressed_size).sum::<u64>())
+    }
+}
+
+/// Metadata for a single block of file data
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct EntryChunk {
+    /// Offset from file byte zero to the block
+    pub offset: u64,
+    /// Size of the block as stored in the archive, including any embedded name and length prefix
+    pub stored_size: u64,
+    /// Size of the data while compressed (`None` if the data isn't compressed)
+    pub compressed_size: Option<u64>,
+    /// Size of the data once decompressed
+    pub uncompressed_size: u64,
+    /// Range of mipmaps stored in the block (only used by DX10 BA2 archives)
+    pub mipmaps: Option<(u16, u16)>,
+}
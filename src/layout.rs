@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hasher;
+use std::io::{BufRead, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use twox_hash::XxHash;
+
+use crate::reader::TESReader;
+use crate::Result;
+
+/// What the bytes of a region of an archive are used for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegionKind {
+    /// Metadata for the whole archive, including the file magic
+    Header,
+    /// Folder and file records, including the name offsets of Morrowind archives
+    Records,
+    /// File and folder names
+    Names,
+    /// The hash table of Morrowind archives
+    Hashes,
+    /// Raw data of one or more files
+    Data,
+    /// Bytes that nothing in the archive refers to
+    Slack,
+}
+
+/// A contiguous range of bytes in an archive
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+    /// What the bytes are used for
+    pub kind:   RegionKind,
+    /// Offset from file byte zero to the start of the region
+    pub offset: u64,
+    /// Size of the region
+    pub size:   u64,
+    /// Every file whose data is stored in this region (only filled in for data regions)
+    pub files:  Vec<PathBuf>,
+}
+
+impl Region {
+    pub(crate) fn new(kind: RegionKind, offset: u64, size: u64) -> Region {
+        Region {
+            kind,
+            offset,
+            size,
+            files: Vec::new(),
+        }
+    }
+
+    /// Offset of the first byte after the region
+    pub fn end(&self) -> u64 {
+        self.offset.saturating_add(self.size)
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#010x}..{:#010x} {:?} ({} bytes)",
+            self.offset,
+            self.end(),
+            self.kind,
+            self.size
+        )?;
+        match self.files.as_slice() {
+            [] => Ok(()),
+            [file_path] => write!(f, " {:#?}", file_path),
+            files => write!(f, " shared by {} files, e.g. {:#?}", files.len(), files[0]),
+        }
+    }
+}
+
+/// A map of how every byte of an archive is used
+#[derive(Debug, Default)]
+pub struct LayoutReport {
+    /// Size of the whole archive
+    pub archive_len: u64,
+    /// Every region of the archive, including slack space, ordered by offset
+    pub regions:     Vec<Region>,
+    /// Pairs of regions that share some, but not all, of their bytes. The first region reaches further back.
+    pub overlaps:    Vec<(Region, Region)>,
+    /// Groups of data regions that are stored separately but have the exact same bytes
+    pub duplicates:  Vec<Vec<Region>>,
+}
+
+impl LayoutReport {
+    /// Builds the map out of the archive's header, records and names (`structure`) and the (offset, size, file path) of
+    /// every data block. Blocks with the exact same offset and size are payloads shared between several file records.
+    pub(crate) fn build<B: BufRead + Seek>(
+        reader: &mut TESReader<B>,
+        structure: Vec<Region>,
+        blocks: Vec<(u64, u64, &Path)>,
+    ) -> Result<Self> {
+        let archive_len = reader.seek(SeekFrom::End(0))?;
+
+        let mut data_regions: Vec<Region> = Vec::new();
+        let mut shared: HashMap<(u64, u64), usize> = HashMap::new();
+        for (offset, size, file_path) in blocks {
+            if size == 0 {
+                continue;
+            }
+            let index = *shared.entry((offset, size)).or_insert_with(|| {
+                data_regions.push(Region::new(RegionKind::Data, offset, size));
+                data_regions.len() - 1
+            });
+            data_regions[index].files.push(file_path.to_path_buf());
+        }
+        for region in &mut data_regions {
+            region.files.sort();
+        }
+
+        let duplicates = find_duplicates(reader, archive_len, &data_regions)?;
+
+        let mut regions: Vec<Region> = structure.into_iter().filter(|region| region.size != 0).collect();
+        regions.append(&mut data_regions);
+        regions.sort_by_key(|region| (region.offset, region.size));
+
+        // sweep over the regions, keeping track of the one that reaches furthest into the archive so far
+        let mut report = LayoutReport {
+            archive_len,
+            duplicates,
+            ..Default::default()
+        };
+        let mut furthest: Option<&Region> = None;
+        let mut slack = Vec::new();
+        for region in &regions {
+            let covered_until = furthest.map_or(0, Region::end);
+            if region.offset > covered_until {
+                slack.push(Region::new(RegionKind::Slack, covered_until, region.offset - covered_until));
+            } else if let Some(prev) = furthest.filter(|prev| region.offset < prev.end()) {
+                report.overlaps.push((prev.clone(), region.clone()));
+            }
+            if region.end() > covered_until {
+                furthest = Some(region);
+            }
+        }
+        let covered_until = furthest.map_or(0, Region::end);
+        if covered_until < archive_len {
+            slack.push(Region::new(RegionKind::Slack, covered_until, archive_len - covered_until));
+        }
+
+        regions.append(&mut slack);
+        regions.sort_by_key(|region| (region.offset, region.size));
+        report.regions = regions;
+        Ok(report)
+    }
+
+    /// Total size of all regions of the given kind
+    pub fn total_len(&self, kind: RegionKind) -> u64 {
+        self.regions
+            .iter()
+            .filter(|region| region.kind == kind)
+            .map(|region| region.size)
+            .sum()
+    }
+
+    /// Bytes saved by file records that already share their payloads
+    pub fn shared_len(&self) -> u64 {
+        self.regions
+            .iter()
+            .filter(|region| region.files.len() > 1)
+            .map(|region| region.size * (region.files.len() as u64 - 1))
+            .sum()
+    }
+
+    /// Bytes taken up by extra copies of payloads that are stored more than once
+    pub fn duplicated_len(&self) -> u64 {
+        self.duplicates
+            .iter()
+            .map(|group| group[0].size * (group.len() as u64 - 1))
+            .sum()
+    }
+
+    /// Estimate of how much smaller the archive would get by rebuilding it without slack space and with every
+    /// duplicated payload stored only once
+    pub fn rebuild_savings(&self) -> u64 {
+        self.total_len(RegionKind::Slack) + self.duplicated_len()
+    }
+}
+
+/// Groups data regions within the archive by the hash of their raw bytes, keeping only groups with more than one region
+fn find_duplicates<B: BufRead + Seek>(
+    reader: &mut TESReader<B>,
+    archive_len: u64,
+    data_regions: &[Region],
+) -> Result<Vec<Vec<Region>>> {
+    let mut by_content: HashMap<(u64, u64), Vec<&Region>> = HashMap::new();
+    for region in data_regions.iter().filter(|region| region.end() <= archive_len) {
+        reader.seek(SeekFrom::Start(region.offset))?;
+        let mut hasher = XxHash::with_seed(0);
+        let mut buffer = [0; 0x1_0000];
+        let mut remaining = region.size;
+        while remaining > 0 {
+            let chunk = &mut buffer[..remaining.min(0x1_0000) as usize];
+            reader.read_exact(chunk)?;
+            hasher.write(chunk);
+            remaining -= chunk.len() as u64;
+        }
+        by_content
+            .entry((region.size, hasher.finish()))
+            .or_default()
+            .push(region);
+    }
+
+    let mut duplicates: Vec<Vec<Region>> = by_content
+        .into_iter()
+        .filter(|(_, group)| group.len() > 1)
+        .map(|(_, mut group)| {
+            group.sort_by_key(|region| region.offset);
+            group.into_iter().cloned().collect()
+        })
+        .collect();
+    duplicates.sort_by_key(|group: &Vec<Region>| group[0].offset);
+    Ok(duplicates)
+}
+
+/// Lists the regions of an archive that aren't file data: its header, records and names
+pub trait Structure {
+    fn structure(&mut self) -> Result<Vec<Region>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    use byteorder::{ByteOrder, LittleEndian};
+
+    use crate::ba2::{BA2Editor, BA2Type, BA2Version};
+    use crate::bsa::{BSAEditor, Version};
+
+    fn region(kind: RegionKind, offset: u64, size: u64, files: &[&str]) -> Region {
+        Region {
+            kind,
+            offset,
+            size,
+            files: files.iter().map(PathBuf::from).collect(),
+        }
+    }
+
+    #[test]
+    fn build_finds_slack_overlaps_and_duplicates() {
+        let mut bytes = vec![0; 40];
+        bytes[16..20].copy_from_slice(b"same");
+        bytes[24..28].copy_from_slice(b"same");
+        bytes[28..34].copy_from_slice(b"other!");
+        let mut reader = TESReader::from_reader(Cursor::new(bytes)).unwrap();
+        let structure = vec![
+            Region::new(RegionKind::Header, 0, 8),
+            Region::new(RegionKind::Records, 8, 8),
+            Region::new(RegionKind::Names, 20, 0),
+        ];
+        let blocks = vec![
+            (16, 4, Path::new("b")),
+            (16, 4, Path::new("a")),
+            (24, 4, Path::new("c")),
+            (28, 6, Path::new("d")),
+            (30, 2, Path::new("e")),
+            (32, 4, Path::new("f")),
+        ];
+        let report = LayoutReport::build(&mut reader, structure, blocks).unwrap();
+
+        assert_eq!(report.archive_len, 40);
+        assert_eq!(
+            report.regions,
+            vec![
+                region(RegionKind::Header, 0, 8, &[]),
+                region(RegionKind::Records, 8, 8, &[]),
+                region(RegionKind::Data, 16, 4, &["a", "b"]),
+                region(RegionKind::Slack, 20, 4, &[]),
+                region(RegionKind::Data, 24, 4, &["c"]),
+                region(RegionKind::Data, 28, 6, &["d"]),
+                region(RegionKind::Data, 30, 2, &["e"]),
+                region(RegionKind::Data, 32, 4, &["f"]),
+                region(RegionKind::Slack, 36, 4, &[]),
+            ]
+        );
+        // "e" lies entirely within "d", which "f" then runs past
+        assert_eq!(
+            report.overlaps,
+            vec![
+                (region(RegionKind::Data, 28, 6, &["d"]), region(RegionKind::Data, 30, 2, &["e"])),
+                (region(RegionKind::Data, 28, 6, &["d"]), region(RegionKind::Data, 32, 4, &["f"])),
+            ]
+        );
+        assert_eq!(
+            report.duplicates,
+            vec![vec![region(RegionKind::Data, 16, 4, &["a", "b"]), region(RegionKind::Data, 24, 4, &["c"])]]
+        );
+        assert_eq!(report.total_len(RegionKind::Slack), 8);
+        assert_eq!(report.shared_len(), 4);
+        assert_eq!(report.duplicated_len(), 4);
+        assert_eq!(report.rebuild_savings(), 12);
+    }
+
+    /// Checks that the regions of `report` cover the whole archive without gaps and returns their kinds in order
+    fn kinds(report: &LayoutReport) -> Vec<RegionKind> {
+        let mut covered_until = 0;
+        for region in &report.regions {
+            assert_eq!(region.offset, covered_until, "{}", region);
+            covered_until = region.end();
+        }
+        assert_eq!(covered_until, report.archive_len);
+        report.regions.iter().map(|region| region.kind).collect()
+    }
+
+    #[test]
+    fn bsa_structure_covers_the_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut editor = BSAEditor::new(Version::SKYRIMSE).unwrap();
+        editor.set_deduplicate(false);
+        editor.add(Path::new("meshes/helmet.nif"), b"same".to_vec()).unwrap();
+        editor.add(Path::new("meshes/boots.nif"), b"same".to_vec()).unwrap();
+        let path = dir.path().join("test.bsa");
+        editor.save(&path).unwrap();
+        // junk after the last file that nothing refers to
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.extend_from_slice(&[0; 10]);
+        std::fs::write(&path, bytes).unwrap();
+
+        let report = crate::bsa::from_file(path).unwrap().layout().unwrap();
+        use RegionKind::*;
+        assert_eq!(kinds(&report), vec![Header, Records, Records, Names, Data, Data, Slack]);
+        assert_eq!(report.regions[0].size, 36);
+        assert_eq!(report.regions[1].size, 24);
+        assert_eq!(report.regions.last().unwrap().size, 10);
+        assert!(report.overlaps.is_empty());
+        assert_eq!(report.duplicates, vec![report.regions[4..6].to_vec()]);
+        assert_eq!(report.duplicates[0][0].files, vec![PathBuf::from("meshes/boots.nif")]);
+    }
+
+    #[test]
+    fn ba2_structure_covers_the_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut editor = BA2Editor::new(BA2Version::Fallout4, BA2Type::General);
+        editor.set_deduplicate(false);
+        editor.add(Path::new("meshes/helmet.nif"), b"same".to_vec()).unwrap();
+        editor.add(Path::new("meshes/boots.nif"), b"same".to_vec()).unwrap();
+        let path = dir.path().join("test.ba2");
+        editor.save(&path).unwrap();
+        // a gap in front of the name table, whose offset follows the magic, version, type and file count
+        let mut bytes = std::fs::read(&path).unwrap();
+        let name_table_offset = LittleEndian::read_u64(&bytes[16..24]);
+        bytes.splice(name_table_offset as usize..name_table_offset as usize, vec![0; 10]);
+        bytes[16..24].copy_from_slice(&(name_table_offset + 10).to_le_bytes());
+        std::fs::write(&path, bytes).unwrap();
+
+        let report = crate::ba2::from_file(path).unwrap().layout().unwrap();
+        use RegionKind::*;
+        assert_eq!(kinds(&report), vec![Header, Records, Data, Data, Slack, Names]);
+        assert_eq!(report.regions[0].size, 24);
+        assert_eq!(report.regions[1].size, 2 * 36);
+        assert_eq!(report.regions[4], region(Slack, name_table_offset, 10, &[]));
+        assert!(report.overlaps.is_empty());
+        assert_eq!(report.duplicates, vec![report.regions[2..4].to_vec()]);
+        assert_eq!(report.rebuild_savings(), 14);
+    }
+}
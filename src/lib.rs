@@ -5,6 +5,7 @@
 //!
 //! BSA parsing support is currently available for the following games:
 //!
+//!   * Fallout 3
 //!   * Fallout New Vegas
 //!   * Morrowind
 //!   * Oblivion
@@ -24,6 +25,7 @@ extern crate nom;
 extern crate failure;
 
 extern crate byteorder;
+extern crate crc32fast;
 extern crate flate2;
 extern crate lz4;
 extern crate twox_hash;
@@ -31,15 +33,48 @@ extern crate twox_hash;
 #[cfg(windows)]
 extern crate winreg;
 
+#[cfg(feature = "async")]
+extern crate tokio;
+
+#[cfg(any(feature = "package", feature = "cache"))]
+extern crate tempfile;
+#[cfg(feature = "package")]
+extern crate zip;
+
+#[cfg(feature = "sevenz")]
+extern crate sevenz_rust;
+
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+
+#[cfg(feature = "cache")]
+extern crate bincode;
+
+#[cfg(feature = "manifest")]
+extern crate glob;
+#[cfg(feature = "manifest")]
+extern crate serde_json;
+#[cfg(feature = "manifest")]
+extern crate toml;
+
+#[cfg(all(feature = "mount", target_os = "linux"))]
+extern crate fuser;
+#[cfg(all(feature = "mount", target_os = "linux"))]
+extern crate libc;
+
+use std::cmp;
+use std::fmt;
 use std::fmt::Debug;
 use std::fs;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::Path;
 
-use byteorder::{ByteOrder, LittleEndian};
-use failure::{err_msg, Error, ResultExt};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use failure::{err_msg, Error, Fail, ResultExt};
 use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
 use nom::{Err, IResult};
 
 // the AutodetectGames enum is unable to be documented because of the arg_enum! macro
@@ -49,16 +84,44 @@ pub mod autodetect;
 mod archive;
 pub mod ba2;
 pub mod bsa;
+#[cfg(feature = "cache")]
+mod cache;
+mod entry;
+mod layout;
+mod lint;
+#[cfg(feature = "manifest")]
+pub mod manifest;
+#[cfg(all(feature = "mount", target_os = "linux"))]
+mod mount;
+#[cfg(feature = "package")]
+pub mod package;
+mod policy;
+#[cfg(feature = "async")]
+mod prefetch;
 mod reader;
+mod verify;
+mod writer;
 
 // Re-exports
-pub use crate::archive::ExtensionSet;
+pub use crate::archive::{ExtensionSet, FileMap};
+pub use crate::entry::{Entry, EntryChunk};
+pub use crate::layout::{LayoutReport, Region, RegionKind};
+pub use crate::lint::{Lint, LintReport, Severity};
+#[cfg(all(feature = "mount", target_os = "linux"))]
+pub use crate::mount::mount;
+pub use crate::policy::{CompressionPolicy, CompressionRule};
+pub use crate::verify::{Problem, VerifyReport};
+pub use crate::writer::WriteReport;
 
 /// Result alias for wrapping the `failure::Error` type
 pub type Result<T> = ::std::result::Result<T, Error>;
 
 type ParserFn<O> = fn(input: &[u8]) -> IResult<&[u8], O>;
 
+/// Upper bound on how much memory is reserved up front based on a size read from an archive. Sizes in archives can't be
+/// trusted, so anything larger than this has to grow as the data actually arrives.
+const MAX_PREALLOCATION: usize = 0x100_0000;
+
 #[allow(clippy::needless_pass_by_value)]
 fn convert_nom_err<P: Debug>(e: Err<P>) -> Error {
     err_msg(format!("Failed to parse: {}", e))
@@ -77,33 +140,201 @@ fn dump_to_file(output_dir: &Path, file_name: &Path, file_data: &[u8]) -> Result
     Ok(())
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Compression {
     None,
+    /// zlib stream
     Zlib,
+    /// LZ4 frame, with its own header and checksums
     Lz4,
+    /// Raw LZ4 block, without any framing
+    Lz4Block,
+    /// Xbox 360 XMem, which can't be decompressed
+    XMem,
+}
+
+/// Error returned when file data is compressed with a codec that can't be decompressed
+#[derive(Debug)]
+pub struct UnsupportedCodec {
+    /// Codec the data is compressed with
+    pub codec: Compression,
+}
+
+impl fmt::Display for UnsupportedCodec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The {:?} codec is unsupported", self.codec)
+    }
 }
 
+impl Fail for UnsupportedCodec {}
+
 impl Compression {
-    fn decompress_buffer(&self, buffer: &[u8]) -> Result<Vec<u8>> {
+    /// Compresses data into a bare stream of this codec, the way BA2 archives store their chunks. `level` is 0-9 for
+    /// zlib and 0-16 for LZ4, `None` picks the codec's default level.
+    pub fn compress(&self, data: &[u8], level: Option<u32>) -> Result<Vec<u8>> {
+        let compressed = match self {
+            Compression::Zlib => {
+                let level = level.map_or_else(flate2::Compression::default, flate2::Compression::new);
+                let mut encoder = ZlibEncoder::new(Vec::with_capacity(data.len() / 2), level);
+                encoder.write_all(data)?;
+                encoder.finish().context("Unable to compress ZLIB data")?
+            }
+            Compression::Lz4 => {
+                let mut encoder = lz4::EncoderBuilder::new()
+                    .level(level.unwrap_or(0))
+                    .build(Vec::with_capacity(data.len() / 2))?;
+                encoder.write_all(data)?;
+                let (compressed, result) = encoder.finish();
+                result.context("Unable to compress LZ4 data")?;
+                compressed
+            }
+            Compression::Lz4Block => {
+                let mode = match level {
+                    Some(level) if level > 0 => lz4::block::CompressionMode::HIGHCOMPRESSION(level as i32),
+                    _ => lz4::block::CompressionMode::DEFAULT,
+                };
+                lz4::block::compress(data, Some(mode), false).context("Unable to compress LZ4 block data")?
+            }
+            Compression::XMem => return Err(UnsupportedCodec { codec: *self }.into()),
+            Compression::None => data.to_vec(),
+        };
+        Ok(compressed)
+    }
+
+    /// Compresses data and prefixes it with its uncompressed length, the way BSA archives store it: a zlib stream for
+    /// Oblivion and Skyrim, an LZ4 frame for Skyrim Special Edition
+    pub fn compress_buffer(&self, data: &[u8], level: Option<u32>) -> Result<Vec<u8>> {
+        if data.len() > u32::MAX as usize {
+            return Err(format_err!("{} bytes is too large to store with a length prefix", data.len()));
+        }
+        let compressed = self.compress(data, level)?;
+        let mut buffer = Vec::with_capacity(compressed.len() + 4);
+        buffer.write_u32::<LittleEndian>(data.len() as u32)?;
+        buffer.extend_from_slice(&compressed);
+        Ok(buffer)
+    }
+
+    /// Decompresses data that is prefixed with its uncompressed length, the way BSA archives store it
+    pub fn decompress_buffer(&self, buffer: &[u8]) -> Result<Vec<u8>> {
+        if buffer.len() < 4 {
+            return Err(format_err!("{} bytes is too short to hold compressed data", buffer.len()));
+        }
         let (length, data) = buffer.split_at(4);
-        let uncompressed_length = LittleEndian::read_u32(length);
-        let mut out_buffer = Vec::with_capacity(uncompressed_length as usize);
+        self.decompress(data, u64::from(LittleEndian::read_u32(length)))
+    }
+
+    /// Decompresses a bare stream that is expected to be `uncompressed_length` bytes long once decompressed
+    pub fn decompress(&self, data: &[u8], uncompressed_length: u64) -> Result<Vec<u8>> {
+        // The length can't be trusted, so it only bounds how much is decompressed. One byte past it is allowed through
+        // so that callers can tell when the data decompresses to more than was declared.
+        let read_limit = uncompressed_length.saturating_add(1);
+        let mut out_buffer = Vec::with_capacity(cmp::min(uncompressed_length as usize, MAX_PREALLOCATION));
         match self {
             Compression::Zlib => {
-                let mut decoder = ZlibDecoder::new(data);
+                let mut decoder = ZlibDecoder::new(data).take(read_limit);
                 decoder
                     .read_to_end(&mut out_buffer)
                     .context("Unable to decompress ZLIB data")?;
             }
             Compression::Lz4 => {
-                let mut decoder = lz4::Decoder::new(data)?;
+                let mut decoder = lz4::Decoder::new(data)?.take(read_limit);
                 decoder
                     .read_to_end(&mut out_buffer)
                     .context("Unable to decompress LZ4 data")?;
             }
+            Compression::Lz4Block => {
+                // A block has to be decompressed into a buffer of its full size in one go, so the length is checked
+                // against the most that LZ4 can possibly expand the data to before anything is allocated
+                if uncompressed_length > i32::MAX as u64 || uncompressed_length > (data.len() as u64) * 255 + 16 {
+                    return Err(format_err!(
+                        "{} bytes of LZ4 data can't decompress to {} bytes",
+                        data.len(),
+                        uncompressed_length
+                    ));
+                }
+                out_buffer = lz4::block::decompress(data, Some(uncompressed_length as i32))
+                    .context("Unable to decompress LZ4 block data")?;
+            }
+            Compression::XMem => return Err(UnsupportedCodec { codec: *self }.into()),
             Compression::None => out_buffer = data.to_vec(),
         };
         Ok(out_buffer)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compressible data that is long enough for every codec to actually shrink it
+    fn sample_data() -> Vec<u8> {
+        b"The Elder Scrolls archive test data. ".iter().cycle().take(4096).cloned().collect()
+    }
+
+    #[test]
+    fn compress_buffer_round_trips() {
+        let data = sample_data();
+        for &codec in &[Compression::Zlib, Compression::Lz4] {
+            for &level in &[None, Some(1), Some(9)] {
+                let buffer = codec.compress_buffer(&data, level).unwrap();
+                assert_eq!(LittleEndian::read_u32(&buffer[..4]) as usize, data.len());
+                assert!(buffer.len() < data.len());
+                assert_eq!(codec.decompress_buffer(&buffer).unwrap(), data);
+            }
+        }
+    }
+
+    #[test]
+    fn compress_buffer_writes_the_format_of_each_codec() {
+        let data = sample_data();
+        // zlib streams start with a CMF byte for deflate with a 32K window, LZ4 frames with their magic number
+        assert_eq!(Compression::Zlib.compress_buffer(&data, None).unwrap()[4], 0x78);
+        let lz4_buffer = Compression::Lz4.compress_buffer(&data, None).unwrap();
+        assert_eq!(LittleEndian::read_u32(&lz4_buffer[4..8]), 0x184D_2204);
+    }
+
+    #[test]
+    fn ba2_chunks_have_no_length_prefix() {
+        let data = sample_data();
+        let chunk = Compression::Zlib.compress(&data, None).unwrap();
+        assert_eq!(chunk[0], 0x78);
+        assert_eq!(Compression::Zlib.decompress(&chunk, data.len() as u64).unwrap(), data);
+
+        let block = Compression::Lz4Block.compress(&data, None).unwrap();
+        assert_eq!(Compression::Lz4Block.decompress(&block, data.len() as u64).unwrap(), data);
+    }
+
+    #[test]
+    fn empty_data_round_trips() {
+        for &codec in &[Compression::None, Compression::Zlib, Compression::Lz4] {
+            let buffer = codec.compress_buffer(&[], None).unwrap();
+            assert_eq!(LittleEndian::read_u32(&buffer[..4]), 0);
+            assert!(codec.decompress_buffer(&buffer).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn xmem_is_rejected() {
+        let error = Compression::XMem.compress_buffer(b"data", None).unwrap_err();
+        assert!(error.downcast_ref::<UnsupportedCodec>().is_some());
+    }
+
+    #[test]
+    fn declared_lengths_only_bound_decompression() {
+        let data = sample_data();
+        let chunk = Compression::Zlib.compress(&data, None).unwrap();
+        assert_eq!(Compression::Zlib.decompress(&chunk, u64::MAX).unwrap(), data);
+        // one byte past a declared length that's too short comes through so the mismatch can be noticed
+        assert_eq!(Compression::Zlib.decompress(&chunk, 10).unwrap().len(), 11);
+    }
+
+    #[test]
+    fn lz4_blocks_cant_claim_more_than_they_can_expand_to() {
+        let block = Compression::Lz4Block.compress(&sample_data(), None).unwrap();
+        for &uncompressed_length in &[block.len() as u64 * 255 + 17, i32::MAX as u64 + 1, u64::MAX] {
+            assert!(Compression::Lz4Block.decompress(&block, uncompressed_length).is_err());
+        }
+        assert!(Compression::Lz4Block.decompress(&[], 17).is_err());
+    }
+}
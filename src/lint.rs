@@ -0,0 +1,324 @@
+//! Checks for mistakes that the game doesn't report, but that keep it from loading files out of an archive
+//!
+//! Which rules apply depends on the game the archive is made for. Version 0x68 BSA archives are used by Fallout 3,
+//! Fallout New Vegas and Skyrim alike, so the game is only a guess for them unless it's passed to `lint_as` (or to
+//! `testract lint --game`):
+//!
+//!   * Skyrim and Skyrim Special Edition can't play sounds that are compressed
+//!   * Fallout 3 and Fallout New Vegas only play sounds and voices out of archives that embed file names
+//!   * BSA archives can't be larger than 2 GB, because the games read their offsets as signed numbers
+//!   * Fallout 4 and Starfield only load textures out of DX10 BA2 archives
+//!   * No game can look up a path longer than 255 characters, which is what's left of Windows' `MAX_PATH` after the
+//!     "Data\" folder that paths are relative to
+//!   * Names are stored as ISO-8859-1, which the games read in the code page of the system they run on, so only ASCII
+//!     names are read the same everywhere
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+// top-level imports
+use crate::ba2::BA2Type;
+use crate::bsa::{ArchiveFlags, FileFlags, Game};
+use crate::policy::UNCOMPRESSED_EXTENSIONS;
+use crate::writer::archive_path;
+
+/// Longest path that the games can look up
+const MAX_PATH_LEN: usize = 255;
+/// Largest BSA archive that the games can read
+const MAX_BSA_LEN: u64 = 0x7fff_ffff;
+
+/// How much a lint matters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Probably a mistake, but the game may still load the files
+    Warning,
+    /// The game won't load the files, or won't load them correctly
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A single mistake found in an archive
+#[derive(Debug, PartialEq)]
+pub enum Lint {
+    /// The archive holds kinds of files that its file flags don't include
+    MissingFileFlags(FileFlags),
+    /// The file flags include kinds of files that the archive doesn't hold
+    ExtraFileFlags(FileFlags),
+    /// A sound is compressed, which the game can't play
+    CompressedSound(Game),
+    /// The archive holds sounds or voices without embedding file names, which the game needs to play them
+    MissingEmbeddedNames(Game),
+    /// The path is longer than the game can look up
+    PathTooLong(usize),
+    /// The path contains characters that can't be stored as ISO-8859-1
+    NotLatin1,
+    /// The path contains characters beyond ASCII, which the game reads in the code page of the system it runs on
+    NotAscii,
+    /// A texture is stored in a general BA2 archive, which the game doesn't load textures from
+    TextureInGeneralArchive,
+    /// The archive is larger than the game can read
+    ArchiveTooLarge(u64),
+    /// The files of the archive add up to more than the game can read, so it's only small enough if they compress well
+    ArchiveMayBeTooLarge(u64),
+}
+
+impl Lint {
+    /// How much the lint matters
+    pub fn severity(&self) -> Severity {
+        match self {
+            Lint::MissingFileFlags(_) | Lint::ExtraFileFlags(_) | Lint::NotAscii | Lint::ArchiveMayBeTooLarge(_) => {
+                Severity::Warning
+            }
+            Lint::CompressedSound(_)
+            | Lint::MissingEmbeddedNames(_)
+            | Lint::PathTooLong(_)
+            | Lint::NotLatin1
+            | Lint::TextureInGeneralArchive
+            | Lint::ArchiveTooLarge(_) => Severity::Error,
+        }
+    }
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Lint::MissingFileFlags(flags) => write!(f, "the file flags are missing {:?}", flags),
+            Lint::ExtraFileFlags(flags) => write!(f, "the file flags include {:?} without any such files", flags),
+            Lint::CompressedSound(game) => write!(f, "sounds have to be stored uncompressed for {:?}", game),
+            Lint::MissingEmbeddedNames(game) => write!(
+                f,
+                "{:?} only plays sounds and voices out of archives with EMBED_FILE_NAMES set",
+                game
+            ),
+            Lint::PathTooLong(len) => write!(
+                f,
+                "the path is {} characters long, but the game can only look up paths of up to {}",
+                len, MAX_PATH_LEN
+            ),
+            Lint::NotLatin1 => write!(f, "the path can't be stored as ISO-8859-1"),
+            Lint::NotAscii => write!(f, "the path isn't ASCII, so it depends on the code page of the system"),
+            Lint::TextureInGeneralArchive => write!(f, "textures are only loaded out of DX10 archives"),
+            Lint::ArchiveTooLarge(len) => write!(
+                f,
+                "the archive is {} bytes, but the game can only read up to {}",
+                len, MAX_BSA_LEN
+            ),
+            Lint::ArchiveMayBeTooLarge(len) => write!(
+                f,
+                "the files add up to {} bytes, but the game can only read archives of up to {}",
+                len, MAX_BSA_LEN
+            ),
+        }
+    }
+}
+
+/// The outcome of linting an archive
+#[derive(Debug, Default)]
+pub struct LintReport {
+    /// Every lint found, paired with the path of the file it concerns (`None` for the archive as a whole)
+    pub lints: Vec<(Option<PathBuf>, Lint)>,
+}
+
+impl LintReport {
+    /// True if nothing was found
+    pub fn is_ok(&self) -> bool {
+        self.lints.is_empty()
+    }
+
+    /// Count of lints of the given severity
+    pub fn count(&self, severity: Severity) -> usize {
+        self.lints
+            .iter()
+            .filter(|(_, lint)| lint.severity() == severity)
+            .count()
+    }
+
+    pub(crate) fn add(&mut self, file_path: Option<&Path>, lint: Lint) {
+        self.lints.push((file_path.map(Path::to_path_buf), lint));
+    }
+
+    /// Compares the file flags of an archive against the ones its files need
+    pub(crate) fn check_file_flags<P, I>(&mut self, file_flags: FileFlags, file_paths: I)
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = P>,
+    {
+        let expected = FileFlags::from_paths(file_paths);
+        if !file_flags.contains(expected) {
+            self.add(None, Lint::MissingFileFlags(expected - file_flags));
+        }
+        if !expected.contains(file_flags) {
+            self.add(None, Lint::ExtraFileFlags(file_flags - expected));
+        }
+    }
+
+    /// Checks that a BSA archive holding sounds or voices embeds file names when its game needs them to
+    pub(crate) fn check_embedded_names<P, I>(&mut self, game: Game, archive_flags: ArchiveFlags, file_paths: I)
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = P>,
+    {
+        let needs_names = match game {
+            Game::Fallout3 | Game::FalloutNewVegas => {
+                FileFlags::from_paths(file_paths).intersects(FileFlags::SOUNDS | FileFlags::VOICES)
+            }
+            _ => false,
+        };
+        if needs_names && !archive_flags.contains(ArchiveFlags::EMBED_FILE_NAMES) {
+            self.add(None, Lint::MissingEmbeddedNames(game));
+        }
+    }
+
+    /// Checks the size of a BSA archive. `may_shrink` is set for archives that aren't built yet and whose files can
+    /// still get smaller when they're compressed.
+    pub(crate) fn check_archive_len(&mut self, len: u64, may_shrink: bool) {
+        if len <= MAX_BSA_LEN {
+            return;
+        }
+        let lint = if may_shrink {
+            Lint::ArchiveMayBeTooLarge(len)
+        } else {
+            Lint::ArchiveTooLarge(len)
+        };
+        self.add(None, lint);
+    }
+
+    /// Checks that a path can be stored and looked up the same way on every system
+    pub(crate) fn check_path(&mut self, file_path: &Path) {
+        let stored_path = archive_path(file_path);
+        let len = stored_path.chars().count();
+        if len > MAX_PATH_LEN {
+            self.add(Some(file_path), Lint::PathTooLong(len));
+        }
+        if stored_path.chars().any(|c| u32::from(c) > 0xff) {
+            self.add(Some(file_path), Lint::NotLatin1);
+        } else if !stored_path.is_ascii() {
+            self.add(Some(file_path), Lint::NotAscii);
+        }
+    }
+
+    /// Checks that a sound in a BSA archive is stored the way its game can play it
+    pub(crate) fn check_compression(&mut self, game: Game, file_path: &Path, compressed: bool) {
+        let is_sound = extension(file_path).is_some_and(|extension| UNCOMPRESSED_EXTENSIONS.contains(&&*extension));
+        if compressed && is_sound && (game == Game::Skyrim || game == Game::SkyrimSE) {
+            self.add(Some(file_path), Lint::CompressedSound(game));
+        }
+    }
+
+    /// Checks that a file in a BA2 archive is in the kind of archive that the game loads it from
+    pub(crate) fn check_ba2_type(&mut self, file_type: BA2Type, file_path: &Path) {
+        if file_type == BA2Type::General && extension(file_path).as_deref() == Some("dds") {
+            self.add(Some(file_path), Lint::TextureInGeneralArchive);
+        }
+    }
+}
+
+/// The lowercase extension of a path
+fn extension(file_path: &Path) -> Option<String> {
+    file_path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lints found by a single check
+    fn lints(check: impl FnOnce(&mut LintReport)) -> Vec<Lint> {
+        let mut report = LintReport::default();
+        check(&mut report);
+        report.lints.into_iter().map(|(_, lint)| lint).collect()
+    }
+
+    #[test]
+    fn file_flags_have_to_match_the_files() {
+        let file_paths = ["meshes/a.nif", "textures/a.dds"];
+        assert_eq!(
+            lints(|report| report.check_file_flags(FileFlags::MESHES | FileFlags::SOUNDS, file_paths)),
+            vec![Lint::MissingFileFlags(FileFlags::TEXTURES), Lint::ExtraFileFlags(FileFlags::SOUNDS)]
+        );
+        let file_flags = FileFlags::MESHES | FileFlags::TEXTURES;
+        assert_eq!(lints(|report| report.check_file_flags(file_flags, file_paths)), vec![]);
+    }
+
+    #[test]
+    fn skyrim_sounds_have_to_be_uncompressed() {
+        let sound = Path::new("sound/fx/clank.wav");
+        assert_eq!(
+            lints(|report| report.check_compression(Game::SkyrimSE, sound, true)),
+            vec![Lint::CompressedSound(Game::SkyrimSE)]
+        );
+        assert_eq!(lints(|report| report.check_compression(Game::SkyrimSE, sound, false)), vec![]);
+        assert_eq!(lints(|report| report.check_compression(Game::Oblivion, sound, true)), vec![]);
+        let mesh = Path::new("meshes/clank.nif");
+        assert_eq!(lints(|report| report.check_compression(Game::Skyrim, mesh, true)), vec![]);
+    }
+
+    #[test]
+    fn fallout_sounds_need_embedded_names() {
+        let file_paths = ["sound/voice/line.ogg"];
+        let flags = ArchiveFlags::INCLUDE_DIR_NAMES | ArchiveFlags::INCLUDE_FILE_NAMES;
+        assert_eq!(
+            lints(|report| report.check_embedded_names(Game::FalloutNewVegas, flags, file_paths)),
+            vec![Lint::MissingEmbeddedNames(Game::FalloutNewVegas)]
+        );
+        let embedded = flags | ArchiveFlags::EMBED_FILE_NAMES;
+        assert_eq!(lints(|report| report.check_embedded_names(Game::Fallout3, embedded, file_paths)), vec![]);
+        assert_eq!(lints(|report| report.check_embedded_names(Game::Skyrim, flags, file_paths)), vec![]);
+        assert_eq!(lints(|report| report.check_embedded_names(Game::Fallout3, flags, ["meshes/a.nif"])), vec![]);
+    }
+
+    #[test]
+    fn paths_have_to_fit_and_be_ascii() {
+        let long_path = format!("meshes/{}.nif", "a".repeat(MAX_PATH_LEN));
+        assert_eq!(
+            lints(|report| report.check_path(Path::new(&long_path))),
+            vec![Lint::PathTooLong(MAX_PATH_LEN + 11)]
+        );
+        assert_eq!(lints(|report| report.check_path(Path::new("meshes/\u{6587}.nif"))), vec![Lint::NotLatin1]);
+        assert_eq!(lints(|report| report.check_path(Path::new("meshes/caf\u{e9}.nif"))), vec![Lint::NotAscii]);
+        assert_eq!(lints(|report| report.check_path(Path::new("meshes/cafe.nif"))), vec![]);
+    }
+
+    #[test]
+    fn textures_have_to_be_in_texture_archives() {
+        let texture = Path::new("textures/a.dds");
+        assert_eq!(
+            lints(|report| report.check_ba2_type(BA2Type::General, texture)),
+            vec![Lint::TextureInGeneralArchive]
+        );
+        assert_eq!(lints(|report| report.check_ba2_type(BA2Type::Textures, texture)), vec![]);
+        assert_eq!(lints(|report| report.check_ba2_type(BA2Type::General, Path::new("meshes/a.nif"))), vec![]);
+    }
+
+    #[test]
+    fn bsa_archives_have_to_fit_in_2_gb() {
+        let too_large = MAX_BSA_LEN + 1;
+        assert_eq!(lints(|report| report.check_archive_len(too_large, false)), vec![Lint::ArchiveTooLarge(too_large)]);
+        assert_eq!(
+            lints(|report| report.check_archive_len(too_large, true)),
+            vec![Lint::ArchiveMayBeTooLarge(too_large)]
+        );
+        assert_eq!(lints(|report| report.check_archive_len(MAX_BSA_LEN, false)), vec![]);
+    }
+
+    #[test]
+    fn report_counts_lints_by_severity() {
+        let mut report = LintReport::default();
+        assert!(report.is_ok());
+        report.add(None, Lint::NotAscii);
+        report.add(None, Lint::NotLatin1);
+        report.add(None, Lint::TextureInGeneralArchive);
+        assert!(!report.is_ok());
+        assert_eq!(report.count(Severity::Warning), 1);
+        assert_eq!(report.count(Severity::Error), 2);
+    }
+}
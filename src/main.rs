@@ -8,11 +8,13 @@ extern crate testract;
 use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
 
-use clap::{App, Arg, ArgGroup, ArgMatches};
-use failure::ResultExt;
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
+use failure::{err_msg, ResultExt};
 
 use testract::autodetect::*;
-use testract::{ba2, bsa, ExtensionSet, Result};
+#[cfg(feature = "manifest")]
+use testract::manifest::{ArchiveManifest, Manifest};
+use testract::{ba2, bsa, ExtensionSet, LintReport, RegionKind, Result, Severity};
 
 fn parse_archives(matches: &ArgMatches, data_path: &PathBuf, output_dir: &Path) -> Result<()> {
     let extension_set = if matches.is_present("all") {
@@ -28,7 +30,7 @@ fn parse_archives(matches: &ArgMatches, data_path: &PathBuf, output_dir: &Path)
         match file_path.extension().and_then(OsStr::to_str) {
             Some("bsa") => {
                 println!("Parsing {:#?}", file_path);
-                let bsa_file = bsa::from_file(file_path)?;
+                let mut bsa_file = bsa::from_file(file_path)?;
                 if matches.is_present("header") {
                     println!("{:#?}", bsa_file.header);
                 }
@@ -36,7 +38,7 @@ fn parse_archives(matches: &ArgMatches, data_path: &PathBuf, output_dir: &Path)
             }
             Some("ba2") => {
                 println!("Parsing {:#?}", file_path);
-                let ba2_file = ba2::from_file(file_path)?;
+                let mut ba2_file = ba2::from_file(file_path)?;
                 if matches.is_present("header") {
                     println!("{:#?}", ba2_file.header);
                 }
@@ -48,11 +50,257 @@ fn parse_archives(matches: &ArgMatches, data_path: &PathBuf, output_dir: &Path)
     Ok(())
 }
 
-fn run() -> Result<()> {
-    let matches = App::new(crate_name!())
+fn verify_archives(matches: &ArgMatches) -> Result<()> {
+    let mut problem_count = 0;
+    for file_path in matches.values_of_os("ARCHIVE").unwrap().map(PathBuf::from) {
+        println!("Verifying {:#?}", file_path);
+        let report = match file_path.extension().and_then(OsStr::to_str) {
+            Some("bsa") => bsa::from_file(file_path)?.verify_contents()?,
+            Some("ba2") => ba2::from_file(file_path)?.verify_contents()?,
+            _ => return Err(err_msg(format!("{:#?} is not a .bsa or .ba2 file", file_path))),
+        };
+        for (file_name, problem) in &report.problems {
+            println!("{:#?}: {}", file_name, problem);
+        }
+        println!(
+            "{} files checked, {} problems found",
+            report.files_checked,
+            report.problems.len()
+        );
+        problem_count += report.problems.len();
+    }
+
+    if problem_count > 0 {
+        return Err(err_msg(format!("Verification failed with {} problems", problem_count)));
+    }
+    Ok(())
+}
+
+fn print_layouts(matches: &ArgMatches) -> Result<()> {
+    for file_path in matches.values_of_os("ARCHIVE").unwrap().map(PathBuf::from) {
+        println!("Mapping {:#?}", file_path);
+        let report = match file_path.extension().and_then(OsStr::to_str) {
+            Some("bsa") => bsa::from_file(file_path)?.layout()?,
+            Some("ba2") => ba2::from_file(file_path)?.layout()?,
+            _ => return Err(err_msg(format!("{:#?} is not a .bsa or .ba2 file", file_path))),
+        };
+        for region in &report.regions {
+            println!("{}", region);
+        }
+        for (first, second) in &report.overlaps {
+            println!("overlap: {} with {}", second, first);
+        }
+        for group in &report.duplicates {
+            println!("duplicate payload stored {} times:", group.len());
+            for region in group {
+                println!("    {}", region);
+            }
+        }
+        println!(
+            "{} bytes of slack, {} bytes shared, {} bytes duplicated, a rebuild would save about {} bytes",
+            report.total_len(RegionKind::Slack),
+            report.shared_len(),
+            report.duplicated_len(),
+            report.rebuild_savings()
+        );
+    }
+    Ok(())
+}
+
+fn lint_archives(matches: &ArgMatches) -> Result<()> {
+    // version 0x68 archives don't say which game they're for, so the game they're checked for can be picked
+    let game = matches.value_of("game").map(|game| match game.to_lowercase().as_str() {
+        "morrowind" => bsa::Game::Morrowind,
+        "oblivion" => bsa::Game::Oblivion,
+        "fallout3" => bsa::Game::Fallout3,
+        "falloutnv" => bsa::Game::FalloutNewVegas,
+        "skyrim" => bsa::Game::Skyrim,
+        _ => bsa::Game::SkyrimSE,
+    });
+
+    let mut error_count = 0;
+    for file_path in matches.values_of_os("INPUT").unwrap().map(PathBuf::from) {
+        println!("Linting {:#?}", file_path);
+        let reports = match file_path.extension().and_then(OsStr::to_str) {
+            Some("bsa") => {
+                let mut archive = bsa::from_file(file_path.clone())?;
+                let report = match game {
+                    Some(game) => archive.lint_as(game)?,
+                    None => archive.lint()?,
+                };
+                vec![(file_path, report)]
+            }
+            Some("ba2") => vec![(file_path.clone(), ba2::from_file(file_path)?.lint()?)],
+            Some("toml") | Some("json") => lint_manifest(&file_path, game)?,
+            _ => return Err(err_msg(format!("{:#?} is not a .bsa, .ba2, .toml or .json file", file_path))),
+        };
+        for (archive_path, report) in &reports {
+            for (file_name, lint) in &report.lints {
+                match file_name {
+                    Some(file_name) => println!("{}: {:#?}: {}", lint.severity(), file_name, lint),
+                    None => println!("{}: {}", lint.severity(), lint),
+                }
+            }
+            println!(
+                "{:#?}: {} errors, {} warnings",
+                archive_path,
+                report.count(Severity::Error),
+                report.count(Severity::Warning)
+            );
+            error_count += report.count(Severity::Error);
+        }
+    }
+
+    if error_count > 0 {
+        return Err(err_msg(format!("Linting failed with {} errors", error_count)));
+    }
+    Ok(())
+}
+
+fn convert_archive(matches: &ArgMatches) -> Result<()> {
+    let input = PathBuf::from(matches.value_of_os("INPUT").unwrap());
+    let output = Path::new(matches.value_of_os("OUTPUT").unwrap());
+    let target = match matches.value_of("to").unwrap().to_lowercase().as_str() {
+        "fallout4" => {
+            // Fallout 4 archives are named after the mod, so OUTPUT is the folder to write them to
+            let mod_name = input
+                .file_stem()
+                .ok_or_else(|| err_msg(format!("{:#?} has no file name", input)))?
+                .to_string_lossy()
+                .into_owned();
+            println!("Repacking {:#?} into {:#?}", input, output);
+            let mut archive = bsa::from_file(input)?;
+            for path in ba2::from_bsa(&mut archive, output, &mod_name)? {
+                println!("Wrote {:#?}", path);
+            }
+            return Ok(());
+        }
+        "oblivion" => bsa::Version::OBLIVION,
+        "skyrim" => bsa::Version::SKYRIM,
+        _ => bsa::Version::SKYRIMSE,
+    };
+
+    println!("Converting {:#?} to {:#?}", input, output);
+    let warnings = bsa::convert(input, target, output)?;
+    for warning in &warnings {
+        println!("warning: {}", warning);
+    }
+    Ok(())
+}
+
+#[cfg(all(feature = "mount", target_os = "linux"))]
+fn mount_archives(matches: &ArgMatches) -> Result<()> {
+    let source = Path::new(matches.value_of_os("SOURCE").unwrap());
+    let mountpoint = Path::new(matches.value_of_os("MOUNTPOINT").unwrap());
+    println!("Mounting {:#?} at {:#?} (unmount with `fusermount -u` to exit)", source, mountpoint);
+    testract::mount(source, mountpoint)
+}
+
+#[cfg(not(all(feature = "mount", target_os = "linux")))]
+fn mount_archives(_matches: &ArgMatches) -> Result<()> {
+    Err(err_msg("Mounting is only supported on Linux when testract is built with the `mount` feature"))
+}
+
+/// Paths in a manifest are relative to the folder containing it
+#[cfg(feature = "manifest")]
+fn manifest_dir(manifest_path: &Path) -> &Path {
+    manifest_path.parent().unwrap_or_else(|| Path::new(""))
+}
+
+#[cfg(feature = "manifest")]
+fn pack_manifest(matches: &ArgMatches) -> Result<()> {
+    let manifest_path = Path::new(matches.value_of_os("manifest").unwrap());
+    println!("Packing {:#?}", manifest_path);
+    let manifest = Manifest::from_file(manifest_path)?;
+    for (path, report) in manifest.build(manifest_dir(manifest_path))? {
+        println!(
+            "Wrote {:#?} ({} blocks deduplicated, {} bytes saved)",
+            path, report.deduplicated_blocks, report.bytes_saved
+        );
+    }
+    Ok(())
+}
+
+#[cfg(feature = "manifest")]
+fn write_manifest(matches: &ArgMatches) -> Result<()> {
+    let archive_path = PathBuf::from(matches.value_of_os("ARCHIVE").unwrap());
+    let manifest_path = Path::new(matches.value_of_os("MANIFEST").unwrap());
+    let file_name = archive_path
+        .file_name()
+        .ok_or_else(|| err_msg(format!("{:#?} has no file name", archive_path)))?;
+    let source_dir = match matches.value_of("sources") {
+        Some(source_dir) => source_dir.to_string(),
+        None => Path::new(file_name).with_extension("").to_string_lossy().into_owned(),
+    };
+    let extract_dir = manifest_dir(manifest_path).join(&source_dir);
+
+    println!("Describing {:#?} in {:#?}", archive_path, manifest_path);
+    let output = PathBuf::from(file_name);
+    let archive_manifest = match archive_path.extension().and_then(OsStr::to_str) {
+        Some("bsa") => {
+            let mut archive = bsa::from_file(archive_path.clone())?;
+            let archive_manifest = ArchiveManifest::from_bsa(&mut archive, output, &source_dir)?;
+            if matches.is_present("extract") {
+                archive.extract_by_extension(&ExtensionSet::All, &extract_dir)?;
+            }
+            archive_manifest
+        }
+        Some("ba2") => {
+            let mut archive = ba2::from_file(archive_path.clone())?;
+            let archive_manifest = ArchiveManifest::from_ba2(&mut archive, output, &source_dir)?;
+            if matches.is_present("extract") {
+                archive.extract_by_extension(&ExtensionSet::All, &extract_dir)?;
+            }
+            archive_manifest
+        }
+        _ => return Err(err_msg(format!("{:#?} is not a .bsa or .ba2 file", archive_path))),
+    };
+    let manifest = Manifest {
+        archives: vec![archive_manifest],
+    };
+    manifest.save(manifest_path)
+}
+
+/// Lints every archive in a manifest, paired with the path the archive is written to
+#[cfg(feature = "manifest")]
+fn lint_manifest(manifest_path: &Path, game: Option<bsa::Game>) -> Result<Vec<(PathBuf, LintReport)>> {
+    let manifest = Manifest::from_file(manifest_path)?;
+    let base_dir = manifest_dir(manifest_path);
+    manifest
+        .archives
+        .iter()
+        .map(|archive| {
+            let report = match game {
+                Some(game) => archive.lint_as(base_dir, game)?,
+                None => archive.lint(base_dir)?,
+            };
+            Ok((archive.output.clone(), report))
+        })
+        .collect()
+}
+
+#[cfg(not(feature = "manifest"))]
+fn pack_manifest(_matches: &ArgMatches) -> Result<()> {
+    Err(err_msg("Packing archives requires testract to be built with the `manifest` feature"))
+}
+
+#[cfg(not(feature = "manifest"))]
+fn write_manifest(_matches: &ArgMatches) -> Result<()> {
+    Err(err_msg("Writing manifests requires testract to be built with the `manifest` feature"))
+}
+
+#[cfg(not(feature = "manifest"))]
+fn lint_manifest(_manifest_path: &Path, _game: Option<bsa::Game>) -> Result<Vec<(PathBuf, LintReport)>> {
+    Err(err_msg("Linting manifests requires testract to be built with the `manifest` feature"))
+}
+
+/// The command line interface, with every subcommand
+fn app() -> App<'static, 'static> {
+    App::new(crate_name!())
         .version(crate_version!())
         .author(crate_authors!("\n"))
         .about(crate_description!())
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::from_usage("-g, --game [GAME] 'The game to autodetect files for'")
                 .possible_values(&["fallout4", "falloutnv", "oblivion", "skyrim", "skyrimse"])
@@ -84,23 +332,101 @@ fn run() -> Result<()> {
             )
             .requires("find"),
         )
-        .get_matches();
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Decompresses every file in the given archives and checks them for corruption")
+                .arg(Arg::from_usage("<ARCHIVE>... 'The .bsa or .ba2 files to verify'")),
+        )
+        .subcommand(
+            SubCommand::with_name("layout")
+                .about("Maps out the regions of the given archives and reports slack space, overlaps and duplicates")
+                .arg(Arg::from_usage("<ARCHIVE>... 'The .bsa or .ba2 files to map out'")),
+        )
+        .subcommand(
+            SubCommand::with_name("lint")
+                .about("Checks archives, or the archives described by manifests, for mistakes the game doesn't report")
+                .arg(Arg::from_usage("<INPUT>... 'The .bsa or .ba2 files or .toml or .json manifests to check'"))
+                .arg(
+                    Arg::from_usage(
+                        "-g, --game [GAME] 'The game to check .bsa files for, instead of the one they seem to be for'",
+                    )
+                    .possible_values(&["morrowind", "oblivion", "fallout3", "falloutnv", "skyrim", "skyrimse"])
+                    .case_insensitive(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("convert")
+                .about("Converts a .bsa file into the format used by another game")
+                .arg(Arg::from_usage("<INPUT> 'The .bsa file to convert'"))
+                .arg(
+                    Arg::from_usage("-t, --to <GAME> 'The game to convert the archive for'")
+                        .possible_values(&["fallout4", "oblivion", "skyrim", "skyrimse"])
+                        .case_insensitive(true),
+                )
+                .arg(Arg::from_usage("<OUTPUT> 'Where to write the converted .bsa file (a folder for fallout4)'")),
+        )
+        .subcommand(
+            SubCommand::with_name("mount")
+                .about("Mounts the files inside archives as a read-only filesystem (Linux only)")
+                .arg(Arg::from_usage(
+                    "<SOURCE> 'A .bsa or .ba2 file, or a data folder whose archives are merged in alphabetical order'",
+                ))
+                .arg(Arg::from_usage("<MOUNTPOINT> 'The empty folder to mount the files at'")),
+        )
+        .subcommand(
+            SubCommand::with_name("pack")
+                .about("Builds the archives described by a manifest")
+                .arg(Arg::from_usage(
+                    "-m, --manifest <MANIFEST> 'The .toml or .json manifest listing the archives and their files'",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("manifest")
+                .about("Writes a manifest that recreates an existing archive when it's packed")
+                .arg(Arg::from_usage("<ARCHIVE> 'The .bsa or .ba2 file to describe'"))
+                .arg(Arg::from_usage("<MANIFEST> 'Where to write the manifest (.toml or .json)'"))
+                .arg(Arg::from_usage(
+                    "-s, --sources [PATH] 'Folder next to the manifest that holds the files (defaults to the name of \
+                     the archive)'",
+                ))
+                .arg(Arg::from_usage("-x, --extract 'Extract the files of the archive into the sources folder'")),
+        )
+}
 
-    let data_path = if matches.is_present("game") {
-        let game = value_t_or_exit!(matches.value_of("game"), String);
-        autodetect_data_path(&game).context(format!("Unable to detect the data path for {}", game))?
-    } else {
-        let directory = value_t_or_exit!(matches.value_of("directory"), String);
-        PathBuf::from(directory)
-    };
+fn run() -> Result<()> {
+    let matches = app().get_matches();
 
-    let output_dir = if matches.is_present("output") {
-        Path::new(matches.value_of("output").unwrap())
+    if let Some(verify_matches) = matches.subcommand_matches("verify") {
+        verify_archives(verify_matches)?;
+    } else if let Some(layout_matches) = matches.subcommand_matches("layout") {
+        print_layouts(layout_matches)?;
+    } else if let Some(lint_matches) = matches.subcommand_matches("lint") {
+        lint_archives(lint_matches)?;
+    } else if let Some(convert_matches) = matches.subcommand_matches("convert") {
+        convert_archive(convert_matches)?;
+    } else if let Some(mount_matches) = matches.subcommand_matches("mount") {
+        mount_archives(mount_matches)?;
+    } else if let Some(pack_matches) = matches.subcommand_matches("pack") {
+        pack_manifest(pack_matches)?;
+    } else if let Some(manifest_matches) = matches.subcommand_matches("manifest") {
+        write_manifest(manifest_matches)?;
     } else {
-        Path::new("")
-    };
+        let data_path = if matches.is_present("game") {
+            let game = value_t_or_exit!(matches.value_of("game"), String);
+            autodetect_data_path(&game).context(format!("Unable to detect the data path for {}", game))?
+        } else {
+            let directory = value_t_or_exit!(matches.value_of("directory"), String);
+            PathBuf::from(directory)
+        };
 
-    parse_archives(&matches, &data_path, &output_dir)?;
+        let output_dir = if matches.is_present("output") {
+            Path::new(matches.value_of("output").unwrap())
+        } else {
+            Path::new("")
+        };
+
+        parse_archives(&matches, &data_path, &output_dir)?;
+    }
 
     println!("All done. Thanks for using testract!");
 
@@ -119,3 +445,39 @@ fn main() {
         std::process::exit(1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `testract lint` with the given arguments
+    fn lint(args: &[&OsStr]) -> Result<()> {
+        let matches = app().get_matches_from(std::iter::once(OsStr::new("testract")).chain(args.iter().cloned()));
+        lint_archives(matches.subcommand_matches("lint").unwrap())
+    }
+
+    #[test]
+    fn lint_checks_archives_for_the_chosen_game() {
+        // a version 0x68 archive with compressed sounds, which is taken for a Skyrim archive
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Sounds.bsa");
+        let mut editor = bsa::BSAEditor::new(bsa::Version::SKYRIM).unwrap();
+        editor.set_compression_policy(testract::CompressionPolicy {
+            extension_rules: Default::default(),
+            ..Default::default()
+        });
+        editor.add(Path::new("sound/fx/clank.wav"), vec![0; 0x100]).unwrap();
+        editor.add(Path::new("sound/fx/clang.wav"), vec![0; 0x100]).unwrap();
+        editor.save(&path).unwrap();
+
+        let lint_as = |game: &str| {
+            lint(&[OsStr::new("lint"), OsStr::new("--game"), OsStr::new(game), path.as_os_str()])
+        };
+        let error = lint(&[OsStr::new("lint"), path.as_os_str()]).unwrap_err();
+        assert_eq!(error.to_string(), "Linting failed with 2 errors");
+        // New Vegas can play compressed sounds, but only out of archives that embed file names
+        let error = lint_as("FalloutNV").unwrap_err();
+        assert_eq!(error.to_string(), "Linting failed with 1 errors");
+        lint_as("oblivion").unwrap();
+    }
+}
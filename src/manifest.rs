@@ -0,0 +1,568 @@
+//! Declarative descriptions of how to build archives
+//!
+//! A manifest lists one or more archives along with their format, flags, compression policy and the files on disk that
+//! go into them. Manifests are written in TOML, or in JSON when the file name ends in ".json". Every path in a manifest
+//! is relative to the folder containing it, so a manifest can sit next to the files of a mod and be built from
+//! anywhere.
+//!
+//! ```toml
+//! [[archive]]
+//! output = "MyMod.bsa"
+//! archive_flags = ["INCLUDE_DIR_NAMES", "INCLUDE_FILE_NAMES", "COMPRESSED_ARCHIVE"]
+//! file_flags = ["MESHES", "TEXTURES"]
+//!
+//! [archive.target]
+//! format = "bsa"
+//! version = "SKYRIMSE"
+//!
+//! [archive.compression]
+//! default = 9
+//! extensions = { wav = "never", dds = "default" }
+//!
+//! [[archive.files]]
+//! source = "build/meshes/**/*.nif"
+//! target = "meshes"
+//!
+//! [[archive.files]]
+//! source = "build/textures/mymod/icon.dds"
+//! target = "textures/interface/icon.dds"
+//! ```
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{BufRead, Seek};
+use std::path::{Path, PathBuf};
+
+use failure::ResultExt;
+use glob::Pattern;
+
+// top-level imports
+use crate::ba2::{BA2Archive, BA2Editor, BA2Type, BA2Version};
+use crate::bsa::{overhead_len, ArchiveFlags, BSAArchive, BSAEditor, FileFlags, Game, Version};
+use crate::writer::normalize_path;
+use crate::{Compression, CompressionPolicy, CompressionRule, Entry, LintReport, Result, WriteReport};
+
+/// Characters that turn a source path into a glob pattern
+const GLOB_CHARS: &[char] = &['*', '?', '['];
+
+/// A set of archives to build
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Archives in the order they're built
+    #[serde(rename = "archive", default)]
+    pub archives: Vec<ArchiveManifest>,
+}
+
+/// Everything needed to build a single archive
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveManifest {
+    /// Where the archive is written to
+    pub output:        PathBuf,
+    /// Archive flags of a BSA archive, or the ones `BSAEditor::new` starts out with if they're left out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive_flags: Option<ArchiveFlags>,
+    /// File flags of a BSA archive, or the flags its files need if they're left out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_flags:    Option<FileFlags>,
+    /// Whether files with identical content share their data
+    #[serde(default = "default_deduplicate")]
+    pub deduplicate:   bool,
+    /// Format and version of the archive
+    pub target:        Target,
+    #[serde(default)]
+    pub compression:   CompressionManifest,
+    /// Files that go into the archive. A later mapping replaces an earlier one that puts a file at the same path.
+    #[serde(default)]
+    pub files:         Vec<FileMapping>,
+}
+
+/// Format and version of an archive
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "format", rename_all = "lowercase")]
+pub enum Target {
+    Bsa {
+        version: Version,
+        /// Game the archive is made for, which version 0x68 archives don't tell on their own
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        game:    Option<Game>,
+    },
+    Ba2 {
+        version:   BA2Version,
+        #[serde(rename = "type")]
+        file_type: BA2Type,
+    },
+}
+
+/// The compression policy of an archive, in the form it's written in a manifest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompressionManifest {
+    /// Store a file uncompressed when compressing it doesn't make it any smaller
+    pub skip_if_larger: bool,
+    /// Rule for files whose extension doesn't have a rule of its own
+    pub default:        CompressionRule,
+    /// Rules by file extension, without the '.'
+    pub extensions:     BTreeMap<String, CompressionRule>,
+}
+
+/// Files on disk that go into an archive
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileMapping {
+    /// Path of a single file, or a glob pattern matching any number of files
+    pub source: String,
+    /// Path of the file in the archive, which defaults to the source path. For a pattern it's the folder that matches
+    /// are placed in instead (the root of the archive by default), and every match keeps its path below the leading
+    /// folders of the pattern that don't contain any wildcards.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+fn default_deduplicate() -> bool {
+    true
+}
+
+/// Whether a manifest is written in JSON rather than TOML
+fn is_json(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("json"))
+}
+
+impl Manifest {
+    /// Reads a manifest from a .toml or .json file
+    pub fn from_file(path: &Path) -> Result<Manifest> {
+        let contents = fs::read_to_string(path).context(format!("Unable to read {:#?}", path))?;
+        let manifest = if is_json(path) {
+            serde_json::from_str(&contents).context(format!("Unable to parse {:#?}", path))?
+        } else {
+            toml::from_str(&contents).context(format!("Unable to parse {:#?}", path))?
+        };
+        Ok(manifest)
+    }
+
+    /// Writes the manifest to a .toml or .json file
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let contents = if is_json(path) {
+            serde_json::to_string_pretty(self)?
+        } else {
+            toml::to_string_pretty(self)?
+        };
+        fs::write(path, contents).context(format!("Unable to write {:#?}", path))?;
+        Ok(())
+    }
+
+    /// Builds every archive, resolving the paths in the manifest against `base_dir`. Returns where each archive was
+    /// written along with its report.
+    pub fn build(&self, base_dir: &Path) -> Result<Vec<(PathBuf, WriteReport)>> {
+        self.archives
+            .iter()
+            .map(|archive| archive.build(base_dir))
+            .collect()
+    }
+}
+
+impl ArchiveManifest {
+    /// Describes an existing BSA archive, so that building the manifest recreates it from its files once they're
+    /// extracted into `source_dir`
+    pub fn from_bsa<R: BufRead + Seek>(
+        archive: &mut BSAArchive<R>,
+        output: PathBuf,
+        source_dir: &str,
+    ) -> Result<ArchiveManifest> {
+        if archive.header.version == Version::MORROWIND {
+            return Err(format_err!("Morrowind BSA files can't be written"));
+        }
+        let target = Target::Bsa {
+            version: archive.header.version,
+            game:    Some(archive.header.game),
+        };
+        let mut manifest = ArchiveManifest::describe(target, &archive.entries()?, output, source_dir)?;
+        manifest.archive_flags = Some(archive.header.archive_flags);
+        manifest.file_flags = Some(archive.header.file_flags);
+        Ok(manifest)
+    }
+
+    /// Describes an existing BA2 archive, so that building the manifest recreates it from its files once they're
+    /// extracted into `source_dir`
+    pub fn from_ba2<R: BufRead + Seek>(
+        archive: &mut BA2Archive<R>,
+        output: PathBuf,
+        source_dir: &str,
+    ) -> Result<ArchiveManifest> {
+        let target = Target::Ba2 {
+            version:   archive.header.version,
+            file_type: archive.header.file_type,
+        };
+        ArchiveManifest::describe(target, &archive.entries()?, output, source_dir)
+    }
+
+    /// Describes an archive from the entries of its files, which all come from a single folder
+    fn describe(target: Target, entries: &[Entry], output: PathBuf, source_dir: &str) -> Result<ArchiveManifest> {
+        if source_dir.contains(GLOB_CHARS) {
+            return Err(format_err!("{:?} can't hold the files because it contains wildcards", source_dir));
+        }
+
+        // the data of a file only shares its offset with another file's if the archive was deduplicated
+        let mut offsets = HashSet::new();
+        // (empty files can all start at the same offset without any data being shared)
+        let deduplicate = entries
+            .iter()
+            .filter_map(|entry| entry.chunks.first())
+            .filter(|chunk| chunk.stored_size > 0)
+            .any(|chunk| !offsets.insert(chunk.offset));
+
+        let files = if entries.is_empty() {
+            Vec::new()
+        } else {
+            vec![FileMapping {
+                source: format!("{}/**/*", source_dir.trim_end_matches(['/', '\\'])),
+                target: None,
+            }]
+        };
+
+        Ok(ArchiveManifest {
+            output,
+            archive_flags: None,
+            file_flags: None,
+            deduplicate,
+            target,
+            compression: CompressionManifest::describe(entries),
+            files,
+        })
+    }
+
+    /// Resolves the file mappings against `base_dir` into (path on disk, path in the archive) pairs, sorted by the
+    /// path in the archive
+    pub fn resolve_files(&self, base_dir: &Path) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let mut files = BTreeMap::new();
+        for mapping in &self.files {
+            for (source, archive_path) in mapping.resolve(base_dir)? {
+                files.insert(normalize_path(&archive_path), (source, archive_path));
+            }
+        }
+        Ok(files.into_values().collect())
+    }
+
+    /// Looks for mistakes that would keep the game from loading files out of the archive once it's built, resolving
+    /// the paths in the manifest against `base_dir`. Version 0x68 BSA archives that don't name their game are checked
+    /// as Skyrim archives, use `lint_as` to check them for another game.
+    pub fn lint(&self, base_dir: &Path) -> Result<LintReport> {
+        self.lint_for(base_dir, None)
+    }
+
+    /// Looks for mistakes like `lint` does, but under the rules of `game` instead of the game named in the manifest.
+    /// Only BSA archives have rules that differ between games.
+    pub fn lint_as(&self, base_dir: &Path, game: Game) -> Result<LintReport> {
+        self.lint_for(base_dir, Some(game))
+    }
+
+    fn lint_for(&self, base_dir: &Path, game: Option<Game>) -> Result<LintReport> {
+        let files = self.resolve_files(base_dir)?;
+        let archive_paths = files.iter().map(|(_, archive_path)| archive_path);
+        let policy = self.compression.policy();
+
+        let mut report = LintReport::default();
+        match self.target {
+            Target::Bsa { version, game: target_game } => {
+                let game = game.or(target_game).unwrap_or(match version {
+                    Version::MORROWIND => Game::Morrowind,
+                    Version::OBLIVION => Game::Oblivion,
+                    Version::SKYRIM => Game::Skyrim,
+                    Version::SKYRIMSE => Game::SkyrimSE,
+                });
+                let archive_flags = match self.archive_flags {
+                    Some(archive_flags) => archive_flags,
+                    None => BSAEditor::new(version)?.archive_flags(),
+                };
+
+                // the files are counted at their full size, since it's unknown how well they'll compress
+                let compressed = |archive_path: &PathBuf| policy.rule_for(archive_path) != CompressionRule::Never;
+                let overhead = overhead_len(
+                    version,
+                    archive_flags,
+                    archive_paths.clone().map(|archive_path| (archive_path, compressed(archive_path))),
+                );
+                let mut len = overhead;
+                for (source, _) in &files {
+                    len += fs::metadata(source).context(format!("Unable to read {:#?}", source))?.len();
+                }
+                report.check_archive_len(len, archive_paths.clone().any(compressed));
+
+                if let Some(file_flags) = self.file_flags {
+                    report.check_file_flags(file_flags, archive_paths.clone());
+                }
+                report.check_embedded_names(game, archive_flags, archive_paths.clone());
+
+                for archive_path in archive_paths {
+                    report.check_path(archive_path);
+                    report.check_compression(game, archive_path, compressed(archive_path));
+                }
+            }
+            Target::Ba2 { file_type, .. } => {
+                for archive_path in archive_paths {
+                    report.check_path(archive_path);
+                    report.check_ba2_type(file_type, archive_path);
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Builds the archive, resolving the paths in the manifest against `base_dir`. Returns where the archive was
+    /// written along with its report.
+    pub fn build(&self, base_dir: &Path) -> Result<(PathBuf, WriteReport)> {
+        let output = base_dir.join(&self.output);
+        let files = self.resolve_files(base_dir)?;
+        if let Some(parent) = output.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let report = match self.target {
+            Target::Bsa { version, .. } => {
+                let mut editor = BSAEditor::new(version)?;
+                if let Some(archive_flags) = self.archive_flags {
+                    editor.set_archive_flags(archive_flags)?;
+                }
+                if let Some(file_flags) = self.file_flags {
+                    editor.set_file_flags(file_flags);
+                }
+                editor.set_compression_policy(self.compression.policy());
+                editor.set_deduplicate(self.deduplicate);
+                for (source, archive_path) in &files {
+                    editor.add(archive_path, read_source(source)?)?;
+                }
+                editor.save(&output)?
+            }
+            Target::Ba2 { version, file_type } => {
+                if self.archive_flags.is_some() || self.file_flags.is_some() {
+                    return Err(format_err!("{:#?} is a BA2 archive, which has no archive or file flags", self.output));
+                }
+                let mut editor = BA2Editor::new(version, file_type);
+                editor.set_compression_policy(self.compression.policy());
+                editor.set_deduplicate(self.deduplicate);
+                for (source, archive_path) in &files {
+                    editor.add(archive_path, read_source(source)?)?;
+                }
+                editor.save(&output)?
+            }
+        };
+        Ok((output, report))
+    }
+}
+
+/// Whether a file has no content at all
+fn is_empty(entry: &Entry) -> bool {
+    entry.chunks.iter().all(|chunk| chunk.uncompressed_size == 0)
+}
+
+fn read_source(source: &Path) -> Result<Vec<u8>> {
+    Ok(fs::read(source).context(format!("Unable to read {:#?}", source))?)
+}
+
+impl Default for CompressionManifest {
+    /// The same policy as `CompressionPolicy::default`
+    fn default() -> Self {
+        let policy = CompressionPolicy::default();
+        CompressionManifest {
+            skip_if_larger: policy.skip_if_larger,
+            default:        policy.default_rule,
+            extensions:     policy.extension_rules.into_iter().collect(),
+        }
+    }
+}
+
+impl CompressionManifest {
+    /// The policy that new files are compressed under
+    pub fn policy(&self) -> CompressionPolicy {
+        CompressionPolicy {
+            default_rule:     self.default,
+            extension_rules:  self
+                .extensions
+                .iter()
+                .map(|(extension, &rule)| (extension.trim_start_matches('.').to_lowercase(), rule))
+                .collect(),
+            skip_if_larger:   self.skip_if_larger,
+            reuse_compressed: true,
+        }
+    }
+
+    /// Works out the rules that compress the files of an archive the same way they already are: the most common choice
+    /// becomes the default, and extensions whose files mostly went the other way get a rule of their own
+    fn describe(entries: &[Entry]) -> CompressionManifest {
+        // (compressed, total) file counts by extension
+        let mut counts: HashMap<String, (usize, usize)> = HashMap::new();
+        // empty files never get smaller, so they're left uncompressed whenever skip_if_larger is on
+        for entry in entries.iter().filter(|entry| !is_empty(entry)) {
+            let extension = entry
+                .path
+                .extension()
+                .map(|extension| extension.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+            let count = counts.entry(extension).or_default();
+            if entry.compression != Compression::None {
+                count.0 += 1;
+            }
+            count.1 += 1;
+        }
+        let rule = |compressed: usize, total: usize| {
+            if compressed * 2 > total {
+                CompressionRule::Default
+            } else {
+                CompressionRule::Never
+            }
+        };
+
+        let default = rule(
+            counts.values().map(|count| count.0).sum(),
+            counts.values().map(|count| count.1).sum(),
+        );
+        let extensions = counts
+            .into_iter()
+            .filter(|(extension, _)| !extension.is_empty())
+            .map(|(extension, (compressed, total))| (extension, rule(compressed, total)))
+            .filter(|&(_, extension_rule)| extension_rule != default)
+            .collect();
+        // files that were compressed without getting any smaller have to stay compressed, and every other archive is
+        // assumed to have left out the files that wouldn't get smaller
+        let skip_if_larger = entries.iter().flat_map(|entry| &entry.chunks).all(|chunk| {
+            chunk
+                .compressed_size
+                .map_or(true, |compressed_size| compressed_size < chunk.uncompressed_size)
+        });
+        CompressionManifest {
+            skip_if_larger,
+            default,
+            extensions,
+        }
+    }
+}
+
+impl FileMapping {
+    /// Finds the files the mapping refers to, as (path on disk, path in the archive) pairs
+    fn resolve(&self, base_dir: &Path) -> Result<Vec<(PathBuf, PathBuf)>> {
+        if !self.source.contains(GLOB_CHARS) {
+            let archive_path = PathBuf::from(self.target.as_ref().unwrap_or(&self.source));
+            return Ok(vec![(base_dir.join(&self.source), archive_path)]);
+        }
+
+        // matches keep their path below the folders that lead up to the first wildcard
+        let literal_dir: PathBuf = Path::new(&self.source)
+            .components()
+            .take_while(|component| !component.as_os_str().to_string_lossy().contains(GLOB_CHARS))
+            .collect();
+        let strip_dir = base_dir.join(literal_dir);
+        let target_dir = Path::new(self.target.as_ref().map_or("", String::as_str));
+
+        // the base folder is escaped so that only the pattern itself can contain wildcards
+        let pattern = Path::new(&Pattern::escape(&base_dir.to_string_lossy())).join(&self.source);
+        let mut files = Vec::new();
+        for source in glob::glob(&pattern.to_string_lossy())? {
+            let source = source?;
+            if !source.is_file() {
+                continue;
+            }
+            let archive_path = target_dir.join(source.strip_prefix(&strip_dir)?);
+            files.push((source, archive_path));
+        }
+        if files.is_empty() {
+            return Err(format_err!("{:?} doesn't match any files", self.source));
+        }
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ba2, bsa, ExtensionSet};
+
+    /// Files of the test archives: compressible data, a duplicate of it, a sound that stays uncompressed and an empty
+    /// file
+    const FILES: &[(&str, &[u8])] = &[
+        ("meshes/armor/helmet.nif", &[b'h'; 0x100]),
+        ("meshes/armor/boots.nif", &[b'h'; 0x100]),
+        ("sound/fx/clank.wav", &[b'c'; 0x100]),
+        ("meshes/empty.nif", &[]),
+    ];
+
+    /// Extracts every file of `archive` into `dir`
+    fn extract_all<H, F: crate::archive::Extract>(archive: &mut crate::archive::Archive<H, F>, dir: &Path) {
+        archive
+            .for_each_matching(&ExtensionSet::All, |file_path, file_data| {
+                let path = dir.join(file_path);
+                fs::create_dir_all(path.parent().unwrap())?;
+                Ok(fs::write(path, file_data)?)
+            })
+            .unwrap();
+    }
+
+    /// (path, compression, size) of every file, sorted by path
+    fn summary(mut entries: Vec<Entry>) -> Vec<(PathBuf, Compression, u64)> {
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        entries
+            .into_iter()
+            .map(|entry| (entry.path.clone(), entry.compression, entry.extracted_len().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn bsa_manifest_rebuilds_the_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut editor = BSAEditor::new(Version::SKYRIMSE).unwrap();
+        for &(file_path, contents) in FILES {
+            editor.add(Path::new(file_path), contents.to_vec()).unwrap();
+        }
+        editor.save(&dir.path().join("source.bsa")).unwrap();
+
+        let mut source = bsa::from_file(dir.path().join("source.bsa")).unwrap();
+        extract_all(&mut source, &dir.path().join("data"));
+        let manifest = ArchiveManifest::from_bsa(&mut source, PathBuf::from("rebuilt.bsa"), "data").unwrap();
+        assert!(manifest.deduplicate);
+        manifest.build(dir.path()).unwrap();
+
+        let mut rebuilt = bsa::from_file(dir.path().join("rebuilt.bsa")).unwrap();
+        let rebuilt_manifest = ArchiveManifest::from_bsa(&mut rebuilt, PathBuf::from("rebuilt.bsa"), "data").unwrap();
+        assert_eq!(format!("{:?}", rebuilt_manifest), format!("{:?}", manifest));
+        assert_eq!(summary(rebuilt.entries().unwrap()), summary(source.entries().unwrap()));
+    }
+
+    #[test]
+    fn ba2_manifest_rebuilds_the_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut editor = BA2Editor::new(BA2Version::Fallout4, BA2Type::General);
+        editor.set_deduplicate(true);
+        for &(file_path, contents) in FILES {
+            editor.add(Path::new(file_path), contents.to_vec()).unwrap();
+        }
+        editor.save(&dir.path().join("source.ba2")).unwrap();
+
+        let mut source = ba2::from_file(dir.path().join("source.ba2")).unwrap();
+        extract_all(&mut source, &dir.path().join("data"));
+        let manifest = ArchiveManifest::from_ba2(&mut source, PathBuf::from("rebuilt.ba2"), "data").unwrap();
+        assert!(manifest.deduplicate);
+        manifest.build(dir.path()).unwrap();
+
+        let mut rebuilt = ba2::from_file(dir.path().join("rebuilt.ba2")).unwrap();
+        let rebuilt_manifest = ArchiveManifest::from_ba2(&mut rebuilt, PathBuf::from("rebuilt.ba2"), "data").unwrap();
+        assert_eq!(format!("{:?}", rebuilt_manifest), format!("{:?}", manifest));
+        assert_eq!(summary(rebuilt.entries().unwrap()), summary(source.entries().unwrap()));
+    }
+
+    #[test]
+    fn empty_files_dont_count_as_deduplicated() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut editor = BSAEditor::new(Version::SKYRIMSE).unwrap();
+        editor.set_compression_policy(CompressionPolicy {
+            default_rule: CompressionRule::Never,
+            ..CompressionPolicy::default()
+        });
+        editor.set_deduplicate(false);
+        for file_path in &["meshes/a.nif", "meshes/b.nif", "meshes/c.nif"] {
+            editor.add(Path::new(file_path), Vec::new()).unwrap();
+        }
+        editor.add(Path::new("meshes/d.nif"), b"data".to_vec()).unwrap();
+        editor.save(&dir.path().join("empty.bsa")).unwrap();
+
+        let mut archive = bsa::from_file(dir.path().join("empty.bsa")).unwrap();
+        let manifest = ArchiveManifest::from_bsa(&mut archive, PathBuf::from("empty.bsa"), "data").unwrap();
+        assert!(!manifest.deduplicate);
+    }
+}
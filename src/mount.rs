@@ -0,0 +1,356 @@
+//! Read-only FUSE filesystem over the contents of one or more archives
+//!
+//! Archives are merged into a single tree in the order they're loaded, so a file in a later archive hides the file with
+//! the same path in an earlier one, like it does in the game. Paths are matched without regard to case. Files are only
+//! extracted when they're read, and the most recently read ones are kept in memory.
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{BufRead, Seek};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+
+use fuser::{FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request};
+use libc::{EIO, EISDIR, ENOENT, ENOTDIR};
+
+// top-level imports
+use crate::archive::{Archive, Extract};
+use crate::{ba2, bsa, Result};
+
+/// How long the kernel may cache names and attributes, which never change because the filesystem is read-only
+const TTL: Duration = Duration::from_secs(60);
+/// Inode number of the root directory
+const ROOT_INO: u64 = 1;
+/// Upper bound on how many bytes of extracted files are kept in memory
+const CACHE_LEN: u64 = 0x1000_0000;
+/// Block size reported to tools that use it to size their reads
+const BLOCK_SIZE: u32 = 0x1_0000;
+
+/// An archive that files can be extracted from
+trait Source {
+    fn extract(&mut self, file_path: &Path) -> Result<Vec<u8>>;
+}
+
+impl<H, F: Extract, R: BufRead + Seek> Source for Archive<H, F, R> {
+    fn extract(&mut self, file_path: &Path) -> Result<Vec<u8>> {
+        self.extract_by_name(file_path)
+    }
+}
+
+enum NodeKind {
+    /// Inode numbers of the entries in a directory, by lowercase name
+    Directory(BTreeMap<String, u64>),
+    File {
+        /// Index of the archive the file is extracted from
+        source: usize,
+        /// Path of the file inside that archive
+        path:   PathBuf,
+        /// Size of the file once extracted
+        size:   u64,
+    },
+}
+
+struct Node {
+    /// Name of the entry as it was first seen in an archive
+    name:   OsString,
+    /// Inode number of the directory containing this entry
+    parent: u64,
+    kind:   NodeKind,
+}
+
+/// The merged tree of every file in a set of archives
+struct ArchiveFs {
+    /// Every file and directory, where a node's inode number is its index plus one
+    nodes:     Vec<Node>,
+    sources:   Vec<Box<dyn Source>>,
+    /// Recently extracted files by inode number, with the most recently read one at the back
+    cache:     VecDeque<(u64, Rc<Vec<u8>>)>,
+    /// Total size of every file in the cache
+    cache_len: u64,
+    /// Modification time of the newest archive, reported for every file
+    mtime:     SystemTime,
+    /// Why reads of a file failed, by inode number. Shared with `mount`, which reports them once unmounted.
+    failed:    Rc<RefCell<BTreeMap<u64, String>>>,
+}
+
+/// Mounts `path` at `mountpoint` and blocks until it's unmounted again (e.g. with `fusermount -u`). `path` is either a
+/// single archive or a directory whose archives are loaded in alphabetical order.
+///
+/// Reads of files that can't be extracted fail with EIO while mounted, and the files are returned as an error once the
+/// filesystem is unmounted.
+pub fn mount(path: &Path, mountpoint: &Path) -> Result<()> {
+    let archive_paths = if path.is_dir() {
+        let mut archive_paths = Vec::new();
+        for dir_entry in path.read_dir()? {
+            let file_path = dir_entry?.path();
+            if archive_kind(&file_path).is_some() {
+                archive_paths.push(file_path);
+            }
+        }
+        archive_paths.sort();
+        archive_paths
+    } else {
+        vec![path.to_path_buf()]
+    };
+
+    let mut filesystem = ArchiveFs {
+        nodes:     vec![Node {
+            name:   OsString::new(),
+            parent: ROOT_INO,
+            kind:   NodeKind::Directory(BTreeMap::new()),
+        }],
+        sources:   Vec::new(),
+        cache:     VecDeque::new(),
+        cache_len: 0,
+        mtime:     SystemTime::UNIX_EPOCH,
+        failed:    Rc::default(),
+    };
+    let failed = Rc::clone(&filesystem.failed);
+    for archive_path in archive_paths {
+        filesystem.load(archive_path)?;
+    }
+
+    let fs_name = path.file_name().unwrap_or(path.as_os_str()).to_string_lossy();
+    let options = [
+        MountOption::RO,
+        MountOption::NoExec,
+        MountOption::FSName(format!("testract:{}", fs_name)),
+    ];
+    fuser::mount2(filesystem, mountpoint, &options)?;
+
+    let failed = failed.borrow();
+    if !failed.is_empty() {
+        let reasons: Vec<&str> = failed.values().map(String::as_str).collect();
+        return Err(format_err!("Unable to extract {} files while mounted:\n{}", failed.len(), reasons.join("\n")));
+    }
+    Ok(())
+}
+
+/// The lowercase extension of a path if it's an archive that can be mounted
+fn archive_kind(path: &Path) -> Option<String> {
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .filter(|extension| extension == "bsa" || extension == "ba2")
+}
+
+impl ArchiveFs {
+    /// Opens an archive and adds every file in it to the tree
+    fn load(&mut self, path: PathBuf) -> Result<()> {
+        let modified = fs::metadata(&path)?.modified()?;
+        if modified > self.mtime {
+            self.mtime = modified;
+        }
+
+        let (source, entries): (Box<dyn Source>, _) = match archive_kind(&path).as_deref() {
+            Some("bsa") => {
+                let mut archive = bsa::from_file(path)?;
+                let entries = archive.entries()?;
+                (Box::new(archive), entries)
+            }
+            Some("ba2") => {
+                let mut archive = ba2::from_file(path)?;
+                let entries = archive.entries()?;
+                (Box::new(archive), entries)
+            }
+            _ => return Err(format_err!("{:#?} is not a .bsa or .ba2 file", path)),
+        };
+        let source_index = self.sources.len();
+        self.sources.push(source);
+
+        for entry in entries {
+            let size = entry.extracted_len()?;
+            let file_path = entry.path.to_string_lossy().replace("\\", "/");
+            let mut components: Vec<&str> = file_path.split('/').filter(|name| !name.is_empty()).collect();
+            let file_name = match components.pop() {
+                Some(file_name) => file_name,
+                None => continue,
+            };
+
+            let mut dir_ino = ROOT_INO;
+            for dir_name in components {
+                dir_ino = self.child(dir_ino, dir_name, || NodeKind::Directory(BTreeMap::new()))?;
+            }
+            let ino = self.child(dir_ino, file_name, || NodeKind::File {
+                source: source_index,
+                path: entry.path.clone(),
+                size,
+            })?;
+
+            // a file in a later archive replaces the one that was loaded before it
+            if let NodeKind::File { .. } = self.node(ino).kind {
+                self.nodes[ino as usize - 1].kind = NodeKind::File {
+                    source: source_index,
+                    path: entry.path,
+                    size,
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// Finds the entry called `name` in a directory, creating it with `kind` if it doesn't exist yet
+    fn child<K>(&mut self, parent: u64, name: &str, kind: K) -> Result<u64>
+    where
+        K: FnOnce() -> NodeKind,
+    {
+        let key = name.to_lowercase();
+        let next_ino = self.nodes.len() as u64 + 1;
+        let ino = match self.nodes[parent as usize - 1].kind {
+            NodeKind::Directory(ref mut children) => *children.entry(key).or_insert(next_ino),
+            NodeKind::File { .. } => return Err(format_err!("{:?} is both a file and a directory", name)),
+        };
+        if ino == next_ino {
+            self.nodes.push(Node {
+                name: OsString::from(name),
+                parent,
+                kind: kind(),
+            });
+        }
+        Ok(ino)
+    }
+
+    fn node(&self, ino: u64) -> &Node {
+        &self.nodes[ino as usize - 1]
+    }
+
+    fn get(&self, ino: u64) -> Option<&Node> {
+        ino.checked_sub(1).and_then(|index| self.nodes.get(index as usize))
+    }
+
+    fn attr(&self, req: &Request, ino: u64) -> Option<FileAttr> {
+        let (kind, size, perm, nlink) = match self.get(ino)?.kind {
+            NodeKind::Directory(_) => (FileType::Directory, 0, 0o555, 2),
+            NodeKind::File { size, .. } => (FileType::RegularFile, size, 0o444, 1),
+        };
+        Some(FileAttr {
+            ino,
+            size,
+            blocks: (size + 511) / 512,
+            atime: self.mtime,
+            mtime: self.mtime,
+            ctime: self.mtime,
+            crtime: self.mtime,
+            kind,
+            perm,
+            nlink,
+            uid: req.uid(),
+            gid: req.gid(),
+            rdev: 0,
+            blksize: BLOCK_SIZE,
+            flags: 0,
+        })
+    }
+
+    /// Gets the content of a file from the cache, or extracts it and adds it to the cache
+    fn contents(&mut self, ino: u64) -> Result<Rc<Vec<u8>>> {
+        if let Some(position) = self.cache.iter().position(|&(cached_ino, _)| cached_ino == ino) {
+            let cached = self.cache.remove(position).unwrap();
+            let contents = Rc::clone(&cached.1);
+            self.cache.push_back(cached);
+            return Ok(contents);
+        }
+
+        let contents = match self.nodes[ino as usize - 1].kind {
+            NodeKind::File { source, ref path, .. } => Rc::new(self.sources[source].extract(path)?),
+            NodeKind::Directory(_) => return Err(format_err!("Inode {} is a directory", ino)),
+        };
+        self.cache_len += contents.len() as u64;
+        self.cache.push_back((ino, Rc::clone(&contents)));
+        while self.cache_len > CACHE_LEN && self.cache.len() > 1 {
+            if let Some((_, evicted)) = self.cache.pop_front() {
+                self.cache_len -= evicted.len() as u64;
+            }
+        }
+        Ok(contents)
+    }
+}
+
+impl Filesystem for ArchiveFs {
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let ino = match self.get(parent).map(|node| &node.kind) {
+            Some(NodeKind::Directory(children)) => children.get(&name.to_string_lossy().to_lowercase()).copied(),
+            Some(NodeKind::File { .. }) => return reply.error(ENOTDIR),
+            None => None,
+        };
+        match ino.and_then(|ino| self.attr(req, ino)) {
+            Some(attr) => reply.entry(&TTL, &attr, 0),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        match self.attr(req, ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self.get(ino).map(|node| &node.kind) {
+            Some(NodeKind::File { .. }) => (),
+            Some(NodeKind::Directory(_)) => return reply.error(EISDIR),
+            None => return reply.error(ENOENT),
+        }
+        match self.contents(ino) {
+            Ok(contents) => {
+                let start = (offset.max(0) as usize).min(contents.len());
+                let end = start.saturating_add(size as usize).min(contents.len());
+                reply.data(&contents[start..end]);
+            }
+            Err(e) => {
+                let reason = match self.node(ino).kind {
+                    NodeKind::File { ref path, .. } => format!("{:#?}: {}", path, e),
+                    NodeKind::Directory(_) => e.to_string(),
+                };
+                self.failed.borrow_mut().insert(ino, reason);
+                reply.error(EIO);
+            }
+        }
+    }
+
+    fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        let node = match self.get(ino) {
+            Some(node) => node,
+            None => return reply.error(ENOENT),
+        };
+        let children = match node.kind {
+            NodeKind::Directory(ref children) => children,
+            NodeKind::File { .. } => return reply.error(ENOTDIR),
+        };
+
+        let dir_entries = [
+            (ino, FileType::Directory, OsStr::new(".")),
+            (node.parent, FileType::Directory, OsStr::new("..")),
+        ];
+        let child_entries = children.values().map(|&child_ino| {
+            let child = self.node(child_ino);
+            let kind = match child.kind {
+                NodeKind::Directory(_) => FileType::Directory,
+                NodeKind::File { .. } => FileType::RegularFile,
+            };
+            (child_ino, kind, child.name.as_os_str())
+        });
+        for (index, (entry_ino, kind, name)) in dir_entries.iter().copied().chain(child_entries).enumerate() {
+            if (index as i64) < offset {
+                continue;
+            }
+            // the offset of an entry is where the next call picks up, and `add` returns true once the buffer is full
+            if reply.add(entry_ino, index as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
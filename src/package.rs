@@ -0,0 +1,265 @@
+//! Reading archives out of mod packages (.zip, and .7z with the `sevenz` feature) without unpacking them to disk
+//!
+//! Members are recognized as archives by their file magic rather than their name. Each one is decompressed into memory,
+//! or into a temporary file once it grows too large, so that the archive parsers can seek around in it.
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use failure::ResultExt;
+use zip::ZipArchive;
+
+#[cfg(feature = "sevenz")]
+use sevenz_rust::{Password, SevenZReader};
+
+// top-level imports
+use crate::ba2::BA2Archive;
+use crate::bsa::BSAArchive;
+use crate::{ba2, bsa, Result};
+
+/// Upper bound on how large a member can get before it is moved out of memory and into a temporary file
+#[cfg(not(test))]
+const MAX_IN_MEMORY_LEN: u64 = 0x400_0000;
+/// Small enough in tests that the archives they build go through a temporary file as well
+#[cfg(test)]
+const MAX_IN_MEMORY_LEN: u64 = 0x200;
+
+/// Seekable copy of a single member of a package
+pub struct MemberReader {
+    backing: Backing,
+}
+
+enum Backing {
+    Memory(Cursor<Vec<u8>>),
+    TempFile(File),
+}
+
+impl Read for MemberReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.backing {
+            Backing::Memory(ref mut cursor) => cursor.read(buf),
+            Backing::TempFile(ref mut file) => file.read(buf),
+        }
+    }
+}
+
+impl Seek for MemberReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self.backing {
+            Backing::Memory(ref mut cursor) => cursor.seek(pos),
+            Backing::TempFile(ref mut file) => file.seek(pos),
+        }
+    }
+}
+
+/// An archive that was found inside of a package
+pub enum PackagedArchive {
+    Bsa(BSAArchive<BufReader<MemberReader>>),
+    Ba2(BA2Archive<BufReader<MemberReader>>),
+}
+
+#[derive(Clone, Copy)]
+enum ArchiveKind {
+    Bsa,
+    Ba2,
+}
+
+impl ArchiveKind {
+    /// Identifies an archive by its file magic
+    fn from_magic(magic: &[u8]) -> Option<ArchiveKind> {
+        match magic {
+            b"BSA\0" | b"\x00\x01\x00\x00" => Some(ArchiveKind::Bsa),
+            b"BTDX" => Some(ArchiveKind::Ba2),
+            _ => None,
+        }
+    }
+}
+
+/// Opens the package at `path` and passes every archive inside of it to `visitor`, along with its path inside of the
+/// package. The kind of package is told apart by its file magic.
+///
+/// Visiting stops at the first error returned by either an archive or the visitor.
+pub fn for_each_archive<V>(path: &Path, visitor: V) -> Result<()>
+where
+    V: FnMut(&Path, PackagedArchive) -> Result<()>,
+{
+    let mut file = File::open(path)?;
+    let mut magic = Vec::new();
+    (&mut file).take(6).read_to_end(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    match magic.as_slice() {
+        [b'P', b'K', 3, 4, ..] | [b'P', b'K', 5, 6, ..] => for_each_zip_archive(BufReader::new(file), visitor),
+        b"7z\xBC\xAF\x27\x1C" => for_each_7z_archive(BufReader::new(file), visitor),
+        _ => Err(format_err!("{:#?} is not a .zip or .7z file", path)),
+    }
+}
+
+/// Passes every archive inside of a .zip package to `visitor`, along with its path inside of the package
+pub fn for_each_zip_archive<R, V>(reader: R, mut visitor: V) -> Result<()>
+where
+    R: Read + Seek,
+    V: FnMut(&Path, PackagedArchive) -> Result<()>,
+{
+    let mut package = ZipArchive::new(reader).context("Unable to read the zip package")?;
+    for index in 0..package.len() {
+        let member = package.by_index(index)?;
+        if member.is_dir() {
+            continue;
+        }
+        let member_path = Path::new(member.name()).to_path_buf();
+        if let Some(archive) = open_member(member, &member_path)? {
+            visitor(&member_path, archive)?;
+        }
+    }
+    Ok(())
+}
+
+/// Passes every archive inside of a .7z package to `visitor`, along with its path inside of the package
+#[cfg(feature = "sevenz")]
+pub fn for_each_7z_archive<R, V>(mut reader: R, mut visitor: V) -> Result<()>
+where
+    R: Read + Seek,
+    V: FnMut(&Path, PackagedArchive) -> Result<()>,
+{
+    let package_len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    let mut package =
+        SevenZReader::new(reader, package_len, Password::empty()).context("Unable to read the 7z package")?;
+
+    // errors from the visitor are kept aside because the callback can only return errors of the 7z crate
+    let mut result = Ok(());
+    package.for_each_entries(|member, member_reader| {
+        if member.is_directory() {
+            return Ok(true);
+        }
+        let member_path = Path::new(member.name()).to_path_buf();
+        let visited = open_member(&mut *member_reader, &member_path)
+            .and_then(|archive| archive.map_or(Ok(()), |archive| visitor(&member_path, archive)));
+        // members share one decompression stream, so whatever wasn't read has to be skipped over for the next one
+        io::copy(member_reader, &mut io::sink())?;
+        match visited {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                result = Err(e);
+                Ok(false)
+            }
+        }
+    })?;
+    result
+}
+
+#[cfg(not(feature = "sevenz"))]
+fn for_each_7z_archive<R, V>(_reader: R, _visitor: V) -> Result<()>
+where
+    R: Read + Seek,
+    V: FnMut(&Path, PackagedArchive) -> Result<()>,
+{
+    Err(format_err!("Reading .7z packages requires testract to be built with the `sevenz` feature"))
+}
+
+/// Copies a member out of a package and parses it if it is an archive. Anything else is skipped without reading more
+/// than its file magic.
+fn open_member<R: Read>(mut member: R, member_path: &Path) -> Result<Option<PackagedArchive>> {
+    let mut data = Vec::new();
+    (&mut member).take(4).read_to_end(&mut data)?;
+    let kind = match ArchiveKind::from_magic(&data) {
+        Some(kind) => kind,
+        None => return Ok(None),
+    };
+
+    // the sizes stored in packages can't be trusted, so members are moved to a temporary file once they actually grow
+    // past the limit rather than based on their declared size
+    (&mut member).take(MAX_IN_MEMORY_LEN).read_to_end(&mut data)?;
+    let backing = if data.len() as u64 > MAX_IN_MEMORY_LEN {
+        let mut file = tempfile::tempfile()?;
+        file.write_all(&data)?;
+        io::copy(&mut member, &mut file)?;
+        file.seek(SeekFrom::Start(0))?;
+        Backing::TempFile(file)
+    } else {
+        Backing::Memory(Cursor::new(data))
+    };
+
+    let reader = MemberReader { backing };
+    let archive = match kind {
+        ArchiveKind::Bsa => bsa::from_reader(reader).map(PackagedArchive::Bsa),
+        ArchiveKind::Ba2 => ba2::from_reader(reader).map(PackagedArchive::Ba2),
+    };
+    Ok(Some(archive.context(format!("Unable to parse {:#?}", member_path))?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    use zip::write::{FileOptions, ZipWriter};
+
+    use crate::ba2::{BA2Editor, BA2Type, BA2Version};
+    use crate::bsa::{BSAEditor, Version};
+
+    /// Saves an archive with `save` and returns its bytes
+    fn saved<S: FnOnce(&Path)>(save: S) -> Vec<u8> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive");
+        save(&path);
+        std::fs::read(path).unwrap()
+    }
+
+    #[test]
+    fn zip_members_are_told_apart_by_magic() {
+        // the BSA is too large to be kept in memory, the BA2 isn't
+        let bsa = saved(|path| {
+            let mut editor = BSAEditor::new(Version::SKYRIMSE).unwrap();
+            // noise that doesn't compress
+            let contents: Vec<u8> = (0u32..0x400).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+            editor.add(Path::new("meshes/helmet.nif"), contents).unwrap();
+            editor.save(path).unwrap();
+        });
+        let ba2 = saved(|path| {
+            let mut editor = BA2Editor::new(BA2Version::Fallout4, BA2Type::General);
+            editor.add(Path::new("meshes/boots.nif"), b"boots".to_vec()).unwrap();
+            editor.save(path).unwrap();
+        });
+        assert!(bsa.len() as u64 > MAX_IN_MEMORY_LEN && (ba2.len() as u64) < MAX_IN_MEMORY_LEN);
+
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        writer.add_directory("data/", FileOptions::default()).unwrap();
+        // members are named so that only their magic gives away what they are
+        for &(name, contents) in &[("data/first.bin", &bsa[..]), ("data/readme.bsa", b"BSA?"), ("second", &ba2)] {
+            writer.start_file(name, FileOptions::default()).unwrap();
+            writer.write_all(contents).unwrap();
+        }
+        let package = writer.finish().unwrap();
+
+        let mut visited = Vec::new();
+        for_each_zip_archive(package, |member_path, archive| {
+            let (file_path, kind, backing, contents) = match archive {
+                PackagedArchive::Bsa(mut archive) => {
+                    let contents = archive.extract_by_name(Path::new("meshes/helmet.nif"))?;
+                    ("helmet", "bsa", archive.reader.into_inner().backing, contents)
+                }
+                PackagedArchive::Ba2(mut archive) => {
+                    let contents = archive.extract_by_name(Path::new("meshes\\boots.nif"))?;
+                    ("boots", "ba2", archive.reader.into_inner().backing, contents)
+                }
+            };
+            let in_memory = match backing {
+                Backing::Memory(_) => true,
+                Backing::TempFile(_) => false,
+            };
+            visited.push((member_path.to_path_buf(), file_path, kind, in_memory, contents.len()));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            visited,
+            vec![
+                (PathBuf::from("data/first.bin"), "helmet", "bsa", false, 0x400),
+                (PathBuf::from("second"), "boots", "ba2", true, 5),
+            ]
+        );
+    }
+}
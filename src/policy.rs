@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+#[cfg(feature = "serde")]
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{Compression, Result};
+
+/// Extensions of sound files that the engine has to be able to stream straight out of an archive
+pub(crate) const UNCOMPRESSED_EXTENSIONS: [&str; 4] = ["wav", "xwm", "fuz", "ogg"];
+
+/// How a single file should be compressed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompressionRule {
+    /// Always store the file uncompressed
+    Never,
+    /// Compress the file with the codec's default level
+    Default,
+    /// Compress the file with the given level (0-9 for zlib, 0-16 for LZ4)
+    Level(u32),
+}
+
+/// Rules are written as "never", "default" or the level itself, which reads better in manifests than an enum would
+#[cfg(feature = "serde")]
+impl Serialize for CompressionRule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match *self {
+            CompressionRule::Never => serializer.serialize_str("never"),
+            CompressionRule::Default => serializer.serialize_str("default"),
+            CompressionRule::Level(level) => serializer.serialize_u32(level),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for CompressionRule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Rule {
+            Name(String),
+            Level(u32),
+        }
+
+        match Rule::deserialize(deserializer)? {
+            Rule::Name(ref name) if name.eq_ignore_ascii_case("never") => Ok(CompressionRule::Never),
+            Rule::Name(ref name) if name.eq_ignore_ascii_case("default") => Ok(CompressionRule::Default),
+            Rule::Name(name) => Err(de::Error::custom(format!("Unknown CompressionRule {:?}", name))),
+            Rule::Level(level) => Ok(CompressionRule::Level(level)),
+        }
+    }
+}
+
+/// Decides which files get compressed, and how strongly, when an archive is written
+#[derive(Debug, Clone)]
+pub struct CompressionPolicy {
+    /// Rule for files whose extension doesn't have a rule of its own
+    pub default_rule:     CompressionRule,
+    /// Rules by lowercase file extension, without the '.'
+    pub extension_rules:  HashMap<String, CompressionRule>,
+    /// Store a file uncompressed when compressing it doesn't make it any smaller
+    pub skip_if_larger:   bool,
+    /// Copy files that are already stored in the source archive as they are, instead of compressing them again
+    pub reuse_compressed: bool,
+}
+
+impl Default for CompressionPolicy {
+    /// Compresses everything with the codec's default level, except for sounds that the engine requires uncompressed
+    fn default() -> Self {
+        let extension_rules = UNCOMPRESSED_EXTENSIONS
+            .iter()
+            .map(|extension| (extension.to_string(), CompressionRule::Never))
+            .collect();
+        CompressionPolicy {
+            default_rule: CompressionRule::Default,
+            extension_rules,
+            skip_if_larger: true,
+            reuse_compressed: true,
+        }
+    }
+}
+
+impl CompressionPolicy {
+    /// A policy that stores every new file uncompressed
+    pub fn uncompressed() -> Self {
+        CompressionPolicy {
+            default_rule:     CompressionRule::Never,
+            extension_rules:  HashMap::new(),
+            skip_if_larger:   true,
+            reuse_compressed: true,
+        }
+    }
+
+    /// Looks up the rule that applies to a file path
+    pub fn rule_for(&self, file_path: &Path) -> CompressionRule {
+        file_path
+            .extension()
+            .and_then(|extension| {
+                self.extension_rules
+                    .get(&extension.to_string_lossy().to_lowercase())
+                    .copied()
+            })
+            .unwrap_or(self.default_rule)
+    }
+
+    /// Compresses a file's data into a bare stream of `codec` if the policy says it should be, for BA2 chunks. Returns
+    /// `None` if the file should be stored uncompressed instead.
+    pub fn compress(&self, codec: Compression, file_path: &Path, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.apply(codec, file_path, data, |level| codec.compress(data, level))
+    }
+
+    /// Compresses a file's data into a length prefixed block of `codec` if the policy says it should be, for BSA
+    /// archives. Returns `None` if the file should be stored uncompressed instead.
+    pub fn compress_buffer(&self, codec: Compression, file_path: &Path, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.apply(codec, file_path, data, |level| codec.compress_buffer(data, level))
+    }
+
+    /// Runs `encode` with the level for a file unless the rules say the file shouldn't be compressed
+    fn apply<F>(&self, codec: Compression, file_path: &Path, data: &[u8], encode: F) -> Result<Option<Vec<u8>>>
+    where
+        F: FnOnce(Option<u32>) -> Result<Vec<u8>>,
+    {
+        let level = match self.rule_for(file_path) {
+            CompressionRule::Never => return Ok(None),
+            CompressionRule::Default => None,
+            CompressionRule::Level(level) => Some(level),
+        };
+        if codec == Compression::None {
+            return Ok(None);
+        }
+        let compressed = encode(level)?;
+        if self.skip_if_larger && compressed.len() >= data.len() {
+            return Ok(None);
+        }
+        Ok(Some(compressed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bsa::{BSAEditor, Version};
+
+    /// Compressible data that is long enough for every codec to actually shrink it
+    fn sample_data() -> Vec<u8> {
+        b"Oblivion, Skyrim and Fallout archive data. ".repeat(50)
+    }
+
+    #[test]
+    fn extension_rules_ignore_case() {
+        let mut policy = CompressionPolicy::default();
+        policy.extension_rules.insert("nif".to_string(), CompressionRule::Level(9));
+        assert_eq!(policy.rule_for(Path::new("meshes/helmet.NIF")), CompressionRule::Level(9));
+        assert_eq!(policy.rule_for(Path::new("Sound/FX/Clank.WAV")), CompressionRule::Never);
+        assert_eq!(policy.rule_for(Path::new("voice/line.Fuz")), CompressionRule::Never);
+        assert_eq!(policy.rule_for(Path::new("textures/helmet.dds")), CompressionRule::Default);
+        assert_eq!(policy.rule_for(Path::new("readme")), CompressionRule::Default);
+
+        let data = sample_data();
+        assert!(policy.compress_buffer(Compression::Zlib, Path::new("SOUND/CLANK.WAV"), &data).unwrap().is_none());
+        assert!(policy.compress_buffer(Compression::Zlib, Path::new("MESHES/HELMET.NIF"), &data).unwrap().is_some());
+    }
+
+    #[test]
+    fn skip_if_larger_stores_incompressible_data() {
+        // xorshift noise that doesn't compress
+        let mut state = 0x1234_5678u32;
+        let data: Vec<u8> = (0..0x400)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        let mut policy = CompressionPolicy::default();
+        for &codec in &[Compression::Zlib, Compression::Lz4, Compression::Lz4Block] {
+            assert!(policy.compress(codec, Path::new("noise.bin"), &data).unwrap().is_none());
+            assert!(policy.compress(codec, Path::new("text.txt"), &sample_data()).unwrap().is_some());
+        }
+
+        policy.skip_if_larger = false;
+        let compressed = policy.compress_buffer(Compression::Zlib, Path::new("noise.bin"), &data).unwrap().unwrap();
+        assert!(compressed.len() >= data.len());
+        assert_eq!(Compression::Zlib.decompress_buffer(&compressed).unwrap(), data);
+        assert!(policy.compress(Compression::None, Path::new("text.txt"), &sample_data()).unwrap().is_none());
+    }
+
+    #[test]
+    fn reuse_compressed_copies_compressed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source.bsa");
+        let mut editor = BSAEditor::new(Version::SKYRIM).unwrap();
+        editor.add(Path::new("meshes/helmet.nif"), sample_data()).unwrap();
+        editor.save(&source).unwrap();
+        let stored_size = |path: &Path| {
+            let archive = crate::bsa::from_file(path.to_path_buf()).unwrap();
+            let size = archive.file_hashmap[Path::new("meshes/helmet.nif")].size;
+            size as usize
+        };
+        assert!(stored_size(&source) < sample_data().len());
+
+        for &reuse_compressed in &[true, false] {
+            let mut editor = BSAEditor::from_file(source.clone()).unwrap();
+            editor.set_compression_policy(CompressionPolicy {
+                reuse_compressed,
+                ..CompressionPolicy::uncompressed()
+            });
+            let copy = dir.path().join(format!("{}.bsa", reuse_compressed));
+            editor.save(&copy).unwrap();
+            let mut archive = crate::bsa::from_file(copy.clone()).unwrap();
+            assert_eq!(archive.extract_by_name(Path::new("meshes/helmet.nif")).unwrap(), sample_data());
+            match reuse_compressed {
+                true => assert_eq!(std::fs::read(&copy).unwrap(), std::fs::read(&source).unwrap()),
+                false => assert_eq!(stored_size(&copy), sample_data().len()),
+            }
+        }
+    }
+}
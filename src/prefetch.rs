@@ -0,0 +1,260 @@
+//! Asynchronous reading of archives through tokio
+//!
+//! The archive parsers read their input synchronously, so the bytes they need are first read asynchronously into a
+//! [`Prefetch`] buffer that the parsers then read from like any other stream. Whenever a parser steps outside of what
+//! has been read so far, the missing bytes are read in and the parser starts over. Parsing is cheap compared to I/O,
+//! and each retry reads twice as much as the one before, so only a handful of retries are ever needed.
+//!
+//! [`Prefetch`]: struct.Prefetch.html
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+
+use failure::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+
+// top-level imports
+use crate::reader::TESReader;
+use crate::Result;
+
+/// How many bytes are read in the first time a parser runs out, every retry after that reads twice as many
+const FIRST_FETCH_LEN: u64 = 0x1_0000;
+/// Upper bound on how many bytes a single retry reads in
+const MAX_FETCH_LEN: u64 = 0x100_0000;
+
+/// Type alias for reading from a prefetched archive
+pub(crate) type PrefetchReader = TESReader<BufReader<Prefetch>>;
+
+/// A part of the archive that a parser tried to read before it was read into memory
+#[derive(Debug)]
+struct MissingRange {
+    offset: u64,
+    len:    u64,
+}
+
+impl fmt::Display for MissingRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} bytes at offset {} haven't been read yet", self.len, self.offset)
+    }
+}
+
+impl error::Error for MissingRange {}
+
+/// Sparse in-memory copy of the parts of an archive that have been read so far
+pub(crate) struct Prefetch {
+    /// Length of the whole archive, so that sizes can be sanity checked before their bytes have been read
+    len:      u64,
+    position: u64,
+    /// Every range of the archive that has been read, by offset. Ranges never overlap.
+    ranges:   BTreeMap<u64, Vec<u8>>,
+}
+
+impl Prefetch {
+    fn new(len: u64) -> Prefetch {
+        Prefetch {
+            len,
+            position: 0,
+            ranges: BTreeMap::new(),
+        }
+    }
+
+    /// Finds the range that contains `offset`, returning its start and data
+    fn range_at(&self, offset: u64) -> Option<(u64, &[u8])> {
+        self.ranges
+            .range(..=offset)
+            .next_back()
+            .filter(|(&start, data)| offset < start + data.len() as u64)
+            .map(|(&start, data)| (start, data.as_slice()))
+    }
+
+    /// Reads up to `len` bytes starting at `offset`, stopping early at the end of the archive or at the next range that
+    /// has already been read. Nothing is read if `offset` has been read already.
+    async fn fetch<R>(&mut self, reader: &mut R, offset: u64, len: u64) -> Result<()>
+    where
+        R: AsyncRead + AsyncSeek + Unpin,
+    {
+        if self.range_at(offset).is_some() {
+            return Ok(());
+        }
+        let next_start = self.ranges.range(offset..).next().map_or(self.len, |(&start, _)| start);
+        let end = offset.saturating_add(len).min(next_start).min(self.len);
+        if end <= offset {
+            return Ok(());
+        }
+
+        let mut data = vec![0; (end - offset) as usize];
+        reader.seek(SeekFrom::Start(offset)).await?;
+        reader.read_exact(&mut data).await?;
+        self.ranges.insert(offset, data);
+        Ok(())
+    }
+}
+
+impl Read for Prefetch {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.len {
+            return Ok(0);
+        }
+        let read_len = match self.range_at(self.position) {
+            Some((start, data)) => {
+                let available = &data[(self.position - start) as usize..];
+                let read_len = available.len().min(buf.len());
+                buf[..read_len].copy_from_slice(&available[..read_len]);
+                read_len
+            }
+            None => {
+                let missing = MissingRange {
+                    offset: self.position,
+                    len:    buf.len() as u64,
+                };
+                return Err(io::Error::new(io::ErrorKind::Other, missing));
+            }
+        };
+        self.position += read_len as u64;
+        Ok(read_len)
+    }
+}
+
+impl Seek for Prefetch {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        // files can't be seeked past i64::MAX either
+        self.position = position
+            .filter(|&position| i64::try_from(position).is_ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek position"))?;
+        Ok(self.position)
+    }
+}
+
+/// Finds out whether an error was caused by a parser reading bytes that haven't been read into memory yet
+fn missing_range(error: &Error) -> Option<&MissingRange> {
+    error
+        .iter_chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .find_map(|e| e.get_ref().and_then(|inner| inner.downcast_ref::<MissingRange>()))
+}
+
+/// Runs a synchronous `parse` over the archive behind the async `reader`, reading in whatever it needs until it either
+/// succeeds or fails for any other reason. `blocks` are (offset, size) ranges that are known to be needed up front.
+pub(crate) async fn parse_async<R, P, T>(reader: &mut R, blocks: &[(u64, u64)], parse: P) -> Result<T>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+    P: Fn(&mut PrefetchReader) -> Result<T>,
+{
+    let len = reader.seek(SeekFrom::End(0)).await?;
+    let mut prefetch = Prefetch::new(len);
+    for &(offset, size) in blocks {
+        prefetch.fetch(reader, offset, size).await?;
+    }
+
+    let mut fetch_len = FIRST_FETCH_LEN;
+    loop {
+        prefetch.position = 0;
+        let mut prefetch_reader = TESReader::from_reader(BufReader::new(prefetch))?;
+        let result = parse(&mut prefetch_reader);
+        prefetch = prefetch_reader.reader.into_inner();
+
+        let missing = match result {
+            Ok(output) => return Ok(output),
+            Err(e) => match missing_range(&e) {
+                Some(missing) => (missing.offset, missing.len),
+                None => return Err(e),
+            },
+        };
+        prefetch.fetch(reader, missing.0, missing.1.max(fetch_len)).await?;
+        fetch_len = (fetch_len * 2).min(MAX_FETCH_LEN);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::{Path, PathBuf};
+
+    use crate::ba2::{BA2Editor, BA2Type, BA2Version};
+    use crate::bsa::{BSAEditor, Version};
+
+    use super::FIRST_FETCH_LEN;
+
+    /// Paths of `count` files spread over several folders
+    fn file_paths(count: usize) -> Vec<PathBuf> {
+        (0..count)
+            .map(|i| PathBuf::from(format!("meshes/folder{}/file_number_{}.nif", i % 50, i)))
+            .collect()
+    }
+
+    /// Saves an archive with `save` and returns its bytes, checking that it's larger than the first prefetch
+    fn saved<S: FnOnce(&Path)>(save: S) -> Vec<u8> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive");
+        save(&path);
+        let bytes = std::fs::read(path).unwrap();
+        assert!(bytes.len() as u64 > 2 * FIRST_FETCH_LEN);
+        bytes
+    }
+
+    #[tokio::test]
+    async fn async_bsa_parse_matches_the_sync_one() {
+        let bytes = saved(|path| {
+            let mut editor = BSAEditor::new(Version::SKYRIMSE).unwrap();
+            // enough files that their records and names alone don't fit in the first prefetch
+            for file_path in file_paths(3000) {
+                let contents = file_path.to_string_lossy().into_owned().into_bytes();
+                editor.add(&file_path, contents).unwrap();
+            }
+            editor.save(path).unwrap();
+        });
+
+        let mut sync = crate::bsa::from_reader(Cursor::new(bytes.clone())).unwrap();
+        let mut archive = crate::bsa::from_async_reader(Cursor::new(bytes)).await.unwrap();
+        assert_eq!(archive.header.file_count, 3000);
+        assert_eq!(archive.header.version, sync.header.version);
+        assert_eq!(archive.file_hashmap.len(), sync.file_hashmap.len());
+        for (file_path, file) in &sync.file_hashmap {
+            let parsed = &archive.file_hashmap[file_path];
+            assert_eq!((parsed.index, parsed.offset, parsed.size), (file.index, file.offset, file.size));
+        }
+        for file_path in file_paths(3000).iter().step_by(97) {
+            let contents = archive.extract_async(file_path).await.unwrap();
+            assert_eq!(contents, sync.extract_by_name(file_path).unwrap());
+            assert_eq!(contents, file_path.to_string_lossy().as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn async_ba2_parse_matches_the_sync_one() {
+        let bytes = saved(|path| {
+            let mut editor = BA2Editor::new(BA2Version::Fallout4, BA2Type::General);
+            // enough data that the name table at the end of the archive is out of reach of the first prefetch
+            for (i, file_path) in file_paths(200).into_iter().enumerate() {
+                let contents = (0u32..0x400).map(|j| ((i as u32 * 0x400 + j).wrapping_mul(2_654_435_761) >> 24) as u8);
+                editor.add(&file_path, contents.collect()).unwrap();
+            }
+            editor.save(path).unwrap();
+        });
+
+        let mut sync = crate::ba2::from_reader(Cursor::new(bytes.clone())).unwrap();
+        let mut archive = crate::ba2::from_async_reader(Cursor::new(bytes)).await.unwrap();
+        assert_eq!(archive.header.file_count, 200);
+        assert_eq!(archive.header.name_table_offset, sync.header.name_table_offset);
+        assert_eq!(archive.file_hashmap.len(), sync.file_hashmap.len());
+        for (file_path, file) in &sync.file_hashmap {
+            let parsed = &archive.file_hashmap[file_path];
+            assert_eq!((parsed.index, parsed.name_hash), (file.index, file.name_hash));
+            assert_eq!(parsed.chunks[0].content_offset, file.chunks[0].content_offset);
+        }
+        let mut file_paths: Vec<PathBuf> = sync.file_hashmap.keys().cloned().collect();
+        file_paths.sort();
+        for file_path in file_paths.iter().step_by(7) {
+            let contents = archive.extract_async(file_path).await.unwrap();
+            assert_eq!(contents, sync.extract_by_name(file_path).unwrap());
+        }
+    }
+}
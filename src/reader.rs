@@ -3,6 +3,7 @@
 //! Some of the code in this module was inspired from two projects:
 //! The [Reader](https://github.com/tafia/quick-xml/blob/master/src/reader.rs) struct in [quick-xml](https://crates.io/search?q=quick-xml).
 //! The [CborReader](https://github.com/BurntSushi/rust-cbor/blob/master/src/decoder.rs) struct in [rust-cbor](https://crates.io/crates/cbor).
+use std::convert::TryFrom;
 use std::fs::File;
 use std::io;
 use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
@@ -23,10 +24,23 @@ pub fn latin1_to_string(buffer: &[u8]) -> String {
     buffer.iter().map(|&c| c as char).collect()
 }
 
+/// The inverse of [`latin1_to_string`]: maps each char back to a single ISO-8859-1 byte. Fails if the string contains a
+/// char that ISO-8859-1 can't represent.
+///
+/// [`latin1_to_string`]: fn.latin1_to_string.html
+pub fn string_to_latin1(string: &str) -> Result<Vec<u8>> {
+    string
+        .chars()
+        .map(|c| u8::try_from(u32::from(c)).map_err(|_| format_err!("{:?} can't be encoded as ISO-8859-1", string)))
+        .collect()
+}
+
 /// Thin wrapper over a buffered reader providing functionality specific to parsing TES files
 pub struct TESReader<B: BufRead> {
     /// Underlying buffered reader
     pub reader: B,
+    /// Total length of the underlying stream, used to sanity check sizes read out of it
+    len: u64,
 }
 
 /// Type alias for reading from a file
@@ -37,14 +51,49 @@ impl TESFile {
     pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<TESFile> {
         let file = File::open(path)?;
         let reader = BufReader::new(file);
-        Ok(TESReader::from_reader(reader))
+        TESReader::from_reader(reader)
     }
 }
 
 impl<B: BufRead + Seek> TESReader<B> {
     /// Opens a buffered file reader at location `file_name` and returns it as a TESFileReader
-    pub fn from_reader(reader: B) -> Self {
-        Self { reader }
+    pub fn from_reader(mut reader: B) -> io::Result<Self> {
+        let position = reader.stream_position()?;
+        let len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(position))?;
+        Ok(Self { reader, len })
+    }
+
+    /// Number of bytes left between the current position and the end of the underlying stream
+    pub fn remaining(&mut self) -> io::Result<u64> {
+        let position = self.reader.stream_position()?;
+        Ok(self.len.saturating_sub(position))
+    }
+
+    /// Fails if there are fewer than `size` bytes left to read.
+    ///
+    /// Every size read out of an archive is checked with this before anything is allocated for it, so a corrupt or
+    /// malicious archive can never allocate more memory than the archive itself could fill.
+    pub fn ensure_remaining(&mut self, size: u64) -> Result<()> {
+        let remaining = self.remaining()?;
+        if size > remaining {
+            return Err(format_err!(
+                "Expected {} bytes but only {} bytes are left in the file",
+                size,
+                remaining
+            ));
+        }
+        Ok(())
+    }
+
+    /// Calculates the serialized size of `count` records of `record_len` bytes each, failing if that many bytes can't
+    /// be read from the current position.
+    pub fn records_len(&mut self, count: usize, record_len: usize) -> Result<usize> {
+        let total_len = count
+            .checked_mul(record_len)
+            .ok_or_else(|| format_err!("{} records of {} bytes overflows", count, record_len))?;
+        self.ensure_remaining(total_len as u64)?;
+        Ok(total_len)
     }
 
     /// Reads a string with a single byte prefixed for length from the file at the current seek position.
@@ -73,6 +122,7 @@ impl<B: BufRead + Seek> TESReader<B> {
     /// Reads a block of '\0' terminated latin-1 strings and parses them into a vector of UTF8 strings  
     pub fn parse_bstring_block(&mut self, total_length: usize) -> Result<Vec<String>> {
         // Read a bstring block
+        self.ensure_remaining(total_length as u64)?;
         let mut buffer = vec![0; total_length];
         self.read_exact(&mut buffer)?;
 
@@ -88,6 +138,7 @@ impl<B: BufRead + Seek> TESReader<B> {
 
     /// Reads a precise number of bytes and applies a named Nom parser function to it.
     pub fn parse_exact<O>(&mut self, input_size: usize, parse_func: ParserFn<O>) -> Result<O> {
+        self.ensure_remaining(input_size as u64)?;
         let mut input_buffer = vec![0; input_size];
         self.read_exact(&mut input_buffer)
             .context(format!("Failed to read {} bytes", input_size))?;
@@ -113,8 +164,11 @@ impl<B: BufRead + Seek> TESReader<B> {
     pub fn parse_zstring(&mut self) -> io::Result<String> {
         let mut string_buf = Vec::new();
         self.read_until(b'\0', &mut string_buf)?;
-        // When Rust creates a String object, it always appends a '\0'; so we only convert the first n-1 bytes
-        Ok(latin1_to_string(&string_buf[0..string_buf.len() - 1]))
+        // The '\0' isn't part of the string, but it will be missing if the end of the file was reached first
+        if string_buf.last() == Some(&b'\0') {
+            string_buf.pop();
+        }
+        Ok(latin1_to_string(&string_buf))
     }
 
     /// Reads a string prefixed with a byte length. NOT zero terminated.
@@ -131,9 +185,10 @@ impl<B: BufRead + Seek> TESReader<B> {
 
     /// Reads a string prefixed with a byte length and terminated with a zero '\0'.
     pub fn parse_bzstring(&mut self) -> io::Result<String> {
-        let string_buf = self.read_string_with_len_prefix()?;
-        // When Rust creates a String object, it always appends a '\0'; so we only convert the first n-1 bytes
-        Ok(latin1_to_string(&string_buf[0..string_buf.len() - 1]))
+        let mut string_buf = self.read_string_with_len_prefix()?;
+        // The length includes the '\0' terminator which isn't part of the string (a corrupt length may also be 0)
+        string_buf.pop();
+        Ok(latin1_to_string(&string_buf))
     }
 }
 
@@ -158,3 +213,33 @@ impl<B: BufRead> BufRead for TESReader<B> {
         self.reader.consume(amt)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn ensure_remaining_counts_from_the_current_position() {
+        let mut reader = TESReader::from_reader(Cursor::new(vec![0; 16])).unwrap();
+        assert!(reader.ensure_remaining(16).is_ok());
+        assert!(reader.ensure_remaining(17).is_err());
+        reader.seek(SeekFrom::Start(10)).unwrap();
+        assert!(reader.ensure_remaining(6).is_ok());
+        assert!(reader.ensure_remaining(7).is_err());
+        assert!(reader.ensure_remaining(u64::MAX).is_err());
+        // seeking past the end leaves nothing to read rather than underflowing
+        reader.seek(SeekFrom::Start(100)).unwrap();
+        assert_eq!(reader.remaining().unwrap(), 0);
+        assert!(reader.ensure_remaining(1).is_err());
+    }
+
+    #[test]
+    fn records_len_rejects_counts_that_dont_fit() {
+        let mut reader = TESReader::from_reader(Cursor::new(vec![0; 64])).unwrap();
+        assert_eq!(reader.records_len(4, 16).unwrap(), 64);
+        assert!(reader.records_len(5, 16).is_err());
+        assert!(reader.records_len(usize::MAX, 16).is_err());
+        assert!(reader.parse_bstring_block(65).is_err());
+    }
+}
@@ -0,0 +1,237 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// A single problem found while verifying one file in an archive
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    /// The file's data runs past the end of the archive
+    OutOfBounds {
+        /// Offset from file byte zero to the data block
+        offset:      u64,
+        /// Size of the data block
+        size:        u64,
+        /// Size of the whole archive
+        archive_len: u64,
+    },
+    /// The file's data overlaps with the data of another file
+    Overlap {
+        /// Path of the file whose data is overlapped
+        other: PathBuf,
+    },
+    /// The file's data could not be read or decompressed
+    Unreadable(String),
+    /// The file's data decompressed to a different length than the archive declared
+    SizeMismatch {
+        /// Uncompressed length declared by the archive
+        expected: u64,
+        /// Length the data actually decompressed to
+        actual:   u64,
+    },
+    /// The name embedded in the file's data doesn't match the path in the file records
+    NameMismatch {
+        /// Name found at the start of the file's data
+        embedded: String,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::OutOfBounds {
+                offset,
+                size,
+                archive_len,
+            } => write!(
+                f,
+                "{} bytes at offset {} run past the end of the archive ({} bytes)",
+                size, offset, archive_len
+            ),
+            Problem::Overlap { other } => write!(f, "data overlaps with {:#?}", other),
+            Problem::Unreadable(reason) => write!(f, "unable to read data: {}", reason),
+            Problem::SizeMismatch { expected, actual } => write!(
+                f,
+                "decompressed to {} bytes but the archive declares {} bytes",
+                actual, expected
+            ),
+            Problem::NameMismatch { embedded } => write!(f, "embedded file name {:#?} doesn't match", embedded),
+        }
+    }
+}
+
+/// The outcome of verifying every file in an archive
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Count of all files that were checked
+    pub files_checked: usize,
+    /// Every problem found, paired with the path of the file it was found in
+    pub problems:      Vec<(PathBuf, Problem)>,
+}
+
+impl VerifyReport {
+    /// True if no problems were found
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub(crate) fn add(&mut self, file_path: &Path, problem: Problem) {
+        self.problems.push((file_path.to_path_buf(), problem));
+    }
+
+    /// Given the (offset, size) of every in-bounds data block, reports every block that begins before a previous
+    /// block has ended. Blocks with the exact same offset and size are payloads shared between several file records,
+    /// which is allowed by the formats, so they are not reported.
+    pub(crate) fn find_overlaps(&mut self, mut blocks: Vec<(u64, u64, &Path)>) {
+        blocks.sort();
+
+        // the block that reaches furthest into the archive so far
+        let mut furthest: Option<(u64, u64, &Path)> = None;
+        for (offset, size, file_path) in blocks {
+            if size == 0 {
+                continue;
+            }
+            if let Some((prev_offset, prev_size, prev_path)) = furthest {
+                let is_shared = prev_offset == offset && prev_size == size;
+                if !is_shared && offset < prev_offset + prev_size {
+                    self.add(
+                        file_path,
+                        Problem::Overlap {
+                            other: prev_path.to_path_buf(),
+                        },
+                    );
+                }
+                if offset + size <= prev_offset + prev_size {
+                    continue;
+                }
+            }
+            furthest = Some((offset, size, file_path));
+        }
+    }
+}
+
+/// Compares a name embedded in a file's data against its path in the file records. Embedded names always use '\\' as a
+/// separator and Bethesda doesn't treat paths as case sensitive.
+pub(crate) fn embedded_name_matches(embedded: &str, file_path: &Path) -> bool {
+    let file_path = file_path.to_string_lossy().replace("\\", "/");
+    embedded.replace("\\", "/").eq_ignore_ascii_case(&file_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsa::{ArchiveFlags, BSAArchive, BSAEditor, Version};
+
+    /// Saves a compressed archive with embedded file names, lets `corrupt` change its bytes and verifies the result
+    fn verify_corrupted<C: FnOnce(&mut Vec<u8>, &BSAArchive)>(corrupt: C) -> VerifyReport {
+        let mut editor = BSAEditor::new(Version::SKYRIM).unwrap();
+        editor
+            .set_archive_flags(
+                ArchiveFlags::INCLUDE_DIR_NAMES
+                    | ArchiveFlags::INCLUDE_FILE_NAMES
+                    | ArchiveFlags::COMPRESSED_ARCHIVE
+                    | ArchiveFlags::EMBED_FILE_NAMES,
+            )
+            .unwrap();
+        editor.add(Path::new("meshes/helmet.nif"), b"helmet ".repeat(50)).unwrap();
+        editor.add(Path::new("meshes/boots.nif"), b"boots ".repeat(50)).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.bsa");
+        editor.save(&path).unwrap();
+
+        let archive = crate::bsa::from_file(path.clone()).unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        corrupt(&mut bytes, &archive);
+        std::fs::write(&path, bytes).unwrap();
+        crate::bsa::from_file(path).unwrap().verify_contents().unwrap()
+    }
+
+    /// Position of the offset field in the file record of `file_path`, which ends the record that starts with its hash
+    fn offset_field(bytes: &[u8], archive: &BSAArchive, file_path: &str) -> usize {
+        let name_hash = archive.file_hashmap[Path::new(file_path)].name_hash.to_le_bytes();
+        bytes.windows(8).position(|window| window == name_hash).unwrap() + 12
+    }
+
+    #[test]
+    fn intact_archives_have_no_problems() {
+        let report = verify_corrupted(|_, _| {});
+        assert_eq!(report.files_checked, 2);
+        assert!(report.is_ok(), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn wrong_declared_sizes_are_size_mismatches() {
+        let report = verify_corrupted(|bytes, archive| {
+            let offset = archive.file_hashmap[Path::new("meshes/helmet.nif")].offset as usize;
+            // the declared size comes after the embedded name, which is prefixed with its length
+            let size_field = offset + 1 + bytes[offset] as usize;
+            bytes[size_field..size_field + 4].copy_from_slice(&360u32.to_le_bytes());
+        });
+        assert_eq!(
+            report.problems,
+            vec![(
+                PathBuf::from("meshes/helmet.nif"),
+                Problem::SizeMismatch {
+                    expected: 360,
+                    actual:   350,
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn wrong_embedded_names_are_name_mismatches() {
+        let report = verify_corrupted(|bytes, _| {
+            let name = bytes.windows(17).position(|window| window == b"meshes\\helmet.nif").unwrap();
+            bytes[name + 7] = b'x';
+        });
+        assert_eq!(
+            report.problems,
+            vec![(
+                PathBuf::from("meshes/helmet.nif"),
+                Problem::NameMismatch {
+                    embedded: "meshes\\xelmet.nif".to_string(),
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn data_past_the_end_is_out_of_bounds() {
+        let mut archive_len = 0;
+        let report = verify_corrupted(|bytes, archive| {
+            archive_len = bytes.len() as u64;
+            let field = offset_field(bytes, archive, "meshes/boots.nif");
+            bytes[field..field + 4].copy_from_slice(&(archive_len as u32 - 10).to_le_bytes());
+        });
+        assert_eq!(report.files_checked, 2);
+        assert_eq!(report.problems.len(), 1);
+        match report.problems[0] {
+            (ref file_path, Problem::OutOfBounds { offset, archive_len: len, .. }) => {
+                assert_eq!(file_path, Path::new("meshes/boots.nif"));
+                assert_eq!((offset, len), (archive_len - 10, archive_len));
+            }
+            ref problem => panic!("unexpected problem {:?}", problem),
+        }
+    }
+
+    #[test]
+    fn data_inside_other_data_is_an_overlap() {
+        let mut paths = ("", "");
+        let report = verify_corrupted(|bytes, archive| {
+            let offset = |file_path: &str| archive.file_hashmap[Path::new(file_path)].offset;
+            paths = match offset("meshes/helmet.nif") < offset("meshes/boots.nif") {
+                true => ("meshes/helmet.nif", "meshes/boots.nif"),
+                false => ("meshes/boots.nif", "meshes/helmet.nif"),
+            };
+            // point the second file into the middle of the first one
+            let field = offset_field(bytes, archive, paths.1);
+            bytes[field..field + 4].copy_from_slice(&(offset(paths.0) + 1).to_le_bytes());
+        });
+        let overlap = (
+            PathBuf::from(paths.1),
+            Problem::Overlap {
+                other: PathBuf::from(paths.0),
+            },
+        );
+        assert!(report.problems.contains(&overlap), "{:?}", report.problems);
+    }
+}
@@ -0,0 +1,183 @@
+//! Helpers shared by everything that writes archives
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::hash::Hasher;
+use std::path::{Component, Path};
+
+use twox_hash::XxHash;
+
+use crate::reader::TESFile;
+use crate::Result;
+
+/// What happened while an archive was written
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct WriteReport {
+    /// Count of data blocks that point at identical data written for another file instead of being written again
+    pub deduplicated_blocks: usize,
+    /// Bytes kept out of the archive by deduplication
+    pub bytes_saved:         u64,
+}
+
+/// Finds data blocks whose content was already written for another file, so that their records can point at it
+#[derive(Default)]
+pub(crate) struct Deduplicator {
+    /// (index, offset) of every block written so far, by its length and the hash of its content
+    written: HashMap<(usize, u64), Vec<(usize, u64)>>,
+    pub report: WriteReport,
+}
+
+impl Deduplicator {
+    /// Looks for an earlier block with the exact same content as block `index`. Returns its offset if there is one,
+    /// otherwise remembers the block as being written at `offset`. Blocks with the same hash are compared byte for byte
+    /// by loading them with `load`, so a hash collision never merges two different blocks.
+    pub(crate) fn find<L>(&mut self, index: usize, offset: u64, data: &[u8], mut load: L) -> Result<Option<u64>>
+    where
+        L: FnMut(usize) -> Result<Vec<u8>>,
+    {
+        let mut hasher = XxHash::with_seed(0);
+        hasher.write(data);
+        let candidates = self.written.entry((data.len(), hasher.finish())).or_default();
+        for &(candidate_index, candidate_offset) in candidates.iter() {
+            if load(candidate_index)? == data {
+                self.report.deduplicated_blocks += 1;
+                self.report.bytes_saved += data.len() as u64;
+                return Ok(Some(candidate_offset));
+            }
+        }
+        candidates.push((index, offset));
+        Ok(None)
+    }
+}
+
+/// The unused space of a source archive's data section: padding in front of its data blocks and after the last one.
+/// Space is only unused if no data block of the source covers it, so the data of removed files never counts.
+#[derive(Default)]
+pub(crate) struct SourceGaps {
+    /// Where the data section starts, right after the records and names in front of it
+    data_start: u64,
+    /// Where the data section ends, at the end of the file or at the name table that follows it
+    data_end:   u64,
+    /// End offset of every data block of the source, sorted
+    block_ends: Vec<u64>,
+}
+
+impl SourceGaps {
+    /// Collects the gaps between `data_start`, `data_end` and the (offset, size) of every data block in between
+    pub(crate) fn new<I>(data_start: u64, data_end: u64, blocks: I) -> SourceGaps
+    where
+        I: IntoIterator<Item = (u64, u64)>,
+    {
+        let mut block_ends: Vec<u64> = blocks.into_iter().map(|(offset, size)| offset + size).collect();
+        block_ends.sort_unstable();
+        SourceGaps {
+            data_start,
+            data_end,
+            block_ends,
+        }
+    }
+
+    /// (offset, size) of the unused space directly in front of the source block at `offset`
+    pub(crate) fn before(&self, offset: u64) -> (u64, u64) {
+        let previous_end = match self.block_ends.partition_point(|&end| end <= offset) {
+            0 => self.data_start,
+            count => self.block_ends[count - 1].max(self.data_start),
+        };
+        (previous_end, offset.saturating_sub(previous_end))
+    }
+
+    /// (offset, size) of the unused space after the last data block of the source
+    pub(crate) fn after(&self) -> (u64, u64) {
+        let last_end = self.block_ends.last().map_or(self.data_start, |&end| end.max(self.data_start));
+        (last_end, self.data_end.saturating_sub(last_end))
+    }
+}
+
+/// Converts a path into the form archives store it in, no matter which platform it came from: relative, with '\\' as
+/// the only separator and without any "." components
+pub(crate) fn archive_path(file_path: &Path) -> String {
+    let components: Vec<_> = file_path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            Component::ParentDir => Some("..".into()),
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => None,
+        })
+        .collect();
+    components.join("\\").replace("/", "\\")
+}
+
+/// Checks that a path added to an archive stays inside the data folder when the archive is extracted again
+pub(crate) fn check_new_path(file_path: &Path) -> Result<()> {
+    // both separators are checked, since '\\' isn't one on every platform
+    if archive_path(file_path).split('\\').any(|name| name == "..") {
+        return Err(format_err!("{:#?} leaves the data folder, so it can't be stored in an archive", file_path));
+    }
+    Ok(())
+}
+
+/// Converts a path into the form Bethesda uses for hashing and comparing paths: lower case with '\\' as the separator
+pub(crate) fn normalize_path(file_path: &Path) -> String {
+    archive_path(file_path).to_ascii_lowercase()
+}
+
+/// The reader of the source archive, which is only missing for archives created from scratch that have no data to copy
+pub(crate) fn source_reader<'r>(reader: &'r mut Option<&mut TESFile>) -> Result<&'r mut TESFile> {
+    reader
+        .as_deref_mut()
+        .ok_or_else(|| format_err!("The archive has no source to copy data from"))
+}
+
+/// Reads a block of raw data out of an archive without decoding it
+pub(crate) fn read_block(reader: &mut TESFile, offset: u64, size: u64) -> Result<Vec<u8>> {
+    let mut block = Vec::new();
+    copy_block(reader, offset, size, &mut block)?;
+    Ok(block)
+}
+
+/// Copies a block of raw data from one archive to another without decoding it
+pub(crate) fn copy_block<W: Write>(reader: &mut TESFile, offset: u64, size: u64, writer: &mut W) -> Result<()> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let copied = io::copy(&mut reader.take(size), writer)?;
+    if copied != size {
+        return Err(format_err!(
+            "Expected {} bytes at offset {} but only {} bytes could be read",
+            size,
+            offset,
+            copied
+        ));
+    }
+    Ok(())
+}
+
+/// Writes an archive to a temporary file next to `path`, and only moves it over `path` once it's complete. This keeps a
+/// half written archive from ever replacing a good one, and allows an archive to be saved over its own source.
+pub(crate) fn write_atomically<F>(path: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<()>,
+{
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| format_err!("{:#?} is not a file path", path))?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let result = File::create(&temp_path)
+        .map_err(Into::into)
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            write(&mut writer)?;
+            writer.flush()?;
+            Ok(())
+        })
+        .and_then(|_| fs::rename(&temp_path, path).map_err(Into::into));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}